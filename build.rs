@@ -0,0 +1,164 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use image::{imageops, Rgba, RgbaImage};
+
+const TEXTURES_DIR: &str = "assets/textures/blocks";
+// Size of a tile in the atlas, textures of another size get resized
+const TILE_SIZE: u32 = 32;
+const TILES_PER_ROW: u32 = 8;
+// Shows where a block uses a tile that doesn't exist
+const MISSING_COLOR: Rgba<u8> = Rgba([244, 0, 255, 255]);
+const FACE_SUFFIXES: [&str; 3] = ["_top", "_bottom", "_side"];
+
+/* Packs the textures of assets/textures/blocks into the texture atlas.
+ * A file is named after its block, with an optional _top, _bottom or _side suffix for the faces
+ * that look different. Also writes atlas.rs, with the tile of every face of every block.
+ * A texture taller than it is wide is an animation, each frame gets its own tile
+ */
+fn main() -> anyhow::Result<()> {
+    println!("cargo:rerun-if-changed={TEXTURES_DIR}");
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+
+    let mut paths = glob::glob(&format!("{TEXTURES_DIR}/*.png"))?.collect::<Result<Vec<_>, _>>()?;
+    // Sorted, so that the tiles don't move around between builds
+    paths.sort();
+
+    let size = TILE_SIZE * TILES_PER_ROW;
+    let mut atlas = RgbaImage::from_pixel(size, size, MISSING_COLOR);
+    let mut textures = BTreeMap::new();
+    let mut index = 0;
+    for path in paths.iter() {
+        let frames = load_frames(path)?;
+        textures.insert(
+            texture_name(path)?,
+            AtlasTexture {
+                tile: index,
+                frames: frames.len() as u32,
+            },
+        );
+        for frame in frames {
+            if index >= TILES_PER_ROW * TILES_PER_ROW {
+                bail!(
+                    "The textures don't fit in the atlas, the maximum is {} tiles",
+                    TILES_PER_ROW * TILES_PER_ROW
+                );
+            }
+            imageops::replace(
+                &mut atlas,
+                &frame,
+                ((index % TILES_PER_ROW) * TILE_SIZE) as i64,
+                ((index / TILES_PER_ROW) * TILE_SIZE) as i64,
+            );
+            index += 1;
+        }
+    }
+    atlas
+        .save(out_dir.join("tex_atlas.png"))
+        .context("Failed to save the atlas")?;
+
+    std::fs::write(out_dir.join("atlas.rs"), generate_lookup(&textures, index)?)?;
+    Ok(())
+}
+
+fn texture_name(path: &Path) -> anyhow::Result<String> {
+    Ok(path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .with_context(|| format!("Invalid texture name {}", path.display()))?
+        .to_string())
+}
+
+fn resize_tile(tile: RgbaImage) -> RgbaImage {
+    if tile.dimensions() == (TILE_SIZE, TILE_SIZE) {
+        return tile;
+    }
+    imageops::resize(&tile, TILE_SIZE, TILE_SIZE, imageops::FilterType::Nearest)
+}
+
+// Animated textures have their square frames stacked from top to bottom
+fn load_frames(path: &Path) -> anyhow::Result<Vec<RgbaImage>> {
+    let image = image::open(path)
+        .with_context(|| format!("Failed to load {}", path.display()))?
+        .to_rgba8();
+    let (width, height) = image.dimensions();
+    if height <= width || height % width != 0 {
+        return Ok(vec![resize_tile(image)]);
+    }
+    Ok((0..height / width)
+        .map(|frame| {
+            resize_tile(imageops::crop_imm(&image, 0, frame * width, width, width).to_image())
+        })
+        .collect())
+}
+
+// Tiles used by a texture file in the atlas
+struct AtlasTexture {
+    // Tile of the first frame, the others follow it
+    tile: u32,
+    frames: u32,
+}
+
+// textures: texture name to its tiles. tile_count: tiles used in the atlas
+fn generate_lookup(
+    textures: &BTreeMap<String, AtlasTexture>,
+    tile_count: u32,
+) -> anyhow::Result<String> {
+    let blocks = textures
+        .keys()
+        .map(|name| {
+            FACE_SUFFIXES
+                .iter()
+                .find_map(|suffix| name.strip_suffix(suffix))
+                .unwrap_or(name)
+        })
+        .collect::<BTreeSet<_>>();
+
+    let mut code = String::from("// Generated by build.rs from assets/textures/blocks\n\n");
+    writeln!(code, "pub const TILES_PER_ROW: u32 = {TILES_PER_ROW};")?;
+    writeln!(code, "pub const TILE_COUNT: u32 = {tile_count};\n")?;
+    writeln!(
+        code,
+        "// Name, first tile and number of frames of every texture"
+    )?;
+    writeln!(
+        code,
+        "pub const TEXTURES: [(&str, u32, u32); {}] = [",
+        textures.len()
+    )?;
+    for (name, texture) in textures.iter() {
+        writeln!(
+            code,
+            "    ({name:?}, {}, {}),",
+            texture.tile, texture.frames
+        )?;
+    }
+    writeln!(code, "];\n")?;
+
+    for block in blocks {
+        let texture = |suffix: &str| textures.get(&format!("{block}{suffix}"));
+        let Some(side) = texture("_side").or(texture("")) else {
+            bail!("{block} doesn't have a side texture");
+        };
+        let top = texture("_top").unwrap_or(side);
+        // Blocks like logs have the same top and bottom
+        let bottom = texture("_bottom").unwrap_or(top);
+        // The block has a single animation for all of its faces
+        if top.frames != side.frames || bottom.frames != side.frames {
+            bail!("The faces of {block} don't have the same number of frames");
+        }
+        writeln!(
+            code,
+            "pub const {}: BlockTextures = BlockTextures {{ side: {}, top: {}, bottom: {}, frames: {} }};",
+            block.to_uppercase(),
+            side.tile,
+            top.tile,
+            bottom.tile,
+            side.frames
+        )?;
+    }
+    Ok(code)
+}
@@ -0,0 +1,349 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use wgpu::util::DrawIndexedIndirect;
+
+use crate::blocks::block::BlockVertexData;
+use crate::chunk::SectionMesh;
+
+const INITIAL_VERTICES: u32 = 1 << 20;
+const INITIAL_INDICES: u32 = 3 << 19;
+// Loaded chunks and LOD chunks
+const INITIAL_CHUNK_SLOTS: u32 = 2048;
+const INITIAL_DRAWS: u32 = 4096;
+
+/* Hands out ranges of a buffer, first fit.
+ * Freed ranges are merged with the free ranges around them, so that they can be reused by bigger meshes
+ */
+#[derive(Debug)]
+pub struct RangeAllocator {
+    pub capacity: u32,
+    // Sorted and never touching each other
+    free: Vec<Range<u32>>,
+}
+
+impl RangeAllocator {
+    pub fn new(capacity: u32) -> Self {
+        Self {
+            capacity,
+            free: std::iter::once(0..capacity).collect(),
+        }
+    }
+    pub fn allocate(&mut self, size: u32) -> Option<Range<u32>> {
+        let index = self.free.iter().position(|r| r.len() as u32 >= size)?;
+        let range = &mut self.free[index];
+        let allocation = range.start..range.start + size;
+        range.start += size;
+        if range.start == range.end {
+            self.free.remove(index);
+        }
+        Some(allocation)
+    }
+    pub fn free(&mut self, range: Range<u32>) {
+        if range.is_empty() {
+            return;
+        }
+        let index = self.free.partition_point(|r| r.start < range.start);
+        self.free.insert(index, range);
+
+        if index + 1 < self.free.len() && self.free[index].end == self.free[index + 1].start {
+            self.free[index].end = self.free.remove(index + 1).end;
+        }
+        if index > 0 && self.free[index - 1].end == self.free[index].start {
+            self.free[index - 1].end = self.free.remove(index).end;
+        }
+    }
+    // The new space is added at the end
+    pub fn grow(&mut self, capacity: u32) {
+        let old_capacity = self.capacity;
+        self.capacity = capacity;
+        self.free(old_capacity..capacity);
+    }
+    pub fn free_space(&self) -> u32 {
+        self.free.iter().map(|r| r.len() as u32).sum()
+    }
+}
+
+/* Every chunk mesh lives in the same vertex and index buffers, so remeshing doesn't create buffers
+ * and all the sections can be drawn without rebinding anything.
+ * The position of each chunk is an instance attribute, each draw has a single instance starting at the chunk's slot
+ */
+pub struct MeshArena {
+    pub vertex_buffer: wgpu::Buffer,
+    pub index_buffer: wgpu::Buffer,
+    pub chunk_buffer: wgpu::Buffer,
+    pub indirect_buffer: wgpu::Buffer,
+    // Drawing everything with a single call per pass needs both features
+    pub multi_draw: bool,
+    vertex_allocator: RangeAllocator,
+    index_allocator: RangeAllocator,
+    chunk_slots: RangeAllocator,
+    indirect_capacity: u32,
+    device: Arc<wgpu::Device>,
+    queue: Arc<wgpu::Queue>,
+}
+
+impl MeshArena {
+    pub const MULTI_DRAW_FEATURES: wgpu::Features =
+        wgpu::Features::MULTI_DRAW_INDIRECT.union(wgpu::Features::INDIRECT_FIRST_INSTANCE);
+
+    pub fn new(device: Arc<wgpu::Device>, queue: Arc<wgpu::Queue>) -> Self {
+        let vertex_buffer = Self::create_buffer(
+            &device,
+            "arena-vertex",
+            INITIAL_VERTICES as u64 * std::mem::size_of::<BlockVertexData>() as u64,
+            wgpu::BufferUsages::VERTEX,
+        );
+        let index_buffer = Self::create_buffer(
+            &device,
+            "arena-index",
+            INITIAL_INDICES as u64 * std::mem::size_of::<u32>() as u64,
+            wgpu::BufferUsages::INDEX,
+        );
+        let chunk_buffer = Self::create_buffer(
+            &device,
+            "arena-chunks",
+            INITIAL_CHUNK_SLOTS as u64 * std::mem::size_of::<[i32; 2]>() as u64,
+            wgpu::BufferUsages::VERTEX,
+        );
+        let indirect_buffer = Self::create_buffer(
+            &device,
+            "arena-indirect",
+            INITIAL_DRAWS as u64 * std::mem::size_of::<DrawIndexedIndirect>() as u64,
+            wgpu::BufferUsages::INDIRECT,
+        );
+        Self {
+            vertex_buffer,
+            index_buffer,
+            chunk_buffer,
+            indirect_buffer,
+            multi_draw: device.features().contains(Self::MULTI_DRAW_FEATURES),
+            vertex_allocator: RangeAllocator::new(INITIAL_VERTICES),
+            index_allocator: RangeAllocator::new(INITIAL_INDICES),
+            chunk_slots: RangeAllocator::new(INITIAL_CHUNK_SLOTS),
+            indirect_capacity: INITIAL_DRAWS,
+            device,
+            queue,
+        }
+    }
+    fn create_buffer(
+        device: &wgpu::Device,
+        label: &str,
+        size: u64,
+        usage: wgpu::BufferUsages,
+    ) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size,
+            usage: usage | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        })
+    }
+    // Copies the buffer into a bigger one, keeping its contents
+    fn grow_buffer(&self, buffer: &wgpu::Buffer, size: u64) -> wgpu::Buffer {
+        let new_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("arena-grown"),
+            size,
+            usage: buffer.usage(),
+            mapped_at_creation: false,
+        });
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("arena_grow_encoder"),
+            });
+        encoder.copy_buffer_to_buffer(buffer, 0, &new_buffer, 0, buffer.size());
+        self.queue.submit(Some(encoder.finish()));
+        new_buffer
+    }
+    // Uploads a mesh, the indices stay relative to the mesh's first vertex
+    pub fn upload(&mut self, vertex: &[BlockVertexData], indices: &[u32]) -> SectionMesh {
+        if indices.is_empty() {
+            return SectionMesh::default();
+        }
+
+        let vertex_range = match self.vertex_allocator.allocate(vertex.len() as u32) {
+            Some(range) => range,
+            None => {
+                let capacity = (self.vertex_allocator.capacity + vertex.len() as u32) * 2;
+                self.vertex_buffer = self.grow_buffer(
+                    &self.vertex_buffer,
+                    capacity as u64 * std::mem::size_of::<BlockVertexData>() as u64,
+                );
+                self.vertex_allocator.grow(capacity);
+                self.vertex_allocator.allocate(vertex.len() as u32).unwrap()
+            }
+        };
+        let index_range = match self.index_allocator.allocate(indices.len() as u32) {
+            Some(range) => range,
+            None => {
+                let capacity = (self.index_allocator.capacity + indices.len() as u32) * 2;
+                self.index_buffer = self.grow_buffer(
+                    &self.index_buffer,
+                    capacity as u64 * std::mem::size_of::<u32>() as u64,
+                );
+                self.index_allocator.grow(capacity);
+                self.index_allocator.allocate(indices.len() as u32).unwrap()
+            }
+        };
+
+        self.queue.write_buffer(
+            &self.vertex_buffer,
+            vertex_range.start as u64 * std::mem::size_of::<BlockVertexData>() as u64,
+            bytemuck::cast_slice(vertex),
+        );
+        self.queue.write_buffer(
+            &self.index_buffer,
+            index_range.start as u64 * std::mem::size_of::<u32>() as u64,
+            bytemuck::cast_slice(indices),
+        );
+
+        SectionMesh {
+            indices: indices.len() as u32,
+            vertex_range,
+            index_range,
+        }
+    }
+    pub fn free(&mut self, mesh: &SectionMesh) {
+        self.vertex_allocator.free(mesh.vertex_range.clone());
+        self.index_allocator.free(mesh.index_range.clone());
+    }
+    // Returns the slot the chunk's meshes are drawn with
+    pub fn allocate_chunk(&mut self, position: (i32, i32)) -> u32 {
+        let slot = match self.chunk_slots.allocate(1) {
+            Some(range) => range.start,
+            None => {
+                let capacity = self.chunk_slots.capacity * 2;
+                self.chunk_buffer = self.grow_buffer(
+                    &self.chunk_buffer,
+                    capacity as u64 * std::mem::size_of::<[i32; 2]>() as u64,
+                );
+                self.chunk_slots.grow(capacity);
+                self.chunk_slots.allocate(1).unwrap().start
+            }
+        };
+        self.queue.write_buffer(
+            &self.chunk_buffer,
+            slot as u64 * std::mem::size_of::<[i32; 2]>() as u64,
+            bytemuck::cast_slice(&[position.0, position.1]),
+        );
+        slot
+    }
+    pub fn free_chunk(&mut self, slot: u32) {
+        self.chunk_slots.free(slot..slot + 1);
+    }
+    pub fn draw_command(mesh: &SectionMesh, slot: u32) -> DrawIndexedIndirect {
+        DrawIndexedIndirect {
+            vertex_count: mesh.indices,
+            instance_count: 1,
+            base_index: mesh.index_range.start,
+            vertex_offset: mesh.vertex_range.start as i32,
+            base_instance: slot,
+        }
+    }
+    /* Writes the draw commands of the frame to the indirect buffer.
+     * Returns the offset of each batch, to be passed to draw_batch
+     */
+    pub fn write_draws(&mut self, batches: &[&[DrawIndexedIndirect]]) -> Vec<u64> {
+        let draws = batches.iter().map(|b| b.len() as u32).sum::<u32>();
+        if !self.multi_draw {
+            return vec![0; batches.len()];
+        }
+        if draws > self.indirect_capacity {
+            self.indirect_capacity = draws * 2;
+            self.indirect_buffer = Self::create_buffer(
+                &self.device,
+                "arena-indirect",
+                self.indirect_capacity as u64 * std::mem::size_of::<DrawIndexedIndirect>() as u64,
+                wgpu::BufferUsages::INDIRECT,
+            );
+        }
+
+        let mut offsets = vec![];
+        let mut data: Vec<u8> = vec![];
+        for batch in batches {
+            offsets.push(data.len() as u64);
+            for command in batch.iter() {
+                data.extend_from_slice(command.as_bytes());
+            }
+        }
+        if !data.is_empty() {
+            self.queue.write_buffer(&self.indirect_buffer, 0, &data);
+        }
+        offsets
+    }
+    // Second vertex buffer of the pipelines drawing the arena, the position of the chunk
+    pub fn chunk_layout() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<[i32; 2]>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &[wgpu::VertexAttribute {
+                offset: 0,
+                shader_location: 2,
+                format: wgpu::VertexFormat::Sint32x2,
+            }],
+        }
+    }
+    // Binds the arena's buffers, every section is then drawn with draw_batch
+    pub fn bind<'a>(&'a self, rpass: &mut wgpu::RenderPass<'a>) {
+        rpass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        rpass.set_vertex_buffer(1, self.chunk_buffer.slice(..));
+        rpass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+    }
+    // offset: returned by write_draws for this batch
+    pub fn draw_batch<'a>(
+        &'a self,
+        rpass: &mut wgpu::RenderPass<'a>,
+        batch: &[DrawIndexedIndirect],
+        offset: u64,
+    ) {
+        if batch.is_empty() {
+            return;
+        }
+        if self.multi_draw {
+            rpass.multi_draw_indexed_indirect(&self.indirect_buffer, offset, batch.len() as u32);
+            return;
+        }
+        for command in batch.iter() {
+            rpass.draw_indexed(
+                command.base_index..command.base_index + command.vertex_count,
+                command.vertex_offset,
+                command.base_instance..command.base_instance + 1,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_reuse_and_merge_freed_ranges() {
+        let mut allocator = RangeAllocator::new(100);
+        let a = allocator.allocate(30).unwrap();
+        let b = allocator.allocate(30).unwrap();
+        let c = allocator.allocate(30).unwrap();
+        assert_eq!((a.start, b.start, c.start), (0, 30, 60));
+        assert!(allocator.allocate(20).is_none());
+
+        // The freed space is reused before the end of the buffer
+        allocator.free(b.clone());
+        assert_eq!(allocator.allocate(10), Some(30..40));
+
+        // Neighbour ranges are merged, so bigger meshes fit in them
+        allocator.free(a);
+        allocator.free(30..40);
+        assert_eq!(allocator.allocate(60), Some(0..60));
+        assert_eq!(allocator.free_space(), 10);
+    }
+
+    #[test]
+    fn should_add_the_grown_space_at_the_end() {
+        let mut allocator = RangeAllocator::new(10);
+        allocator.allocate(5).unwrap();
+        allocator.grow(20);
+        // The free space left before growing is merged with the new one
+        assert_eq!(allocator.allocate(15), Some(5..20));
+    }
+}
@@ -0,0 +1,55 @@
+use super::block::FaceDirections;
+
+// Tiles of the texture atlas used by the faces of a block, the atlas is packed by build.rs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockTextures {
+    pub side: u32,
+    pub top: u32,
+    pub bottom: u32,
+    // Frames of the animation, in the tiles following the ones of the faces
+    pub frames: u32,
+}
+
+impl BlockTextures {
+    pub fn get(&self, face_dir: FaceDirections) -> u32 {
+        let tile = match face_dir {
+            FaceDirections::Top => self.top,
+            FaceDirections::Bottom => self.bottom,
+            _ => self.side,
+        };
+        debug_assert!(tile < TILE_COUNT);
+        tile
+    }
+}
+
+// Animated textures cycle through the frames of their tiles, see BlockTextures::frames
+#[derive(Clone, Copy, Debug)]
+pub struct TextureAnimation {
+    pub frames: u32,
+    // Seconds that each frame is shown
+    pub frame_time: f32,
+}
+
+pub const MAX_TILES: usize = (TILES_PER_ROW * TILES_PER_ROW) as usize;
+
+// PNG of the packed atlas
+pub const ATLAS_PNG: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/tex_atlas.png"));
+
+include!(concat!(env!("OUT_DIR"), "/atlas.rs"));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_fall_back_to_the_side_and_top_tiles() {
+        assert_eq!(GRASS.get(FaceDirections::Left), GRASS.side);
+        assert_ne!(GRASS.top, GRASS.bottom);
+        // wood has a single texture for both ends
+        assert_eq!(WOOD.top, WOOD.bottom);
+        assert_ne!(WOOD.top, WOOD.side);
+        assert_eq!(STONE.top, STONE.side);
+        assert_eq!(STONE.bottom, STONE.side);
+        assert_eq!(STONE.frames, 1);
+    }
+}
@@ -1,9 +1,12 @@
 use bytemuck::{Pod, Zeroable};
 use std::collections::HashMap;
 
+use super::block_shape::{BlockShape, ShapeBox};
 use super::block_type::BlockType;
-use crate::chunk::BlockVec;
-use crate::effects::ao::{convert_ao_u8_to_f32, from_vertex_position};
+use crate::chunk::{BlockVec, Chunk};
+use crate::effects::ao::from_face_corner;
+use crate::light::MAX_LIGHT;
+use crate::utils::ChunkFromPosition;
 use crate::world::CHUNK_SIZE;
 use glam::Vec3;
 use std::sync::{Arc, MutexGuard, RwLock};
@@ -13,6 +16,8 @@ pub struct Block {
     pub position: glam::Vec3,
     pub absolute_position: glam::Vec3,
     pub block_type: BlockType,
+    // Horizontal direction the block was placed towards, the stairs go up towards it
+    pub facing: FaceDirections,
 }
 
 #[rustfmt::skip]
@@ -28,7 +33,8 @@ pub const CUBE_VERTEX: [f32; 24] = [
 ];
 
 pub trait TexturedBlock {
-    fn get_texcoords(&self, face_dir: FaceDirections) -> [[f32; 2]; 4];
+    // Index of the face's tile in the texture atlas
+    fn get_texture_index(&self, face_dir: FaceDirections) -> u32;
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
@@ -47,12 +53,122 @@ impl FaceDirections {
         block: Arc<RwLock<Block>>,
         blocks: &Vec<((i32, i32), BlockVec)>,
     ) -> (Vec<BlockVertexData>, Vec<u32>) {
+        let (unique_indices, mut indices_map) = self.get_unique_indices();
+        let mut vertex_data: Vec<BlockVertexData> = Vec::with_capacity(4);
+
+        let block_read = block.read().unwrap();
+        let texture_index = block_read.block_type.get_texture_index(*self);
+
+        unique_indices.iter().enumerate().for_each(|(i, index)| {
+            let cube_vertex = glam::vec3(
+                CUBE_VERTEX[*index as usize * 3],
+                CUBE_VERTEX[*index as usize * 3 + 1],
+                CUBE_VERTEX[*index as usize * 3 + 2],
+            );
+
+            vertex_data.push(BlockVertexData::new(
+                cube_vertex + block_read.position,
+                *self,
+                FACE_UVS[i],
+                texture_index,
+                from_face_corner(&block_read.absolute_position, *self, &cube_vertex, blocks),
+            ))
+        });
+
+        // Split the quad along the other diagonal when it's darker, so the occlusion isn't anisotropic
+        let ao: Vec<u8> = vertex_data.iter().map(|v| v.ao()).collect();
+        if ao[0] as u32 + (ao[2] as u32) < ao[1] as u32 + ao[3] as u32 {
+            indices_map = vec![1, 2, 3, 1, 3, 0];
+        }
+
+        (vertex_data, indices_map)
+    }
+    // Face of a box that is smaller than the block, the texture gets cropped to the box's size
+    pub fn create_box_face_data(
+        &self,
+        block: Arc<RwLock<Block>>,
+        shape_box: &ShapeBox,
+    ) -> (Vec<BlockVertexData>, Vec<u32>) {
+        let (unique_indices, indices_map) = self.get_unique_indices();
+
+        let block_read = block.read().unwrap();
+        let texture_index = block_read.block_type.get_texture_index(*self);
+
+        let vertex_data = unique_indices
+            .iter()
+            .map(|index| {
+                let corner = glam::vec3(
+                    CUBE_VERTEX[*index as usize * 3],
+                    CUBE_VERTEX[*index as usize * 3 + 1],
+                    CUBE_VERTEX[*index as usize * 3 + 2],
+                ) + 0.5;
+                let local_position = shape_box.min + (shape_box.max - shape_box.min) * corner;
+
+                BlockVertexData::new(
+                    local_position + block_read.position - 0.5,
+                    *self,
+                    self.get_box_uv(local_position),
+                    texture_index,
+                    3,
+                )
+            })
+            .collect();
+
+        (vertex_data, indices_map)
+    }
+    /* Face of an arbitrary box with the whole texture stretched over it, fully lit and without AO
+     * min, max: chunk-local corners of the box
+     */
+    pub fn create_cuboid_face_data(
+        &self,
+        min: Vec3,
+        max: Vec3,
+        texture_index: u32,
+    ) -> (Vec<BlockVertexData>, Vec<u32>) {
+        let (unique_indices, indices_map) = self.get_unique_indices();
+
+        let vertex_data = unique_indices
+            .iter()
+            .enumerate()
+            .map(|(i, index)| {
+                let corner = glam::vec3(
+                    CUBE_VERTEX[*index as usize * 3],
+                    CUBE_VERTEX[*index as usize * 3 + 1],
+                    CUBE_VERTEX[*index as usize * 3 + 2],
+                ) + 0.5;
+                let mut vertex = BlockVertexData::new(
+                    min + (max - min) * corner,
+                    *self,
+                    FACE_UVS[i],
+                    texture_index,
+                    3,
+                );
+                vertex.set_light(MAX_LIGHT, 0);
+                vertex
+            })
+            .collect();
+
+        (vertex_data, indices_map)
+    }
+    // Texture coordinates (in 1/16 of a tile) of a point on the face, matching FACE_UVS for full faces
+    fn get_box_uv(&self, local_position: Vec3) -> [u32; 2] {
+        let p = local_position;
+        let uv = match self {
+            FaceDirections::Back => [1.0 - p.x, p.y],
+            FaceDirections::Front => [p.x, p.y],
+            FaceDirections::Left => [1.0 - p.z, p.y],
+            FaceDirections::Right => [p.z, p.y],
+            FaceDirections::Top => [p.x, p.z],
+            FaceDirections::Bottom => [p.x, 1.0 - p.z],
+        };
+        uv.map(|c| (c * 16.0).round() as u32)
+    }
+    // The 4 unique corners of the face and the indices of the two triangles into them
+    fn get_unique_indices(&self) -> (Vec<u32>, Vec<u32>) {
         let indices = self.get_indices();
 
         let mut unique_indices: Vec<u32> = Vec::with_capacity(4);
 
-        let mut vertex_data: Vec<BlockVertexData> = Vec::with_capacity(4);
-
         let mut indices_map: Vec<u32> = vec![0; 6];
 
         for ind in indices.iter() {
@@ -70,41 +186,73 @@ impl FaceDirections {
                 .unwrap();
             *indices_map = index_of as u32;
         }
-
-        let block_read = block.read().unwrap();
-        let face_texcoords = block_read.block_type.get_texcoords(*self);
-        let normals = self.get_normal_vector();
-
-        unique_indices.iter().enumerate().for_each(|(i, index)| {
-            let vertex_position = glam::vec3(
-                CUBE_VERTEX[(*index as usize * 3 + 0) as usize] + block_read.absolute_position.x,
-                CUBE_VERTEX[(*index as usize * 3 + 1) as usize] + block_read.absolute_position.y,
-                CUBE_VERTEX[(*index as usize * 3 + 2) as usize] + block_read.absolute_position.z,
-            );
-
-            vertex_data.push(BlockVertexData {
-                position: [
-                    CUBE_VERTEX[(*index as usize * 3 + 0) as usize] + block_read.position.x,
-                    CUBE_VERTEX[(*index as usize * 3 + 1) as usize] + block_read.position.y,
-                    CUBE_VERTEX[(*index as usize * 3 + 2) as usize] + block_read.position.z,
-                ],
-                ao: convert_ao_u8_to_f32(from_vertex_position(&vertex_position, &blocks)),
-                normal: normals.into(),
-                tex_coords: face_texcoords[i],
-            })
-        });
-
-        (vertex_data, indices_map)
+        (unique_indices, indices_map)
     }
 }
 
+// Texture coordinates of the 4 unique face vertices, in 1/16 of a tile
+pub const FACE_UVS: [[u32; 2]; 4] = [[0, 0], [0, 16], [16, 16], [16, 0]];
+
+/* Packed chunk vertex, 8 bytes per vertex (decoded in shader.wgsl)
+ * data[0]: x (9 bits) | y (13 bits) | z (9 bits), chunk-local and in 1/16 of a block
+ * data[1]: normal (3 bits) | ao (2 bits) | u (5 bits) | v (5 bits) | texture index (8 bits)
+ *          | sky light (4 bits) | block light (4 bits)
+ */
 #[repr(C)]
-#[derive(Pod, Copy, Clone, Zeroable)]
+#[derive(Pod, Copy, Clone, Zeroable, Debug)]
 pub struct BlockVertexData {
-    pub position: [f32; 3],
-    pub normal: [f32; 3],
-    pub tex_coords: [f32; 2],
-    pub ao: f32,
+    pub data: [u32; 2],
+}
+
+impl BlockVertexData {
+    // Amount of steps a block is subdivided into
+    pub const POSITION_SCALE: f32 = 16.0;
+
+    // position: chunk-local vertex position (blocks are centered on their position)
+    // uv: texture coordinates inside the tile, 0..=16
+    pub fn new(
+        position: Vec3,
+        face: FaceDirections,
+        uv: [u32; 2],
+        texture_index: u32,
+        ao: u8,
+    ) -> Self {
+        let position = ((position + 0.5) * Self::POSITION_SCALE).round();
+        let (x, y, z) = (position.x as u32, position.y as u32, position.z as u32);
+
+        Self {
+            data: [
+                (x & 0x1FF) | (y & 0x1FFF) << 9 | (z & 0x1FF) << 22,
+                face.to_id()
+                    | (ao as u32 & 0x3) << 3
+                    | (uv[0] & 0x1F) << 5
+                    | (uv[1] & 0x1F) << 10
+                    | (texture_index & 0xFF) << 15,
+            ],
+        }
+    }
+    // Chunk-local position of the vertex
+    pub fn position(&self) -> Vec3 {
+        let x = self.data[0] & 0x1FF;
+        let y = (self.data[0] >> 9) & 0x1FFF;
+        let z = (self.data[0] >> 22) & 0x1FF;
+        glam::vec3(x as f32, y as f32, z as f32) / Self::POSITION_SCALE - 0.5
+    }
+    pub fn ao(&self) -> u8 {
+        ((self.data[1] >> 3) & 0x3) as u8
+    }
+    pub fn face(&self) -> FaceDirections {
+        let id = self.data[1] & 0x7;
+        *FaceDirections::all()
+            .iter()
+            .find(|face| face.to_id() == id)
+            .expect("Invalid face id")
+    }
+    // Sky and block light levels, 0..=15
+    pub fn set_light(&mut self, sky: u8, block: u8) {
+        self.data[1] &= !(0xFF << 23);
+        self.data[1] |= (sky as u32 & 0xF) << 23 | (block as u32 & 0xF) << 27;
+    }
 }
 
 impl Block {
@@ -119,25 +267,45 @@ impl Block {
             position,
             block_type,
             absolute_position,
+            facing: FaceDirections::Back,
         }
     }
-    pub fn get_neighbour_chunks_coords(&self) -> Vec<(i32, i32)> {
-        let chunk = self.get_chunk_coords();
-        let mut neighbour_chunks = vec![];
-
-        if self.position.x == 15.0 {
-            neighbour_chunks.push((chunk.0 + 1, chunk.1));
-        }
-        if self.position.x == 0.0 {
-            neighbour_chunks.push((chunk.0 - 1, chunk.1));
-        }
-        if self.position.z == 15.0 {
-            neighbour_chunks.push((chunk.0, chunk.1 + 1));
+    // Shape of the block type turned towards the facing
+    pub fn get_shape(&self) -> BlockShape {
+        match self.block_type.get_shape() {
+            BlockShape::Stairs(_) => BlockShape::Stairs(self.facing),
+            // Models of the stairs in the resource packs turn like them
+            BlockShape::Model(boxes)
+                if matches!(self.block_type.get_config().shape, BlockShape::Stairs(_)) =>
+            {
+                BlockShape::Model(boxes.iter().map(|b| b.rotate(self.facing)).collect())
+            }
+            shape => shape,
         }
-        if self.position.z == 0.0 {
-            neighbour_chunks.push((chunk.0, chunk.1 - 1));
+    }
+    // Chunk sections whose mesh depends on this block, the block's own section included
+    pub fn get_neighbour_sections(&self) -> Vec<((i32, i32), usize)> {
+        let mut sections = vec![];
+
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    let position =
+                        self.absolute_position + glam::vec3(x as f32, y as f32, z as f32);
+                    if Chunk::is_outside_bounds(&position) {
+                        continue;
+                    }
+                    let section = (
+                        position.get_chunk_from_position_absolute(),
+                        Chunk::get_section_index(position.y),
+                    );
+                    if !sections.contains(&section) {
+                        sections.push(section);
+                    }
+                }
+            }
         }
-        return neighbour_chunks;
+        sections
     }
     pub fn is_on_chunk_border(&self) -> bool {
         return self.position.x == 0.0
@@ -156,30 +324,18 @@ impl Block {
             array_stride: std::mem::size_of::<BlockVertexData>() as wgpu::BufferAddress,
             step_mode: wgpu::VertexStepMode::Vertex,
             attributes: &[
-                // Position
+                // Packed position
                 wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32x3,
+                    format: wgpu::VertexFormat::Uint32,
                     offset: 0,
                     shader_location: 0,
                 },
-                // Normals
+                // Packed normal, ao and texture data
                 wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32x3,
-                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    format: wgpu::VertexFormat::Uint32,
+                    offset: std::mem::size_of::<u32>() as wgpu::BufferAddress,
                     shader_location: 1,
                 },
-                // Tex coords
-                wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32x2,
-                    offset: std::mem::size_of::<[f32; 6]>() as wgpu::BufferAddress,
-                    shader_location: 2,
-                },
-                // Ao
-                wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32,
-                    offset: std::mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
-                    shader_location: 3,
-                },
             ],
         }
     }
@@ -206,6 +362,17 @@ impl FaceDirections {
             FaceDirections::Right => FaceDirections::Left,
         }
     }
+    // Index of the face, matches the normals table in shader.wgsl
+    pub fn to_id(&self) -> u32 {
+        match self {
+            FaceDirections::Back => 0,
+            FaceDirections::Bottom => 1,
+            FaceDirections::Top => 2,
+            FaceDirections::Front => 3,
+            FaceDirections::Left => 4,
+            FaceDirections::Right => 5,
+        }
+    }
     pub fn get_normal_vector(&self) -> glam::Vec3 {
         match self {
             FaceDirections::Back => glam::vec3(0.0, 0.0, 1.0),
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use super::block::{Block, BlockVertexData, FaceDirections, TexturedBlock};
+use crate::chunk::BlockVec;
+use crate::collision::CollisionBox;
+
+// Axis aligned box inside of a block, in block units (0.0..=1.0)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShapeBox {
+    pub min: glam::Vec3,
+    pub max: glam::Vec3,
+}
+
+impl ShapeBox {
+    pub const FULL: ShapeBox = ShapeBox::new(0, 0, 0, 16, 16, 16);
+
+    // Takes the bounds in 1/16 of a block
+    pub const fn new(x0: u32, y0: u32, z0: u32, x1: u32, y1: u32, z1: u32) -> Self {
+        Self {
+            min: glam::Vec3::new(x0 as f32 / 16.0, y0 as f32 / 16.0, z0 as f32 / 16.0),
+            max: glam::Vec3::new(x1 as f32 / 16.0, y1 as f32 / 16.0, z1 as f32 / 16.0),
+        }
+    }
+    // Whether the face of the box lies on the face of the block, so that a neighbour can hide it
+    pub fn touches_block_face(&self, face: FaceDirections) -> bool {
+        match face {
+            FaceDirections::Front => self.min.z == 0.0,
+            FaceDirections::Back => self.max.z == 1.0,
+            FaceDirections::Left => self.min.x == 0.0,
+            FaceDirections::Right => self.max.x == 1.0,
+            FaceDirections::Bottom => self.min.y == 0.0,
+            FaceDirections::Top => self.max.y == 1.0,
+        }
+    }
+    // Turns the box around the vertical axis, so that what was towards +z is towards `facing`
+    pub fn rotate(&self, facing: FaceDirections) -> ShapeBox {
+        let (min, max) = (self.min, self.max);
+        let (x, z) = match facing {
+            FaceDirections::Right => ((min.z, max.z), (1.0 - max.x, 1.0 - min.x)),
+            FaceDirections::Front => ((1.0 - max.x, 1.0 - min.x), (1.0 - max.z, 1.0 - min.z)),
+            FaceDirections::Left => ((1.0 - max.z, 1.0 - min.z), (min.x, max.x)),
+            _ => return *self,
+        };
+        ShapeBox {
+            min: glam::vec3(x.0, min.y, z.0),
+            max: glam::vec3(x.1, max.y, z.1),
+        }
+    }
+    // Collision box of the shape box for a block at the given absolute position
+    pub fn to_collision_box(&self, block_position: glam::Vec3) -> CollisionBox {
+        let size = self.max - self.min;
+        let min = block_position + self.min;
+        CollisionBox::new(min.x, min.y, min.z, size.x, size.y, size.z)
+    }
+}
+
+const CUBE_BOXES: [ShapeBox; 1] = [ShapeBox::FULL];
+const SLAB_BOXES: [ShapeBox; 1] = [ShapeBox::new(0, 0, 0, 16, 8, 16)];
+// Ascending towards +z, +x, -z and -x
+const STAIRS_BOXES: [[ShapeBox; 2]; 4] = [
+    [
+        ShapeBox::new(0, 0, 0, 16, 8, 16),
+        ShapeBox::new(0, 8, 8, 16, 16, 16),
+    ],
+    [
+        ShapeBox::new(0, 0, 0, 16, 8, 16),
+        ShapeBox::new(8, 8, 0, 16, 16, 16),
+    ],
+    [
+        ShapeBox::new(0, 0, 0, 16, 8, 16),
+        ShapeBox::new(0, 8, 0, 16, 16, 8),
+    ],
+    [
+        ShapeBox::new(0, 0, 0, 16, 8, 16),
+        ShapeBox::new(0, 8, 0, 8, 16, 16),
+    ],
+];
+const FENCE_BOXES: [ShapeBox; 1] = [ShapeBox::new(6, 0, 6, 10, 16, 10)];
+// Fences are taller than they look, so that they can't be jumped over
+const FENCE_COLLISION_BOXES: [ShapeBox; 1] = [ShapeBox::new(6, 0, 6, 10, 24, 10)];
+const TORCH_BOXES: [ShapeBox; 1] = [ShapeBox::new(7, 0, 7, 9, 10, 9)];
+const CROSS_SELECTION_BOXES: [ShapeBox; 1] = [ShapeBox::new(2, 0, 2, 14, 13, 14)];
+
+lazy_static! {
+    // Block models of the resource packs, by block id
+    static ref BLOCK_MODELS: RwLock<HashMap<u32, Arc<[ShapeBox]>>> = RwLock::new(HashMap::new());
+}
+// Skips the lock while no pack has models, the shapes are looked up for every block that gets meshed
+static HAS_BLOCK_MODELS: AtomicBool = AtomicBool::new(false);
+
+// models: boxes of the blocks whose shape is replaced, by block id
+pub fn set_block_models(models: HashMap<u32, Vec<ShapeBox>>) {
+    let mut block_models = BLOCK_MODELS.write().unwrap();
+    *block_models = models
+        .into_iter()
+        .map(|(id, boxes)| (id, Arc::from(boxes)))
+        .collect();
+    HAS_BLOCK_MODELS.store(!block_models.is_empty(), Ordering::Relaxed);
+}
+
+pub fn get_block_model(block_id: u32) -> Option<BlockShape> {
+    if !HAS_BLOCK_MODELS.load(Ordering::Relaxed) {
+        return None;
+    }
+    let block_models = BLOCK_MODELS.read().unwrap();
+    block_models
+        .get(&block_id)
+        .map(|boxes| BlockShape::Model(boxes.clone()))
+}
+
+/* Parses a block model, a box per line with its bounds in 1/16 of a block:
+ * x0 y0 z0 x1 y1 z1
+ * Empty lines and lines starting with # are ignored
+ */
+pub fn parse_block_model(source: &str) -> Result<Vec<ShapeBox>, String> {
+    source
+        .lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            let bounds = line
+                .split_whitespace()
+                .map(|n| n.parse::<u32>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("line {}: {e}", i + 1))?;
+            let [x0, y0, z0, x1, y1, z1] = bounds[..] else {
+                return Err(format!("line {}: expected 6 numbers", i + 1));
+            };
+            if x0 >= x1 || y0 >= y1 || z0 >= z1 || x1 > 16 || y1 > 16 || z1 > 16 {
+                return Err(format!("line {}: the box isn't inside of the block", i + 1));
+            }
+            Ok(ShapeBox::new(x0, y0, z0, x1, y1, z1))
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlockShape {
+    Cube,
+    Slab,
+    // Ascending towards the direction
+    Stairs(FaceDirections),
+    Fence,
+    // Two diagonal quads, used by plants
+    Cross,
+    Torch,
+    // Boxes loaded from a resource pack
+    Model(Arc<[ShapeBox]>),
+}
+
+impl BlockShape {
+    pub fn is_full_cube(&self) -> bool {
+        *self == BlockShape::Cube
+    }
+    // Boxes that make up the rendered geometry
+    pub fn get_boxes(&self) -> &[ShapeBox] {
+        match self {
+            BlockShape::Cube => &CUBE_BOXES,
+            BlockShape::Slab => &SLAB_BOXES,
+            BlockShape::Stairs(facing) => match facing {
+                FaceDirections::Right => &STAIRS_BOXES[1],
+                FaceDirections::Front => &STAIRS_BOXES[2],
+                FaceDirections::Left => &STAIRS_BOXES[3],
+                _ => &STAIRS_BOXES[0],
+            },
+            BlockShape::Fence => &FENCE_BOXES,
+            BlockShape::Torch => &TORCH_BOXES,
+            BlockShape::Cross => &[],
+            BlockShape::Model(boxes) => boxes,
+        }
+    }
+    // Boxes the player collides with
+    pub fn get_collision_boxes(&self) -> &[ShapeBox] {
+        match self {
+            BlockShape::Fence => &FENCE_COLLISION_BOXES,
+            BlockShape::Cross | BlockShape::Torch => &[],
+            _ => self.get_boxes(),
+        }
+    }
+    // Boxes the player can target with the cursor
+    pub fn get_selection_boxes(&self) -> &[ShapeBox] {
+        match self {
+            BlockShape::Cross => &CROSS_SELECTION_BOXES,
+            _ => self.get_boxes(),
+        }
+    }
+    // visible_faces: block faces that are not hidden by the neighbours
+    pub fn create_mesh_data(
+        &self,
+        block: Arc<RwLock<Block>>,
+        visible_faces: &[FaceDirections],
+        blocks: &Vec<((i32, i32), BlockVec)>,
+    ) -> (Vec<BlockVertexData>, Vec<u32>) {
+        let mut vertex_data = vec![];
+        let mut indices = vec![];
+
+        match self {
+            BlockShape::Cube => {
+                for face in visible_faces.iter() {
+                    let (mut face_vertex, face_indices) =
+                        face.create_face_data(block.clone(), blocks);
+                    let offset = vertex_data.len() as u32;
+                    vertex_data.append(&mut face_vertex);
+                    indices.extend(face_indices.iter().map(|i| i + offset));
+                }
+            }
+            BlockShape::Cross => {
+                let block = block.read().unwrap();
+                let texture_index = block.block_type.get_texture_index(FaceDirections::Front);
+                let origin = block.position - 0.5;
+
+                for (start, end) in [
+                    (glam::vec3(0.0, 0.0, 0.0), glam::vec3(1.0, 0.0, 1.0)),
+                    (glam::vec3(1.0, 0.0, 0.0), glam::vec3(0.0, 0.0, 1.0)),
+                ] {
+                    let offset = vertex_data.len() as u32;
+                    for (corner, uv) in [
+                        (start, [0, 0]),
+                        (start + glam::Vec3::Y, [0, 16]),
+                        (end + glam::Vec3::Y, [16, 16]),
+                        (end, [16, 0]),
+                    ] {
+                        vertex_data.push(BlockVertexData::new(
+                            origin + corner,
+                            FaceDirections::Top,
+                            uv,
+                            texture_index,
+                            3,
+                        ));
+                    }
+                    // Both windings, so that the quad is visible from both sides
+                    indices.extend([0, 1, 2, 0, 2, 3, 0, 2, 1, 0, 3, 2].map(|i| i + offset));
+                }
+            }
+            _ => {
+                for shape_box in self.get_boxes().iter() {
+                    for face in FaceDirections::all().iter() {
+                        if shape_box.touches_block_face(*face) && !visible_faces.contains(face) {
+                            continue;
+                        }
+                        let (mut face_vertex, face_indices) =
+                            face.create_box_face_data(block.clone(), shape_box);
+                        let offset = vertex_data.len() as u32;
+                        vertex_data.append(&mut face_vertex);
+                        indices.extend(face_indices.iter().map(|i| i + offset));
+                    }
+                }
+            }
+        }
+
+        (vertex_data, indices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_block_models() {
+        let model = "# A table\n0 12 0 16 16 16\n\n7 0 7 9 12 9\n";
+        assert_eq!(
+            parse_block_model(model),
+            Ok(vec![
+                ShapeBox::new(0, 12, 0, 16, 16, 16),
+                ShapeBox::new(7, 0, 7, 9, 12, 9)
+            ])
+        );
+        assert!(parse_block_model("0 0 0 16 16").is_err());
+        assert!(parse_block_model("0 0 0 16 17 16").is_err());
+        assert!(parse_block_model("8 0 0 8 16 16").is_err());
+    }
+
+    #[test]
+    fn should_rotate_the_stairs_towards_their_facing() {
+        for facing in [
+            FaceDirections::Back,
+            FaceDirections::Right,
+            FaceDirections::Front,
+            FaceDirections::Left,
+        ] {
+            let shape = BlockShape::Stairs(facing);
+            let rotated = STAIRS_BOXES[0].map(|b| b.rotate(facing));
+            assert_eq!(shape.get_boxes(), &rotated);
+            // The top step is on the side the stairs go up towards
+            assert!(rotated[1].touches_block_face(facing));
+        }
+    }
+}
@@ -1,23 +1,32 @@
-use rand::random;
+use rand::Rng;
 use std::any::Any;
 
+use super::atlas::{self, BlockTextures, TextureAnimation};
 use super::block::{FaceDirections, TexturedBlock};
+use super::block_shape::{self, BlockShape};
 
-#[derive(Clone, Copy, Debug)]
-// This can be 1, 2 [because sometimes we want to reuse the same texture for the bottom as the top]
-pub struct FaceTexture(u32);
 #[derive(Clone, Copy, Debug)]
 pub struct BlockTypeConfigs {
     pub id: u32,
-    // The amount to add to the block id (because some blocks have more than 1 texture)
-    pub step: u32,
-    // Different texture for top face
-    pub top_texture: Option<FaceTexture>,
-    // Different texture for bottom face
-    pub bottom_texture: Option<FaceTexture>,
+    // Tiles of the faces in the atlas
+    pub textures: BlockTextures,
+    pub animation: Option<TextureAnimation>,
     pub is_translucent: bool,
+    // Has holes in its texture (leaves), so the faces behind it are visible even between two of them
+    pub is_cutout: bool,
+    // Faces between two blocks of the same fluid are hidden
+    pub is_fluid: bool,
+    // Geometry and collision boxes of the block
+    pub shape: &'static BlockShape,
+    // Block light level it emits, 0..=15
+    pub light_emission: u8,
+    // Seconds the mouse button is held to break it, 0 breaks it at once
+    pub break_time: f32,
 }
 
+// Number of block types, their ids go from 0 to BLOCK_TYPE_COUNT - 1
+pub const BLOCK_TYPE_COUNT: u32 = 13;
+
 #[repr(u32)]
 #[derive(Clone, Copy, Debug)]
 pub enum BlockType {
@@ -27,6 +36,13 @@ pub enum BlockType {
     Wood(BlockTypeConfigs),
     Leaf(BlockTypeConfigs),
     Stone(BlockTypeConfigs),
+    StoneSlab(BlockTypeConfigs),
+    StoneStairs(BlockTypeConfigs),
+    Fence(BlockTypeConfigs),
+    Flower(BlockTypeConfigs),
+    TallGrass(BlockTypeConfigs),
+    Torch(BlockTypeConfigs),
+    Glowstone(BlockTypeConfigs),
 }
 impl BlockType {
     pub fn from_id(id: u32) -> BlockType {
@@ -37,74 +53,255 @@ impl BlockType {
             3 => Self::stone(),
             4 => Self::wood(),
             5 => Self::grass(),
+            6 => Self::stone_slab(),
+            7 => Self::stone_stairs(),
+            8 => Self::fence(),
+            9 => Self::flower(),
+            10 => Self::tall_grass(),
+            11 => Self::torch(),
+            12 => Self::glowstone(),
             _ => panic!("Invalid id"),
         }
     }
+    pub fn all() -> impl Iterator<Item = BlockType> {
+        (0..BLOCK_TYPE_COUNT).map(Self::from_id)
+    }
     pub fn to_id(&self) -> u32 {
+        self.get_config().id
+    }
+    // Name of the block's textures and model in the resource packs
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Grass(_) => "grass",
+            Self::Dirt(_) => "dirt",
+            Self::Water(_) => "water",
+            Self::Wood(_) => "wood",
+            Self::Leaf(_) => "leaf",
+            Self::Stone(_) => "stone",
+            Self::StoneSlab(_) => "stone_slab",
+            Self::StoneStairs(_) => "stone_stairs",
+            Self::Fence(_) => "fence",
+            Self::Flower(_) => "flower",
+            Self::TallGrass(_) => "tall_grass",
+            Self::Torch(_) => "torch",
+            Self::Glowstone(_) => "glowstone",
+        }
+    }
+    pub fn get_config(&self) -> &BlockTypeConfigs {
         // meh
         match self {
-            Self::Grass(f) => f.id,
-            Self::Dirt(f) => f.id,
-            Self::Water(f) => f.id,
-            Self::Wood(f) => f.id,
-            Self::Leaf(f) => f.id,
-            Self::Stone(f) => f.id,
+            Self::Grass(f) => f,
+            Self::Dirt(f) => f,
+            Self::Water(f) => f,
+            Self::Wood(f) => f,
+            Self::Leaf(f) => f,
+            Self::Stone(f) => f,
+            Self::StoneSlab(f) => f,
+            Self::StoneStairs(f) => f,
+            Self::Fence(f) => f,
+            Self::Flower(f) => f,
+            Self::TallGrass(f) => f,
+            Self::Torch(f) => f,
+            Self::Glowstone(f) => f,
+        }
+    }
+    // The model of the resource packs if there is one
+    pub fn get_shape(&self) -> BlockShape {
+        block_shape::get_block_model(self.to_id())
+            .unwrap_or_else(|| self.get_config().shape.clone())
+    }
+    pub fn is_translucent(&self) -> bool {
+        self.get_config().is_translucent
+    }
+    // Fully hides whatever is behind it
+    pub fn is_opaque(&self) -> bool {
+        let config = self.get_config();
+        !config.is_translucent && !config.is_cutout && self.get_shape().is_full_cube()
+    }
+    // Whether this block hides the face of the neighbouring `block` that touches it
+    pub fn hides_face_of(&self, block: &BlockType) -> bool {
+        let config = self.get_config();
+        if self.is_opaque() {
+            return true;
         }
+        // Cutout blocks can be seen through, so they keep their inner faces
+        if config.is_cutout {
+            return false;
+        }
+        config.is_fluid && self.to_id() == block.to_id()
     }
 
     pub fn dirt() -> Self {
         Self::Dirt(BlockTypeConfigs {
             id: 0,
-            step: 0,
-            bottom_texture: None,
-            top_texture: None,
+            textures: atlas::DIRT,
+            animation: None,
             is_translucent: false,
+            is_cutout: false,
+            is_fluid: false,
+            shape: &BlockShape::Cube,
+            light_emission: 0,
+            break_time: 0.5,
         })
     }
     pub fn water() -> Self {
         Self::Water(BlockTypeConfigs {
             id: 1,
-            step: 0,
-            bottom_texture: None,
-            top_texture: None,
+            textures: atlas::WATER,
+            animation: Some(TextureAnimation {
+                frames: atlas::WATER.frames,
+                frame_time: 0.25,
+            }),
             is_translucent: true,
+            is_cutout: false,
+            is_fluid: true,
+            shape: &BlockShape::Cube,
+            light_emission: 0,
+            break_time: 0.0,
         })
     }
     pub fn leaf() -> Self {
         Self::Leaf(BlockTypeConfigs {
             id: 2,
-            step: 0,
-            bottom_texture: None,
-            top_texture: None,
-            is_translucent: false,
+            textures: atlas::LEAF,
+            animation: None,
+            is_translucent: true,
+            is_cutout: true,
+            is_fluid: false,
+            shape: &BlockShape::Cube,
+            light_emission: 0,
+            break_time: 0.3,
         })
     }
     pub fn stone() -> Self {
         Self::Stone(BlockTypeConfigs {
             id: 3,
-            step: 0,
-            bottom_texture: None,
-            top_texture: None,
+            textures: atlas::STONE,
+            animation: None,
             is_translucent: false,
+            is_cutout: false,
+            is_fluid: false,
+            shape: &BlockShape::Cube,
+            light_emission: 0,
+            break_time: 1.5,
         })
     }
     pub fn wood() -> Self {
         Self::Wood(BlockTypeConfigs {
             id: 4,
-            step: 0,
-            bottom_texture: Some(FaceTexture(1)),
-            top_texture: Some(FaceTexture(1)),
+            textures: atlas::WOOD,
+            animation: None,
             is_translucent: false,
+            is_cutout: false,
+            is_fluid: false,
+            shape: &BlockShape::Cube,
+            light_emission: 0,
+            break_time: 2.0,
         })
     }
 
     pub fn grass() -> Self {
         Self::Grass(BlockTypeConfigs {
             id: 5,
-            step: 1,
-            bottom_texture: Some(FaceTexture(2)),
-            top_texture: Some(FaceTexture(1)),
+            textures: atlas::GRASS,
+            animation: None,
+            is_translucent: false,
+            is_cutout: false,
+            is_fluid: false,
+            shape: &BlockShape::Cube,
+            light_emission: 0,
+            break_time: 0.6,
+        })
+    }
+    pub fn stone_slab() -> Self {
+        Self::StoneSlab(BlockTypeConfigs {
+            id: 6,
+            textures: atlas::STONE,
+            animation: None,
+            is_translucent: false,
+            is_cutout: false,
+            is_fluid: false,
+            shape: &BlockShape::Slab,
+            light_emission: 0,
+            break_time: 1.5,
+        })
+    }
+    pub fn stone_stairs() -> Self {
+        Self::StoneStairs(BlockTypeConfigs {
+            id: 7,
+            textures: atlas::STONE,
+            animation: None,
+            is_translucent: false,
+            is_cutout: false,
+            is_fluid: false,
+            shape: &BlockShape::Stairs(FaceDirections::Back),
+            light_emission: 0,
+            break_time: 1.5,
+        })
+    }
+    pub fn fence() -> Self {
+        Self::Fence(BlockTypeConfigs {
+            id: 8,
+            textures: atlas::WOOD,
+            animation: None,
             is_translucent: false,
+            is_cutout: false,
+            is_fluid: false,
+            shape: &BlockShape::Fence,
+            light_emission: 0,
+            break_time: 2.0,
+        })
+    }
+    pub fn flower() -> Self {
+        Self::Flower(BlockTypeConfigs {
+            id: 9,
+            textures: atlas::FLOWER,
+            animation: None,
+            is_translucent: false,
+            is_cutout: true,
+            is_fluid: false,
+            shape: &BlockShape::Cross,
+            light_emission: 0,
+            break_time: 0.0,
+        })
+    }
+    pub fn tall_grass() -> Self {
+        Self::TallGrass(BlockTypeConfigs {
+            id: 10,
+            textures: atlas::TALL_GRASS,
+            animation: None,
+            is_translucent: false,
+            is_cutout: true,
+            is_fluid: false,
+            shape: &BlockShape::Cross,
+            light_emission: 0,
+            break_time: 0.0,
+        })
+    }
+    pub fn torch() -> Self {
+        Self::Torch(BlockTypeConfigs {
+            id: 11,
+            textures: atlas::TORCH,
+            animation: None,
+            is_translucent: false,
+            is_cutout: true,
+            is_fluid: false,
+            shape: &BlockShape::Torch,
+            light_emission: 14,
+            break_time: 0.0,
+        })
+    }
+    pub fn glowstone() -> Self {
+        Self::Glowstone(BlockTypeConfigs {
+            id: 12,
+            textures: atlas::GLOWSTONE,
+            animation: None,
+            is_translucent: false,
+            is_cutout: false,
+            is_fluid: false,
+            shape: &BlockShape::Cube,
+            light_emission: 15,
+            break_time: 0.3,
         })
     }
 }
@@ -112,9 +309,17 @@ impl BlockType {
     const U_STONE_THRESHOLD: u32 = 20;
     const L_STONE_THRESHOLD: u32 = 1;
 
-    pub fn from_y_position(y: u32) -> BlockType {
+    // Most likely top block of a column of the given height, for when the exact block doesn't matter
+    pub fn surface_from_y_position(y: u32) -> BlockType {
+        if (Self::L_STONE_THRESHOLD..=Self::U_STONE_THRESHOLD + 5).contains(&y) {
+            BlockType::grass()
+        } else {
+            BlockType::stone()
+        }
+    }
+    pub fn from_y_position(y: u32, rng: &mut impl Rng) -> BlockType {
         if y > Self::U_STONE_THRESHOLD {
-            let t: f32 = random();
+            let t: f32 = rng.gen();
             let scaler = (y as f32 - Self::U_STONE_THRESHOLD as f32) / 10.0;
             let res = t + scaler;
             if res > 1.0 {
@@ -130,47 +335,8 @@ impl BlockType {
     }
 }
 
-const TEXTURE_SIZE: u32 = 256;
-const BLOCK_PER_ROW: u32 = 8;
-const BLOCK_OFFSET: u32 = TEXTURE_SIZE / BLOCK_PER_ROW;
-const BLOCK_OFFSET_NORMALIZED: f32 = BLOCK_OFFSET as f32 / TEXTURE_SIZE as f32;
-
-fn get_base_coords(config: &BlockTypeConfigs, face_dir: FaceDirections) -> glam::Vec2 {
-    let face_offset = match face_dir {
-        FaceDirections::Top => config.top_texture.unwrap_or(FaceTexture(0)),
-        FaceDirections::Bottom => config.bottom_texture.unwrap_or(FaceTexture(0)),
-        _ => FaceTexture(0),
-    };
-
-    let position = config.id + config.step + face_offset.0;
-    let wrap = position / BLOCK_PER_ROW;
-
-    let low_bound = 1.0 - (BLOCK_OFFSET_NORMALIZED + (BLOCK_OFFSET_NORMALIZED * wrap as f32));
-    let left_bound = (position as f32 % BLOCK_PER_ROW as f32) / BLOCK_PER_ROW as f32;
-    glam::vec2(left_bound, low_bound)
-}
-fn get_tex_coords(config: &BlockTypeConfigs, face_dir: FaceDirections) -> [[f32; 2]; 4] {
-    let bc = get_base_coords(config, face_dir);
-    [
-        [bc.x, bc.y],
-        [bc.x, bc.y + BLOCK_OFFSET_NORMALIZED],
-        [
-            bc.x + BLOCK_OFFSET_NORMALIZED,
-            bc.y + BLOCK_OFFSET_NORMALIZED,
-        ],
-        [bc.x + BLOCK_OFFSET_NORMALIZED, bc.y],
-    ]
-}
-
 impl TexturedBlock for BlockType {
-    fn get_texcoords(&self, face_dir: FaceDirections) -> [[f32; 2]; 4] {
-        match self {
-            BlockType::Grass(config) => get_tex_coords(config, face_dir),
-            BlockType::Dirt(config) => get_tex_coords(config, face_dir),
-            BlockType::Water(config) => get_tex_coords(config, face_dir),
-            BlockType::Stone(config) => get_tex_coords(config, face_dir),
-            BlockType::Wood(config) => get_tex_coords(config, face_dir),
-            BlockType::Leaf(config) => get_tex_coords(config, face_dir),
-        }
+    fn get_texture_index(&self, face_dir: FaceDirections) -> u32 {
+        self.get_config().textures.get(face_dir)
     }
 }
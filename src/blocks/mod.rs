@@ -1,2 +1,4 @@
+pub mod atlas;
 pub mod block;
+pub mod block_shape;
 pub mod block_type;
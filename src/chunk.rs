@@ -1,37 +1,79 @@
+use crate::arena::MeshArena;
+use crate::culling::{Aabb, SectionVisibility};
+use crate::light::{create_light_vec, LightVec, LightWorld};
+use crate::mesher::{build_section_mesh, ChunkSnapshot, SectionMeshData};
 use crate::persistence::{Loadable, Saveable};
 use crate::world::WorldChunk;
 use crate::{
     blocks::{
-        block::{Block, BlockVertexData, FaceDirections},
+        block::{Block, FaceDirections},
         block_type::BlockType,
     },
     structures::Structure,
-    world::{NoiseData, CHUNK_SIZE, MAX_TREES_PER_CHUNK, NOISE_CHUNK_PER_ROW, NOISE_SIZE},
+    world::{NoiseData, CHUNK_HEIGHT, CHUNK_SIZE, MAX_TREES_PER_CHUNK, NOISE_SIZE},
 };
 use glam::Vec3;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use std::any::Any;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::error::Error;
+use std::ops::Range;
+use std::path::Path;
 use std::rc::Rc;
 use std::sync::{Arc, RwLock};
-use wgpu::util::DeviceExt;
 
 pub type BlockVec = Arc<RwLock<Vec<Vec<Option<Arc<RwLock<Block>>>>>>>;
 
+pub const SECTION_HEIGHT: u32 = 16;
+pub const SECTIONS_PER_CHUNK: usize = (CHUNK_HEIGHT as usize + 1) / SECTION_HEIGHT as usize;
+
+// Where a mesh is inside of the arena's buffers
+#[derive(Debug, Default, Clone)]
+pub struct SectionMesh {
+    pub indices: u32,
+    pub vertex_range: Range<u32>,
+    pub index_range: Range<u32>,
+}
+
+// A vertical slice of the chunk mesh, so that edits only remesh the part that changed
+#[derive(Debug, Default)]
+pub struct ChunkSection {
+    pub opaque: SectionMesh,
+    // Drawn after the opaque meshes, sorted back to front
+    pub translucent: SectionMesh,
+    // Used to skip the sections hidden behind other sections
+    pub visibility: SectionVisibility,
+}
+
+impl ChunkSection {
+    // Uploads the section's meshes to the GPU
+    pub fn new(arena: &mut MeshArena, mesh: &SectionMeshData) -> Self {
+        Self {
+            opaque: arena.upload(&mesh.opaque.vertex, &mesh.opaque.indices),
+            translucent: arena.upload(&mesh.translucent.vertex, &mesh.translucent.indices),
+            visibility: mesh.visibility,
+        }
+    }
+    // Gives the space of the meshes back to the arena
+    pub fn free(&self, arena: &mut MeshArena) {
+        arena.free(&self.opaque);
+        arena.free(&self.translucent);
+    }
+}
+
 #[derive(Debug)]
 pub struct Chunk {
     pub x: i32,
     pub y: i32,
     pub blocks: BlockVec,
-    pub indices: u32,
-    pub device: Arc<wgpu::Device>,
-    pub queue: Arc<wgpu::Queue>,
+    pub light: LightVec,
+    // Bounds of the chunk's blocks, in world space
+    pub aabb: Aabb,
+    pub sections: Vec<ChunkSection>,
     pub noise_data: Arc<NoiseData>,
-    pub chunk_bind_group: wgpu::BindGroup,
-    pub chunk_position_buffer: wgpu::Buffer,
-    pub chunk_index_buffer: Option<wgpu::Buffer>,
-    pub chunk_vertex_buffer: Option<wgpu::Buffer>,
+    // Index of the chunk's position in the arena, set when the chunk is added to the world
+    pub slot: u32,
     pub outside_blocks: Vec<Arc<RwLock<Block>>>,
 }
 
@@ -56,6 +98,7 @@ impl Chunk {
         }
 
         y_blocks[block_position.y as usize] = Some(block);
+        self.aabb.max.y = f32::max(self.aabb.max.y, block_position.y + 0.5);
     }
     pub fn remove_block(&mut self, block_r_position: &Vec3) {
         let mut blocks_borrow = self.blocks.write().unwrap();
@@ -64,21 +107,6 @@ impl Chunk {
             .expect("Cannot delete oob block");
         y_blocks[block_r_position.y as usize] = None;
     }
-    pub fn exists_block_at(&self, position: &glam::Vec3) -> bool {
-        if let Some(y_blocks) = self
-            .blocks
-            .read()
-            .unwrap()
-            .get(((position.x as u32 * CHUNK_SIZE) + position.z as u32) as usize)
-        {
-            if let Some(block_opt) = y_blocks.get(position.y as usize) {
-                if let Some(_) = block_opt {
-                    return true;
-                }
-            }
-        }
-        return false;
-    }
     pub fn get_block_at_relative(&self, position: &glam::Vec3) -> Option<Arc<RwLock<Block>>> {
         if let Some(y_blocks) = self
             .blocks
@@ -110,128 +138,63 @@ impl Chunk {
             false
         }
     }
-    pub fn build_mesh(&self, other_chunks: Vec<WorldChunk>) -> (u32, wgpu::Buffer, wgpu::Buffer) {
-        let mut vertex: Vec<BlockVertexData> = vec![];
-        let mut indices: Vec<u32> = vec![];
-        let mut adjacent_chunks: Vec<((i32, i32), BlockVec)> = vec![((self.x, self.y), self.blocks.clone())];
-
-        for chunk in &other_chunks {
-            let chunk_read = chunk.read().unwrap();
-            if chunk_read.x == self.x + 1 || chunk_read.x == self.x - 1 {
-                adjacent_chunks.push(((chunk_read.x, chunk_read.y), chunk_read.blocks.clone()));
-            } else if chunk_read.y == self.y + 1 || chunk_read.y == self.y - 1 {
-                adjacent_chunks.push(((chunk_read.x, chunk_read.y), chunk_read.blocks.clone()));
-            }
-        }
-
-        for region in self.blocks.read().unwrap().iter() {
-            for y in 0..region.len() {
-                if let Some(block_ptr) = &region[y] {
-                    let block = block_ptr.read().unwrap();
-                    let position = block.position;
-                    let faces = FaceDirections::all();
-
-                    for face in faces.iter() {
-                        let mut is_visible = true;
-                        let face_position = face.get_normal_vector() + position;
-
-                        if Chunk::is_outside_bounds(&face_position) {
-                            is_visible = false;
-                        } else if Chunk::is_outside_chunk(&face_position) {
-                            let target_chunk_x =
-                                self.x + (f32::floor(face_position.x / CHUNK_SIZE as f32) as i32);
-                            let target_chunk_y =
-                                self.y + (f32::floor(face_position.z / CHUNK_SIZE as f32) as i32);
-
-                            let target_block = glam::vec3(
-                                (face_position.x + CHUNK_SIZE as f32) % CHUNK_SIZE as f32,
-                                face_position.y,
-                                (face_position.z + CHUNK_SIZE as f32) % CHUNK_SIZE as f32,
-                            );
-
-                            let target_chunk = other_chunks.iter().find(|c| {
-                                let c = c.read().unwrap();
-                                c.x == target_chunk_x && c.y == target_chunk_y
-                            });
-                            // If there's a chunk loaded in memory then check that, else it means we're on a edge and we can
-                            // Calculate the block's height when the chunk gets generated
-                            // TODO: Check for saved file chunk
-                            match target_chunk {
-                                Some(chunk) => {
-                                    let chunk = chunk.read().unwrap();
-                                    if chunk.exists_block_at(&target_block) {
-                                        is_visible = false;
-                                    }
-                                }
-                                None => {
-                                    if face_position.y as u32
-                                        <= Chunk::get_height_value(
-                                            target_chunk_x,
-                                            target_chunk_y,
-                                            target_block.x as u32,
-                                            target_block.z as u32,
-                                            self.noise_data.clone(),
-                                        )
-                                    {
-                                        is_visible = false
-                                    };
-                                }
-                            }
-                        } else if self.exists_block_at(&face_position) {
-                            is_visible = false;
-                        }
-
-                        if is_visible {
-                            let (mut vertex_data, index_data) =
-                                face.create_face_data(block_ptr.clone(), &adjacent_chunks);
-                            vertex.append(&mut vertex_data);
-                            let indices_offset = vertex.len() as u32 - 4;
-                            indices.append(
-                                &mut index_data.iter().map(|i| i + indices_offset).collect(),
-                            )
-                        }
-                    }
-                }
-            }
-        }
-
-        let chunk_vertex_buffer =
-            self.device
-                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    contents: bytemuck::cast_slice(&vertex),
-                    label: Some(&format!("chunk-vertex-{}-{}", self.x, self.y)),
-                    usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-                });
-        let chunk_index_buffer =
-            self.device
-                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    contents: bytemuck::cast_slice(&indices),
-                    label: Some(&format!("chunk-vertex-{}-{}", self.x, self.y)),
-                    usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
-                });
-
-        (
-            indices.len() as u32,
-            chunk_vertex_buffer,
-            chunk_index_buffer,
+    // Center of the section in world space (blocks are centered on their position)
+    pub fn get_section_center(&self, section: usize) -> glam::Vec3 {
+        let half_size = CHUNK_SIZE as f32 / 2.0;
+        glam::vec3(
+            (self.x * CHUNK_SIZE as i32) as f32 + half_size,
+            (section as u32 * SECTION_HEIGHT) as f32 + SECTION_HEIGHT as f32 / 2.0,
+            (self.y * CHUNK_SIZE as i32) as f32 + half_size,
+        ) - 0.5
+    }
+    // Bounds of the part of the section that can contain blocks
+    pub fn get_section_aabb(&self, section: usize) -> Aabb {
+        let section_min = (section as u32 * SECTION_HEIGHT) as f32 - 0.5;
+        let mut aabb = self.aabb;
+        aabb.min.y = f32::max(aabb.min.y, section_min);
+        aabb.max.y = f32::min(aabb.max.y, section_min + SECTION_HEIGHT as f32);
+        aabb
+    }
+    // Blocks are centered on their position, so the box starts half a block before the chunk
+    fn calc_aabb(x: i32, y: i32, blocks: &BlockVec) -> Aabb {
+        let height = blocks
+            .read()
+            .unwrap()
+            .iter()
+            .map(|c| c.len())
+            .max()
+            .unwrap_or(0);
+        let min = glam::vec3(
+            (x * CHUNK_SIZE as i32) as f32,
+            0.0,
+            (y * CHUNK_SIZE as i32) as f32,
+        ) - 0.5;
+        Aabb::new(
+            min,
+            min + glam::vec3(CHUNK_SIZE as f32, height as f32, CHUNK_SIZE as f32),
         )
     }
-    pub fn get_bind_group_layout() -> wgpu::BindGroupLayoutDescriptor<'static> {
-        wgpu::BindGroupLayoutDescriptor {
-            label: Some("chunk_bind_group"),
-            entries: &[wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::VERTEX,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            }],
+    pub fn get_section_index(y: f32) -> usize {
+        usize::min(y as usize / SECTION_HEIGHT as usize, SECTIONS_PER_CHUNK - 1)
+    }
+    pub fn snapshot(&self) -> ChunkSnapshot {
+        ChunkSnapshot {
+            x: self.x,
+            y: self.y,
+            blocks: self.blocks.clone(),
+            light: self.light.clone(),
         }
     }
-
+    // Builds the CPU mesh of the blocks that are inside the given section
+    pub fn build_mesh(&self, section: usize, other_chunks: &[WorldChunk]) -> SectionMeshData {
+        let neighbours = other_chunks
+            .iter()
+            .map(|c| c.read().unwrap())
+            .filter(|c| (c.x - self.x).abs() <= 1 && (c.y - self.y).abs() <= 1)
+            .map(|c| c.snapshot())
+            .collect::<Vec<_>>();
+        build_section_mesh(&self.snapshot(), &neighbours, &self.noise_data, section)
+    }
     pub fn get_height_value(
         chunk_x: i32,
         chunk_y: i32,
@@ -239,21 +202,24 @@ impl Chunk {
         z: u32,
         noise_data: Arc<NoiseData>,
     ) -> u32 {
-        let mut x = (chunk_x * CHUNK_SIZE as i32) + x as i32 % NOISE_SIZE as i32;
-        let mut z = (chunk_y * CHUNK_SIZE as i32) + z as i32 % NOISE_SIZE as i32;
-
-        if x < 0 {
-            x = NOISE_SIZE as i32 + (x % (NOISE_CHUNK_PER_ROW * CHUNK_SIZE) as i32);
-        }
-        if z < 0 {
-            z = NOISE_SIZE as i32 + (z % (NOISE_CHUNK_PER_ROW * CHUNK_SIZE) as i32);
-        }
+        // The noise repeats every NOISE_SIZE blocks, also for the negative positions
+        let x = (chunk_x * CHUNK_SIZE as i32 + x as i32).rem_euclid(NOISE_SIZE as i32);
+        let z = (chunk_y * CHUNK_SIZE as i32 + z as i32).rem_euclid(NOISE_SIZE as i32);
 
         let y_top = (noise_data[((z * NOISE_SIZE as i32) + x) as usize] + 1.0) * 0.5;
         return (f32::powf(100.0, y_top) - 1.0) as u32;
     }
 
-    pub fn create_blocks_data(chunk_x: i32, chunk_y: i32, noise_data: Arc<NoiseData>) -> BlockVec {
+    // Random numbers of the generation of a chunk, so that a chunk is always generated the same
+    pub fn create_rng(chunk_x: i32, chunk_y: i32) -> StdRng {
+        StdRng::seed_from_u64(((chunk_x as u32 as u64) << 32) | chunk_y as u32 as u64)
+    }
+    pub fn create_blocks_data(
+        chunk_x: i32,
+        chunk_y: i32,
+        noise_data: Arc<NoiseData>,
+        rng: &mut StdRng,
+    ) -> BlockVec {
         let size = (CHUNK_SIZE * CHUNK_SIZE) as usize;
         let mut blocks: BlockVec = Arc::new(RwLock::new(vec![vec![]; size]));
 
@@ -262,7 +228,7 @@ impl Chunk {
                 let y_top = Chunk::get_height_value(chunk_x, chunk_y, x, z, noise_data.clone());
 
                 for y in 0..=y_top {
-                    let block_type = match BlockType::from_y_position(y) {
+                    let block_type = match BlockType::from_y_position(y, rng) {
                         BlockType::Dirt(..) if y == y_top => BlockType::grass(),
                         b => b,
                     };
@@ -281,13 +247,13 @@ impl Chunk {
 
         blocks
     }
-    pub fn place_trees(&mut self) {
-        let number_of_trees = rand::random::<f32>();
+    pub fn place_trees(&mut self, rng: &mut StdRng) {
+        let number_of_trees = rng.gen::<f32>();
         let number_of_trees = f32::floor(number_of_trees * MAX_TREES_PER_CHUNK as f32) as u32;
 
         for _ in 0..number_of_trees {
-            let x = f32::floor(rand::random::<f32>() * CHUNK_SIZE as f32) as usize;
-            let z = f32::floor(rand::random::<f32>() * CHUNK_SIZE as f32) as usize;
+            let x = f32::floor(rng.gen::<f32>() * CHUNK_SIZE as f32) as usize;
+            let z = f32::floor(rng.gen::<f32>() * CHUNK_SIZE as f32) as usize;
 
             let blocks_read = self.blocks.read().unwrap();
             let block_column = blocks_read
@@ -317,62 +283,44 @@ impl Chunk {
         }
     }
 
-    pub fn new(
-        x: i32,
-        y: i32,
-        noise_data: Arc<NoiseData>,
-        device: Arc<wgpu::Device>,
-        queue: Arc<wgpu::Queue>,
-        chunk_data_layout: Arc<wgpu::BindGroupLayout>,
-    ) -> Chunk {
+    pub fn new(x: i32, y: i32, noise_data: Arc<NoiseData>, data_dir: &Path) -> Chunk {
         let mut was_loaded = false;
-        let blocks = if let Ok(blocks) = Self::load(Box::new((x, y))) {
+        let mut rng = Self::create_rng(x, y);
+        let blocks = if let Ok(blocks) = Self::load(data_dir, Box::new((x, y))) {
             was_loaded = true;
             blocks
         } else {
-            Self::create_blocks_data(x, y, noise_data.clone())
+            Self::create_blocks_data(x, y, noise_data.clone(), &mut rng)
         };
 
-        let chunk_position_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            contents: bytemuck::cast_slice(&[x, y]),
-            label: Some(&format!("chunk-position-{x}-{y}")),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
-
-        let chunk_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: chunk_data_layout.as_ref(),
-            label: Some(&format!("chunk-bg-{x}-{y}")),
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: chunk_position_buffer.as_entire_binding(),
-            }],
-        });
-
         let mut chunk = Chunk {
+            aabb: Self::calc_aabb(x, y, &blocks),
             blocks,
+            light: create_light_vec(),
             x,
             y,
-            device,
-            queue,
             noise_data,
-            chunk_vertex_buffer: None,
-            chunk_index_buffer: None,
-            chunk_bind_group,
-            chunk_position_buffer,
-            indices: 0,
+            sections: (0..SECTIONS_PER_CHUNK)
+                .map(|_| ChunkSection::default())
+                .collect(),
+            slot: 0,
             outside_blocks: vec![],
         };
 
         if !was_loaded {
-            chunk.place_trees();
+            chunk.place_trees(&mut rng);
         }
+        // Light from the neighbour chunks gets added once they are loaded
+        LightWorld::new(vec![((x, y), chunk.blocks.clone(), chunk.light.clone())])
+            .init_chunk((x, y));
+
         return chunk;
     }
 }
 
 impl Saveable<Chunk> for Chunk {
-    fn save(&self) -> Result<(), Box<dyn Error>> {
-        if let Ok(_) = std::fs::create_dir("data") {
+    fn save(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        if let Ok(_) = std::fs::create_dir_all(dir) {
             println!("Created dir");
         }
         let mut data = String::new();
@@ -382,27 +330,28 @@ impl Saveable<Chunk> for Chunk {
                 if let Some(block_ptr) = block {
                     let blockbrw = block_ptr.read().unwrap();
                     data += &format!(
-                        "{},{},{},{}\n",
+                        "{},{},{},{},{}\n",
                         blockbrw.position.x,
                         blockbrw.position.y,
                         blockbrw.position.z,
-                        blockbrw.block_type.to_id()
+                        blockbrw.block_type.to_id(),
+                        blockbrw.facing.to_id()
                     );
                 }
             }
         }
 
-        let chunk_file_name = format!("data/chunk{}_{}", self.x, self.y);
-        std::fs::write(chunk_file_name.clone(), data.as_bytes())?;
+        let chunk_file_name = dir.join(format!("chunk{}_{}", self.x, self.y));
+        std::fs::write(chunk_file_name, data.as_bytes())?;
 
         Ok(())
     }
 }
 
 impl Loadable<BlockVec> for Chunk {
-    fn load(args: Box<dyn Any>) -> Result<BlockVec, Box<dyn Error>> {
+    fn load(dir: &Path, args: Box<dyn Any>) -> Result<BlockVec, Box<dyn Error>> {
         if let Ok(chunk_position) = args.downcast::<(i32, i32)>() {
-            for entry in std::fs::read_dir("data")? {
+            for entry in std::fs::read_dir(dir)? {
                 let file = entry?;
                 let filename_chunk = file.file_name();
                 let mut coords = filename_chunk
@@ -418,7 +367,8 @@ impl Loadable<BlockVec> for Chunk {
                 let size = (CHUNK_SIZE * CHUNK_SIZE) as usize;
                 let mut blocks: BlockVec = Arc::new(RwLock::new(vec![vec![]; size]));
                 if *chunk_position == (x, y) {
-                    let file_contents = std::fs::read_to_string(format!("data/chunk{}_{}", x, y))?;
+                    let file_contents =
+                        std::fs::read_to_string(dir.join(format!("chunk{}_{}", x, y)))?;
                     for line in file_contents.lines() {
                         let mut i = line.split(",");
                         let bx = i.next().unwrap().parse::<u32>()?;
@@ -426,12 +376,26 @@ impl Loadable<BlockVec> for Chunk {
                         let bz = i.next().unwrap().parse::<u32>()?;
                         let block_type = i.next().unwrap().parse::<u32>()?;
                         let block_type = BlockType::from_id(block_type);
-
-                        let block = Block::new(
-                            glam::vec3(bx as f32, by as f32, bz as f32),
-                            (x, y),
-                            block_type,
-                        );
+                        // Older saves don't have it
+                        let facing = match i.next() {
+                            Some(id) => {
+                                let id = id.parse::<u32>()?;
+                                FaceDirections::all()
+                                    .into_iter()
+                                    .find(|face| face.to_id() == id)
+                                    .ok_or("Invalid facing")?
+                            }
+                            None => FaceDirections::Back,
+                        };
+
+                        let block = Block {
+                            facing,
+                            ..Block::new(
+                                glam::vec3(bx as f32, by as f32, bz as f32),
+                                (x, y),
+                                block_type,
+                            )
+                        };
                         let y_blocks =
                             &mut blocks.write().unwrap()[((bx * CHUNK_SIZE) + bz) as usize];
                         let start_len = y_blocks.len();
@@ -450,3 +414,32 @@ impl Loadable<BlockVec> for Chunk {
         return Err("Not valid args".into());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_sample_the_height_far_from_the_origin() {
+        let mut noise_data = vec![0.0; (NOISE_SIZE * NOISE_SIZE) as usize];
+        noise_data[(3 * NOISE_SIZE + 2) as usize] = 1.0;
+        let noise_data = Arc::new(noise_data);
+        let chunks = (NOISE_SIZE / CHUNK_SIZE) as i32;
+
+        let height = Chunk::get_height_value(0, 0, 2, 3, noise_data.clone());
+        assert_eq!(height, 99);
+        // The same column of the repeated noise
+        for (chunk_x, chunk_y) in [(chunks, 3 * chunks), (-chunks, -5 * chunks), (100, -100)] {
+            let chunk_x = chunk_x - chunk_x.rem_euclid(chunks);
+            let chunk_y = chunk_y - chunk_y.rem_euclid(chunks);
+            let sampled = Chunk::get_height_value(chunk_x, chunk_y, 2, 3, noise_data.clone());
+            assert_eq!(sampled, height);
+        }
+        // Every column of chunks far away is in bounds
+        for chunk in [48, 64, 1000, -1, -48, -1000] {
+            for x in [0, CHUNK_SIZE - 1] {
+                Chunk::get_height_value(chunk, chunk, x, x, noise_data.clone());
+            }
+        }
+    }
+}
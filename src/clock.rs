@@ -0,0 +1,169 @@
+use std::any::Any;
+use std::error::Error;
+use std::f32::consts;
+use std::path::Path;
+
+use glam::Vec3;
+
+use crate::persistence::{Loadable, Saveable};
+
+// Seconds that a full day lasts at normal speed
+pub const DAY_LENGTH: f32 = 600.0;
+const MIN_SPEED: f32 = 0.125;
+const MAX_SPEED: f32 = 64.0;
+
+const DAY_SKY_COLOR: Vec3 = glam::vec3(0.45, 0.65, 0.95);
+const SUNSET_SKY_COLOR: Vec3 = glam::vec3(0.85, 0.45, 0.25);
+const NIGHT_SKY_COLOR: Vec3 = glam::vec3(0.01, 0.01, 0.04);
+// Sky light multiplier at midnight, so that the surface isn't pitch black
+const NIGHT_SKY_LIGHT: f32 = 0.2;
+
+/* Time of the day, in the 0..1 range
+ * 0.0 is sunrise, 0.25 is noon, 0.5 is sunset and 0.75 is midnight
+ */
+pub struct WorldClock {
+    pub time: f32,
+    // Multiplies how fast the time passes
+    pub speed: f32,
+    pub is_paused: bool,
+}
+
+impl Default for WorldClock {
+    fn default() -> Self {
+        Self {
+            time: 0.1,
+            speed: 1.0,
+            is_paused: false,
+        }
+    }
+}
+
+impl WorldClock {
+    pub fn update(&mut self, delta_time: f32) {
+        if self.is_paused {
+            return;
+        }
+        self.set_time(self.time + delta_time * self.speed / DAY_LENGTH);
+    }
+    pub fn set_time(&mut self, time: f32) {
+        self.time = time.rem_euclid(1.0);
+    }
+    // Jumps to the next sunrise, noon, sunset or midnight
+    pub fn skip_to_next_quarter(&mut self) {
+        self.set_time((f32::floor(self.time * 4.0) + 1.0) / 4.0);
+    }
+    pub fn toggle_pause(&mut self) {
+        self.is_paused = !self.is_paused;
+    }
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+    }
+    // Direction from the world towards the sun, the moon is on the opposite side
+    pub fn sun_direction(&self) -> Vec3 {
+        let angle = self.time * consts::TAU;
+        glam::vec3(f32::cos(angle), f32::sin(angle), 0.3).normalize()
+    }
+    // How much the sun lights the world, 0 at night and 1 at noon
+    pub fn daylight(&self) -> f32 {
+        let height = self.sun_direction().y;
+        // Light fades in and out while the sun is close to the horizon
+        let t = ((height + 0.1) / 0.4).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+    pub fn sky_light_multiplier(&self) -> f32 {
+        NIGHT_SKY_LIGHT + (1.0 - NIGHT_SKY_LIGHT) * self.daylight()
+    }
+    pub fn sky_color(&self) -> Vec3 {
+        let daylight = self.daylight();
+        let color = NIGHT_SKY_COLOR.lerp(DAY_SKY_COLOR, daylight);
+        // Sunrises and sunsets are tinted orange
+        let sunset = 1.0 - f32::min(self.sun_direction().y.abs() / 0.3, 1.0);
+        color.lerp(SUNSET_SKY_COLOR, sunset * daylight * 0.6)
+    }
+}
+
+impl WorldClock {
+    // `time,speed,is_paused`
+    fn to_data(&self) -> String {
+        format!("{},{},{}", self.time, self.speed, self.is_paused)
+    }
+    fn from_data(data: &str) -> Result<WorldClock, Box<dyn Error>> {
+        let mut data = data.split(",");
+        let time = data.next().ok_or("Missing time")?.parse::<f32>()?;
+        let speed = data.next().ok_or("Missing speed")?.parse::<f32>()?;
+        // Older saves don't have it
+        let is_paused = data.next().map(str::parse::<bool>).transpose()?;
+
+        let mut clock = WorldClock::default();
+        clock.set_time(time);
+        clock.set_speed(speed);
+        clock.is_paused = is_paused.unwrap_or(false);
+        Ok(clock)
+    }
+}
+
+impl Saveable<WorldClock> for WorldClock {
+    fn save(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        if std::fs::create_dir_all(dir).is_ok() {
+            println!("Created dir");
+        }
+        std::fs::write(dir.join("clock"), self.to_data().as_bytes())?;
+
+        Ok(())
+    }
+}
+
+impl Loadable<WorldClock> for WorldClock {
+    fn load(dir: &Path, _: Box<dyn Any>) -> Result<WorldClock, Box<dyn Error>> {
+        let data = String::from_utf8(std::fs::read(dir.join("clock"))?)?;
+        WorldClock::from_data(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_wrap_the_time_around_the_day() {
+        let mut clock = WorldClock::default();
+        clock.set_time(0.9);
+        clock.update(DAY_LENGTH * 0.2);
+        assert!((clock.time - 0.1).abs() < 1e-4);
+
+        clock.toggle_pause();
+        clock.update(DAY_LENGTH);
+        assert!((clock.time - 0.1).abs() < 1e-4);
+
+        clock.skip_to_next_quarter();
+        assert_eq!(clock.time, 0.25);
+    }
+
+    #[test]
+    fn should_save_whether_the_clock_is_paused() {
+        let mut clock = WorldClock::default();
+        clock.set_speed(4.0);
+        clock.toggle_pause();
+        let loaded = WorldClock::from_data(&clock.to_data()).unwrap();
+        assert_eq!(loaded.time, clock.time);
+        assert_eq!(loaded.speed, 4.0);
+        assert!(loaded.is_paused);
+
+        assert!(!WorldClock::from_data("0.5,1").unwrap().is_paused);
+        assert!(WorldClock::from_data("0.5,1,maybe").is_err());
+    }
+
+    #[test]
+    fn should_be_brighter_at_noon_than_at_midnight() {
+        let mut clock = WorldClock::default();
+        clock.set_time(0.25);
+        assert!(clock.sun_direction().y > 0.9);
+        assert_eq!(clock.daylight(), 1.0);
+        assert_eq!(clock.sky_light_multiplier(), 1.0);
+
+        clock.set_time(0.75);
+        assert_eq!(clock.daylight(), 0.0);
+        assert_eq!(clock.sky_light_multiplier(), NIGHT_SKY_LIGHT);
+        assert!(clock.sky_color().length() < DAY_SKY_COLOR.length());
+    }
+}
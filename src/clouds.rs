@@ -0,0 +1,514 @@
+use bytemuck::{Pod, Zeroable};
+use wgpu::util::DeviceExt;
+use wgpu::{BindGroup, Buffer, RenderPipeline};
+
+use crate::material::Texture;
+use crate::pipeline::{EnvironmentUniforms, PipelineTrait, PipelineType, Uniforms};
+use crate::post::HDR_FORMAT;
+use crate::render_graph::{Frame, RenderNode};
+use crate::state::State;
+use crate::utils::noise;
+
+// Width of a cloud box, in blocks
+const CELL_SIZE: f32 = 12.0;
+// The cloud layer repeats every CLOUD_CELLS boxes, it's wider than the fog so that it's never seen twice
+const CLOUD_CELLS: u32 = 48;
+// Cells per noise unit, CLOUD_CELLS / NOISE_SCALE is the period of the noise
+const NOISE_SCALE: u32 = 6;
+// Part of the sky covered by the clouds grows with it, -1..1
+const COVERAGE: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloudStyle {
+    Off,
+    // A single quad per cell
+    Flat,
+    // Extruded boxes
+    Boxes,
+}
+
+impl CloudStyle {
+    pub fn next(self) -> Self {
+        match self {
+            CloudStyle::Off => CloudStyle::Flat,
+            CloudStyle::Flat => CloudStyle::Boxes,
+            CloudStyle::Boxes => CloudStyle::Off,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CloudSettings {
+    pub style: CloudStyle,
+    // Height of the bottom of the clouds
+    pub altitude: f32,
+    // Height of the boxes
+    pub thickness: f32,
+    // Blocks per second along x
+    pub speed: f32,
+}
+
+impl Default for CloudSettings {
+    fn default() -> Self {
+        Self {
+            style: CloudStyle::Boxes,
+            altitude: 110.0,
+            thickness: 4.0,
+            speed: 1.5,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable)]
+pub struct CloudVertex {
+    // Relative to the corner of the box
+    pub position: [f32; 3],
+    // Corner of the box in the cloud layer, the whole box is moved with it
+    pub cell: [f32; 2],
+    // Brightness of the face
+    pub shade: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable)]
+pub struct CloudUniforms {
+    pub altitude: f32,
+    pub speed: f32,
+    // Width of the cloud layer before it repeats
+    pub period: f32,
+    pub _padding: f32,
+}
+
+impl From<&CloudSettings> for CloudUniforms {
+    fn from(settings: &CloudSettings) -> Self {
+        Self {
+            altitude: settings.altitude,
+            speed: settings.speed,
+            period: CLOUD_CELLS as f32 * CELL_SIZE,
+            _padding: 0.0,
+        }
+    }
+}
+
+// Cells of the layer covered by a cloud, indexed by x * CLOUD_CELLS + z
+pub fn cloud_cells() -> Vec<bool> {
+    let period = CLOUD_CELLS / NOISE_SCALE;
+    (0..CLOUD_CELLS * CLOUD_CELLS)
+        .map(|i| {
+            let x = (i / CLOUD_CELLS) as f32 / NOISE_SCALE as f32;
+            let z = (i % CLOUD_CELLS) as f32 / NOISE_SCALE as f32;
+            noise::fbm(x, z, period, 2) > COVERAGE
+        })
+        .collect()
+}
+
+/* Boxes of the covered cells. The faces between two boxes are hidden, so they are skipped,
+ * the layer wraps around so the cells on its edges are neighbours too
+ */
+pub fn create_cloud_mesh(
+    cells: &[bool],
+    style: CloudStyle,
+    thickness: f32,
+) -> (Vec<CloudVertex>, Vec<u32>) {
+    let mut vertices = vec![];
+    let mut indices = vec![];
+    let covered =
+        |x: u32, z: u32| cells[((x % CLOUD_CELLS) * CLOUD_CELLS + z % CLOUD_CELLS) as usize];
+    let (s, h) = (CELL_SIZE, thickness);
+
+    for x in 0..CLOUD_CELLS {
+        for z in 0..CLOUD_CELLS {
+            if style == CloudStyle::Off || !covered(x, z) {
+                continue;
+            }
+            // (corners, shade, whether a neighbour hides it)
+            let mut faces = vec![(
+                [[0.0, 0.0, 0.0], [s, 0.0, 0.0], [s, 0.0, s], [0.0, 0.0, s]],
+                0.7,
+                false,
+            )];
+            if style == CloudStyle::Boxes {
+                faces.extend([
+                    (
+                        [[0.0, h, 0.0], [0.0, h, s], [s, h, s], [s, h, 0.0]],
+                        1.0,
+                        false,
+                    ),
+                    (
+                        [[0.0, 0.0, 0.0], [0.0, 0.0, s], [0.0, h, s], [0.0, h, 0.0]],
+                        0.85,
+                        covered(x + CLOUD_CELLS - 1, z),
+                    ),
+                    (
+                        [[s, 0.0, 0.0], [s, h, 0.0], [s, h, s], [s, 0.0, s]],
+                        0.85,
+                        covered(x + 1, z),
+                    ),
+                    (
+                        [[0.0, 0.0, 0.0], [0.0, h, 0.0], [s, h, 0.0], [s, 0.0, 0.0]],
+                        0.9,
+                        covered(x, z + CLOUD_CELLS - 1),
+                    ),
+                    (
+                        [[0.0, 0.0, s], [s, 0.0, s], [s, h, s], [0.0, h, s]],
+                        0.9,
+                        covered(x, z + 1),
+                    ),
+                ]);
+            }
+            let cell = [x as f32 * CELL_SIZE, z as f32 * CELL_SIZE];
+            for (corners, shade, hidden) in faces {
+                if hidden {
+                    continue;
+                }
+                let first = vertices.len() as u32;
+                vertices.extend(corners.map(|position| CloudVertex {
+                    position,
+                    cell,
+                    shade,
+                }));
+                indices.extend([0, 1, 2, 0, 2, 3].map(|i| first + i));
+            }
+        }
+    }
+    (vertices, indices)
+}
+
+// Mesh of the cloud layer, rebuilt when the settings change
+pub struct Clouds {
+    pub vertex_buffer: wgpu::Buffer,
+    pub index_buffer: wgpu::Buffer,
+    pub index_count: u32,
+    pub settings: CloudSettings,
+}
+
+impl Clouds {
+    pub fn new(device: &wgpu::Device, settings: CloudSettings) -> Self {
+        let (vertices, indices) =
+            create_cloud_mesh(&cloud_cells(), settings.style, settings.thickness);
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("cloud_vertices"),
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("cloud_indices"),
+            contents: bytemuck::cast_slice(&indices),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+        Self {
+            vertex_buffer,
+            index_buffer,
+            index_count: indices.len() as u32,
+            settings,
+        }
+    }
+    pub fn update(&mut self, device: &wgpu::Device, settings: CloudSettings) {
+        if settings.style != self.settings.style || settings.thickness != self.settings.thickness {
+            *self = Self::new(device, settings);
+        }
+        self.settings = settings;
+    }
+
+    pub fn get_vertex_data_layout() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<CloudVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                // Position
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x3,
+                    offset: 0,
+                    shader_location: 0,
+                },
+                // Cell
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x2,
+                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                },
+                // Shade
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32,
+                    offset: std::mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                },
+            ],
+        }
+    }
+}
+
+// Node of the render graph drawing the clouds over the world, before the post-process
+pub fn draw_cloud_pass<'a>(
+    _node: &'a RenderNode,
+    pipeline: &'a dyn PipelineTrait,
+    frame: &'a Frame<'a>,
+    rpass: &mut wgpu::RenderPass<'a>,
+) {
+    let clouds = &frame.state.clouds;
+    if clouds.index_count == 0 {
+        return;
+    }
+    rpass.set_bind_group(0, pipeline.bind_group_0(), &[]);
+    rpass.set_bind_group(1, pipeline.bind_group_1(), &[]);
+    rpass.set_vertex_buffer(0, clouds.vertex_buffer.slice(..));
+    rpass.set_index_buffer(clouds.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+    // The depth of the nearest faces first, so that the faces behind them aren't blended too
+    rpass.set_pipeline(pipeline.pipeline());
+    rpass.draw_indexed(0..clouds.index_count, 0, 0..1);
+    if let Some(color_pipeline) = pipeline.translucent_pipeline() {
+        rpass.set_pipeline(color_pipeline);
+        rpass.draw_indexed(0..clouds.index_count, 0, 0..1);
+    }
+}
+
+pub struct CloudPipeline {
+    pub projection_buffer: wgpu::Buffer,
+    pub view_buffer: wgpu::Buffer,
+    pub environment_buffer: wgpu::Buffer,
+    // Only writes the depth of the clouds
+    pub pipeline: wgpu::RenderPipeline,
+    // Blends the nearest faces over the world
+    pub color_pipeline: wgpu::RenderPipeline,
+    pub bind_group_0: wgpu::BindGroup,
+    pub bind_group_1: wgpu::BindGroup,
+    pub pipeline_type: PipelineType,
+}
+
+impl CloudPipeline {
+    pub fn new(state: &State) -> Self {
+        let shader_source = state.resources.shader("clouds.wgsl");
+
+        let shader = state
+            .device
+            .create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(shader_source.into()),
+            });
+
+        let uniforms = Uniforms::from(&state.player.camera);
+
+        let projection_buffer =
+            state
+                .device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("projection_matrix"),
+                    contents: bytemuck::cast_slice(&[uniforms.projection]),
+                    usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                });
+
+        // View matrix
+        let view_buffer = state
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("projection_matrix"),
+                contents: bytemuck::cast_slice(&[uniforms.view]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+
+        // Drives the drift, the fog and the light of the clouds
+        let environment_buffer =
+            state
+                .device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("environment"),
+                    contents: bytemuck::cast_slice(&[EnvironmentUniforms::from(
+                        &state.world.clock,
+                    )]),
+                    usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                });
+        let cloud_buffer = state
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("clouds"),
+                contents: bytemuck::cast_slice(&[CloudUniforms::from(&state.config.clouds)]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+
+        let uniform_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        // Bind groups
+        let bind_group_0_layout =
+            state
+                .device
+                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    label: Some("bind_group_0"),
+                    entries: &[uniform_entry(0), uniform_entry(1), uniform_entry(2)],
+                });
+        let bind_group_0 = state.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_0_layout,
+            label: None,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: projection_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: view_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: environment_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let bind_group_1_layout =
+            state
+                .device
+                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    label: Some("bind_group_1"),
+                    entries: &[uniform_entry(0)],
+                });
+        let bind_group_1 = state.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("bind_group_1"),
+            layout: &bind_group_1_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: cloud_buffer.as_entire_binding(),
+            }],
+        });
+
+        // Pipeline layouts
+        let pipeline_layout =
+            state
+                .device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: None,
+                    bind_group_layouts: &[&bind_group_0_layout, &bind_group_1_layout],
+                    push_constant_ranges: &[],
+                });
+
+        let create_pipeline = |label: &str, color_writes, depth_write_enabled, depth_compare| {
+            state
+                .device
+                .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some(label),
+                    layout: Some(&pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: "vs_main",
+                        buffers: &[Clouds::get_vertex_data_layout()],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader,
+                        entry_point: "fs_main",
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format: HDR_FORMAT,
+                            blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                            write_mask: color_writes,
+                        })],
+                    }),
+                    // Seen from under and above
+                    primitive: wgpu::PrimitiveState {
+                        cull_mode: None,
+                        ..Default::default()
+                    },
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: Texture::DEPTH_FORMAT,
+                        depth_write_enabled,
+                        depth_compare,
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState::default(),
+                    }),
+                    multisample: wgpu::MultisampleState {
+                        count: state.config.post.anti_aliasing.samples(),
+                        ..Default::default()
+                    },
+                    multiview: None,
+                })
+        };
+        let depth_pipeline = create_pipeline(
+            "cloud_depth",
+            wgpu::ColorWrites::empty(),
+            true,
+            wgpu::CompareFunction::Less,
+        );
+        // Only the faces that won the depth test of the first pass
+        let color_pipeline = create_pipeline(
+            "cloud_color",
+            wgpu::ColorWrites::ALL,
+            false,
+            wgpu::CompareFunction::Equal,
+        );
+
+        Self {
+            projection_buffer,
+            view_buffer,
+            environment_buffer,
+            pipeline: depth_pipeline,
+            color_pipeline,
+            bind_group_0,
+            bind_group_1,
+            pipeline_type: PipelineType::CLOUDS,
+        }
+    }
+}
+
+impl PipelineTrait for CloudPipeline {
+    fn projection_buffer(&self) -> &Buffer {
+        &self.projection_buffer
+    }
+
+    fn pipeline(&self) -> &RenderPipeline {
+        &self.pipeline
+    }
+
+    fn translucent_pipeline(&self) -> Option<&RenderPipeline> {
+        Some(&self.color_pipeline)
+    }
+
+    fn environment_buffer(&self) -> Option<&Buffer> {
+        Some(&self.environment_buffer)
+    }
+
+    fn view_buffer(&self) -> &Buffer {
+        &self.view_buffer
+    }
+
+    fn bind_group_0(&self) -> &BindGroup {
+        &self.bind_group_0
+    }
+
+    fn bind_group_1(&self) -> &BindGroup {
+        &self.bind_group_1
+    }
+
+    fn get_type(&self) -> PipelineType {
+        self.pipeline_type
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_skip_the_faces_between_two_boxes() {
+        let mut cells = vec![false; (CLOUD_CELLS * CLOUD_CELLS) as usize];
+        // Two boxes side by side across the edge of the layer, which wraps around
+        cells[0] = true;
+        cells[((CLOUD_CELLS - 1) * CLOUD_CELLS) as usize] = true;
+
+        let (vertices, indices) = create_cloud_mesh(&cells, CloudStyle::Boxes, 4.0);
+        // 5 faces each
+        assert_eq!(indices.len(), 2 * 5 * 6);
+        assert_eq!(vertices.len(), 2 * 5 * 4);
+
+        let (_, indices) = create_cloud_mesh(&cells, CloudStyle::Flat, 4.0);
+        assert_eq!(indices.len(), 2 * 6);
+        let (_, indices) = create_cloud_mesh(&cells, CloudStyle::Off, 4.0);
+        assert!(indices.is_empty());
+    }
+}
@@ -105,16 +105,6 @@ impl CollisionBox {
             self.min_z + (self.max_z - self.min_z) / 2.0,
         )
     }
-    pub fn from_block_position(x: f32, y: f32, z: f32) -> Self {
-        CollisionBox {
-            min_x: x,
-            max_x: x + 1.0,
-            min_y: y,
-            max_y: y + 1.0,
-            min_z: z,
-            max_z: z + 1.0,
-        }
-    }
     pub fn to_block_position(&self) -> glam::Vec3 {
         return glam::vec3(self.min_x, self.min_y, self.min_z);
     }
@@ -0,0 +1,327 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use glam::{Mat4, Vec3, Vec4};
+
+use crate::blocks::block::FaceDirections;
+use crate::chunk::{Chunk, SECTIONS_PER_CHUNK, SECTION_HEIGHT};
+use crate::player::Camera;
+use crate::world::CHUNK_SIZE;
+
+// Axis aligned bounding box in world space
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self { min, max }
+    }
+}
+
+/* The 6 planes of the camera's view volume, pointing inwards
+ * https://www.gamedevs.org/uploads/fast-extraction-viewing-frustum-planes-from-world-view-projection-matrix.pdf
+ */
+pub struct Frustum {
+    planes: [Vec4; 6],
+}
+
+impl Frustum {
+    pub fn from_matrix(view_projection: Mat4) -> Self {
+        let (row0, row1, row2, row3) = (
+            view_projection.row(0),
+            view_projection.row(1),
+            view_projection.row(2),
+            view_projection.row(3),
+        );
+        // Depth goes from 0 to 1, so the near plane is the third row alone
+        let planes = [
+            row3 + row0,
+            row3 - row0,
+            row3 + row1,
+            row3 - row1,
+            row2,
+            row3 - row2,
+        ]
+        .map(|plane| plane / plane.truncate().length());
+
+        Self { planes }
+    }
+    pub fn from_camera(camera: &Camera) -> Self {
+        Self::from_matrix(camera.build_projection_matrix() * camera.build_view_matrix())
+    }
+    // Whether some part of the box is inside, boxes close to a corner might give false positives
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // Corner of the box that is the furthest along the plane's normal
+            let corner = Vec3::select(plane.truncate().cmpge(Vec3::ZERO), aabb.max, aabb.min);
+            plane.truncate().dot(corner) + plane.w >= 0.0
+        })
+    }
+}
+
+// How many chunks and sections the last frame drew and skipped
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CullingStats {
+    pub chunks_total: u32,
+    pub chunks_culled: u32,
+    pub sections_total: u32,
+    pub sections_culled: u32,
+    // Sections inside of the frustum hidden by the visibility graph, included in sections_culled
+    pub sections_occluded: u32,
+}
+
+impl std::fmt::Display for CullingStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "chunks {}/{} sections {}/{} occluded {}",
+            self.chunks_total - self.chunks_culled,
+            self.chunks_total,
+            self.sections_total - self.sections_culled,
+            self.sections_total,
+            self.sections_occluded,
+        )
+    }
+}
+
+/* Which faces of a chunk section can be seen from each other through non-opaque blocks
+ * One bit for every pair of faces, indexed by FaceDirections::to_id
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SectionVisibility(u64);
+
+impl Default for SectionVisibility {
+    // An empty section can be seen through from everywhere
+    fn default() -> Self {
+        Self(u64::MAX)
+    }
+}
+
+impl SectionVisibility {
+    const SECTION_CELLS: usize = (CHUNK_SIZE * CHUNK_SIZE * SECTION_HEIGHT) as usize;
+
+    pub fn cell_index(x: usize, y: usize, z: usize) -> usize {
+        (x * CHUNK_SIZE as usize + z) * SECTION_HEIGHT as usize + y
+    }
+    /* Flood fills the non-opaque cells of the section, faces touched by the same fill are connected
+     * opaque_cells: indexed by cell_index, with the section-local position
+     */
+    pub fn from_opaque_cells(opaque_cells: &[bool]) -> Self {
+        debug_assert_eq!(opaque_cells.len(), Self::SECTION_CELLS);
+        let size = CHUNK_SIZE as i32;
+        let height = SECTION_HEIGHT as i32;
+
+        let mut visibility = Self(0);
+        let mut visited = vec![false; Self::SECTION_CELLS];
+
+        for start in 0..Self::SECTION_CELLS {
+            if opaque_cells[start] || visited[start] {
+                continue;
+            }
+            visited[start] = true;
+            let mut faces: Vec<FaceDirections> = vec![];
+            let mut queue = VecDeque::from([start]);
+
+            while let Some(cell) = queue.pop_front() {
+                let y = (cell % SECTION_HEIGHT as usize) as i32;
+                let z = ((cell / SECTION_HEIGHT as usize) % CHUNK_SIZE as usize) as i32;
+                let x = (cell / (SECTION_HEIGHT * CHUNK_SIZE) as usize) as i32;
+
+                for face in FaceDirections::all().iter() {
+                    let normal = face.get_normal_vector();
+                    let (nx, ny, nz) = (
+                        x + normal.x as i32,
+                        y + normal.y as i32,
+                        z + normal.z as i32,
+                    );
+
+                    if nx < 0 || nx >= size || ny < 0 || ny >= height || nz < 0 || nz >= size {
+                        if !faces.contains(face) {
+                            faces.push(*face);
+                        }
+                        continue;
+                    }
+                    let neighbour = Self::cell_index(nx as usize, ny as usize, nz as usize);
+                    if !opaque_cells[neighbour] && !visited[neighbour] {
+                        visited[neighbour] = true;
+                        queue.push_back(neighbour);
+                    }
+                }
+            }
+            for a in faces.iter() {
+                for b in faces.iter() {
+                    visibility.connect(*a, *b);
+                }
+            }
+        }
+        visibility
+    }
+    fn connect(&mut self, a: FaceDirections, b: FaceDirections) {
+        self.0 |= 1 << (a.to_id() * 6 + b.to_id());
+        self.0 |= 1 << (b.to_id() * 6 + a.to_id());
+    }
+    pub fn is_connected(&self, a: FaceDirections, b: FaceDirections) -> bool {
+        self.0 & (1 << (a.to_id() * 6 + b.to_id())) != 0
+    }
+}
+
+/* Sections that can be seen from the camera, going through the faces connected by the visibility graph
+ * https://tomcc.github.io/2014/08/31/visibility-1.html
+ * Returns (index in `chunks`, section) pairs, or None if the camera isn't inside of a loaded section
+ */
+pub fn find_reachable_sections(
+    chunks: &[&Chunk],
+    eye: Vec3,
+    frustum: &Frustum,
+) -> Option<HashSet<(usize, usize)>> {
+    let chunk_indices: HashMap<(i32, i32), usize> = chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| ((chunk.x, chunk.y), i))
+        .collect();
+
+    // Blocks are centered on their position
+    let position = eye + 0.5;
+    if position.y < 0.0 || position.y >= (SECTIONS_PER_CHUNK as u32 * SECTION_HEIGHT) as f32 {
+        return None;
+    }
+    let camera_chunk = (
+        f32::floor(position.x / CHUNK_SIZE as f32) as i32,
+        f32::floor(position.z / CHUNK_SIZE as f32) as i32,
+    );
+    let camera_section = (
+        *chunk_indices.get(&camera_chunk)?,
+        Chunk::get_section_index(position.y),
+    );
+
+    let mut reachable = HashSet::from([camera_section]);
+    // (chunk index, section, face it was entered from, directions travelled so far)
+    let mut queue: VecDeque<(usize, usize, Option<FaceDirections>, Vec<FaceDirections>)> =
+        VecDeque::from([(camera_section.0, camera_section.1, None, vec![])]);
+
+    while let Some((chunk_index, section, entered_from, directions)) = queue.pop_front() {
+        let chunk = chunks[chunk_index];
+        let visibility = chunk.sections[section].visibility;
+
+        for direction in FaceDirections::all().iter() {
+            // Never go back towards the camera
+            if directions.contains(&direction.opposite()) {
+                continue;
+            }
+            if let Some(entered_from) = entered_from {
+                if !visibility.is_connected(entered_from, *direction) {
+                    continue;
+                }
+            }
+            let normal = direction.get_normal_vector();
+            let neighbour_section = section as i32 + normal.y as i32;
+            if neighbour_section < 0 || neighbour_section >= SECTIONS_PER_CHUNK as i32 {
+                continue;
+            }
+            let Some(neighbour_chunk) =
+                chunk_indices.get(&(chunk.x + normal.x as i32, chunk.y + normal.z as i32))
+            else {
+                continue;
+            };
+            let neighbour = (*neighbour_chunk, neighbour_section as usize);
+            if reachable.contains(&neighbour) {
+                continue;
+            }
+            let neighbour_chunk = chunks[neighbour.0];
+            let min = glam::vec3(
+                (neighbour_chunk.x * CHUNK_SIZE as i32) as f32,
+                (neighbour.1 as u32 * SECTION_HEIGHT) as f32,
+                (neighbour_chunk.y * CHUNK_SIZE as i32) as f32,
+            ) - 0.5;
+            let bounds = Aabb::new(
+                min,
+                min + glam::vec3(CHUNK_SIZE as f32, SECTION_HEIGHT as f32, CHUNK_SIZE as f32),
+            );
+            if !frustum.intersects(&bounds) {
+                continue;
+            }
+
+            reachable.insert(neighbour);
+            let mut neighbour_directions = directions.clone();
+            if !neighbour_directions.contains(direction) {
+                neighbour_directions.push(*direction);
+            }
+            queue.push_back((
+                neighbour.0,
+                neighbour.1,
+                Some(direction.opposite()),
+                neighbour_directions,
+            ));
+        }
+    }
+    Some(reachable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts;
+
+    fn create_frustum() -> Frustum {
+        // Looking towards +z from the origin
+        let view = Mat4::look_at_lh(Vec3::ZERO, Vec3::Z, Vec3::Y);
+        let projection = Mat4::perspective_lh(consts::FRAC_PI_2, 1.0, 0.1, 100.0);
+        Frustum::from_matrix(projection * view)
+    }
+
+    #[test]
+    fn should_keep_boxes_in_front_of_the_camera() {
+        let frustum = create_frustum();
+        let aabb = Aabb::new(glam::vec3(-1.0, -1.0, 10.0), glam::vec3(1.0, 1.0, 12.0));
+        assert!(frustum.intersects(&aabb));
+
+        // Only partially inside
+        let aabb = Aabb::new(glam::vec3(5.0, -1.0, 4.0), glam::vec3(20.0, 1.0, 6.0));
+        assert!(frustum.intersects(&aabb));
+    }
+
+    #[test]
+    fn should_cull_boxes_outside_of_the_view() {
+        let frustum = create_frustum();
+        // Behind
+        let aabb = Aabb::new(glam::vec3(-1.0, -1.0, -12.0), glam::vec3(1.0, 1.0, -10.0));
+        assert!(!frustum.intersects(&aabb));
+        // Too far to the side
+        let aabb = Aabb::new(glam::vec3(20.0, -1.0, 10.0), glam::vec3(22.0, 1.0, 12.0));
+        assert!(!frustum.intersects(&aabb));
+        // Past the far plane
+        let aabb = Aabb::new(glam::vec3(-1.0, -1.0, 110.0), glam::vec3(1.0, 1.0, 120.0));
+        assert!(!frustum.intersects(&aabb));
+    }
+
+    #[test]
+    fn should_connect_the_faces_through_empty_space() {
+        let cells = vec![false; SectionVisibility::SECTION_CELLS];
+        let visibility = SectionVisibility::from_opaque_cells(&cells);
+        assert!(visibility.is_connected(FaceDirections::Top, FaceDirections::Bottom));
+        assert!(visibility.is_connected(FaceDirections::Left, FaceDirections::Front));
+
+        let cells = vec![true; SectionVisibility::SECTION_CELLS];
+        let visibility = SectionVisibility::from_opaque_cells(&cells);
+        assert!(!visibility.is_connected(FaceDirections::Top, FaceDirections::Bottom));
+        assert!(!visibility.is_connected(FaceDirections::Left, FaceDirections::Right));
+    }
+
+    #[test]
+    fn should_split_the_faces_separated_by_a_wall() {
+        // Opaque floor in the middle of the section
+        let mut cells = vec![false; SectionVisibility::SECTION_CELLS];
+        for x in 0..CHUNK_SIZE as usize {
+            for z in 0..CHUNK_SIZE as usize {
+                cells[SectionVisibility::cell_index(x, 8, z)] = true;
+            }
+        }
+        let visibility = SectionVisibility::from_opaque_cells(&cells);
+        assert!(!visibility.is_connected(FaceDirections::Top, FaceDirections::Bottom));
+        assert!(visibility.is_connected(FaceDirections::Top, FaceDirections::Left));
+        assert!(visibility.is_connected(FaceDirections::Bottom, FaceDirections::Right));
+        assert!(visibility.is_connected(FaceDirections::Front, FaceDirections::Back));
+    }
+}
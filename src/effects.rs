@@ -1,10 +1,9 @@
 pub mod ao {
+    use crate::blocks::block::FaceDirections;
     use crate::chunk::BlockVec;
-    use crate::perf;
     use crate::utils::{ChunkFromPosition, RelativeFromAbsolute};
     use crate::world::CHUNK_SIZE;
     use glam::vec3;
-    use std::time::Instant;
 
     // https://0fps.net/2013/07/03/ambient-occlusion-for-minecraft-like-worlds/
     pub(crate) fn calc_vertex_ao(side1: bool, side2: bool, up: bool) -> u8 {
@@ -13,71 +12,73 @@ pub mod ao {
         }
         return 3 - (side1 as u8 + side2 as u8 + up as u8);
     }
-    pub(crate) fn from_vertex_position(
-        vertex_position: &glam::Vec3,
-        blocks_positions: &Vec<((i32, i32), BlockVec)>,
-    ) -> u8 {
-        let side1_position = *vertex_position + glam::vec3(1.0, 1.0, 0.0);
-        let side2_position = *vertex_position + glam::vec3(0.0, 1.0, 1.0);
-        let corner_position = *vertex_position + glam::vec3(1.0, 1.0, 1.0);
-
-        let side1_chunk = side1_position.get_chunk_from_position_absolute();
-        let side1_position = side1_position.relative_from_absolute();
-
-        let side2_chunk = side2_position.get_chunk_from_position_absolute();
-        let side2_position = side2_position.relative_from_absolute();
-
-        let corner_chunk = corner_position.get_chunk_from_position_absolute();
-        let corner_position = corner_position.relative_from_absolute();
-
-        let mut has_side1 = false;
-        let mut has_side2 = false;
-        let mut has_corner = false;
-
-        for (position, chunk, val) in [
-            (side1_position, side1_chunk, &mut has_side1),
-            (side2_position, side2_chunk, &mut has_side2),
-            (corner_position, corner_chunk, &mut has_corner),
-        ] {
-            if let Some(blocks) = blocks_positions.iter().find_map(|c| {
-                if c.0 == chunk {
-                    Some(c.1.clone())
-                } else {
-                    None
-                }
-            }) {
-                let blocks = blocks.read().unwrap();
-                let ycol = &blocks[((position.x * CHUNK_SIZE as f32) + position.z) as usize];
-                if let Some(place) = ycol.get(position.y as usize) {
-                    if let Some(_) = place {
-                        *val = true
-                    }
-                }
-            }
+    // Whether there is a block at the absolute position that casts ambient occlusion
+    fn is_occluding(position: glam::Vec3, blocks_positions: &Vec<((i32, i32), BlockVec)>) -> bool {
+        if position.y < 0.0 {
+            return false;
+        }
+        let chunk = position.get_chunk_from_position_absolute();
+        let position = position.relative_from_absolute();
+
+        let Some((_, blocks)) = blocks_positions.iter().find(|c| c.0 == chunk) else {
+            return false;
+        };
+        let blocks = blocks.read().unwrap();
+        let ycol = &blocks[((position.x * CHUNK_SIZE as f32) + position.z) as usize];
+        match ycol.get(position.y as usize) {
+            Some(Some(block)) => block.read().unwrap().block_type.is_opaque(),
+            _ => false,
         }
-        return calc_vertex_ao(has_side1, has_side2, has_corner);
     }
-    // ao -> 1 (max)
-    // ao -> 0 (min)
-    pub(crate) fn convert_ao_u8_to_f32(ao: u8) -> f32 {
-        1.0 - (ao as f32 / 3.0)
+    /* AO of one corner of a block face
+     * block_position: absolute position of the block
+     * corner: offset of the corner from the block's center (each axis is -0.5 or 0.5)
+     * The three neighbours are sampled in the layer of blocks the face is looking at
+     */
+    pub(crate) fn from_face_corner(
+        block_position: &glam::Vec3,
+        face: FaceDirections,
+        corner: &glam::Vec3,
+        blocks_positions: &Vec<((i32, i32), BlockVec)>,
+    ) -> u8 {
+        let (side1, side2) = match face {
+            FaceDirections::Top | FaceDirections::Bottom => (
+                vec3(corner.x.signum(), 0.0, 0.0),
+                vec3(0.0, 0.0, corner.z.signum()),
+            ),
+            FaceDirections::Left | FaceDirections::Right => (
+                vec3(0.0, corner.y.signum(), 0.0),
+                vec3(0.0, 0.0, corner.z.signum()),
+            ),
+            FaceDirections::Front | FaceDirections::Back => (
+                vec3(corner.x.signum(), 0.0, 0.0),
+                vec3(0.0, corner.y.signum(), 0.0),
+            ),
+        };
+        let plane_position = *block_position + face.get_normal_vector();
+
+        calc_vertex_ao(
+            is_occluding(plane_position + side1, blocks_positions),
+            is_occluding(plane_position + side2, blocks_positions),
+            is_occluding(plane_position + side1 + side2, blocks_positions),
+        )
     }
 
+    #[cfg(test)]
     mod test {
         use super::*;
-        use crate::blocks::block::Block;
+        use crate::blocks::block::{Block, BlockVertexData};
         use crate::blocks::block_type::BlockType;
         use std::sync::{Arc, RwLock};
 
-        #[test]
-        fn should_calculate_the_correct_ao() {
-            let vertex_position = vec3(0.5, 0.5, 0.5); // Belongs to voxel 0,0,0
+        const BLOCK_POSITION: glam::Vec3 = vec3(5.0, 5.0, 5.0);
+
+        fn create_blocks(voxels: &[glam::Vec3]) -> Vec<((i32, i32), BlockVec)> {
             let block_vec: BlockVec = Arc::new(RwLock::new(vec![
                 vec![];
                 (CHUNK_SIZE * CHUNK_SIZE) as usize
             ]));
-            let neighbour_voxels = [vec3(1.0, 1.0, 0.0), vec3(1.0, 1.0, 1.0)];
-            for voxel in &neighbour_voxels {
+            for voxel in voxels.iter() {
                 let mut block_write = block_vec.write().unwrap();
                 let region = &mut block_write[((voxel.x * CHUNK_SIZE as f32) + voxel.z) as usize];
 
@@ -86,28 +87,136 @@ pub mod ao {
                 }
 
                 region[voxel.y as usize] = Some(Arc::new(RwLock::new(Block::new(
-                    voxel.clone(),
-                    voxel.get_chunk_from_position_absolute(),
+                    *voxel,
+                    (0, 0),
                     BlockType::dirt(),
                 ))));
             }
-            let chunk_blocks = vec![((0, 0), block_vec)];
-
-            let vao = from_vertex_position(&vertex_position, &chunk_blocks);
-
-            assert_eq!(vao, 1);
+            vec![((0, 0), block_vec)]
+        }
+        fn create_face(
+            face: FaceDirections,
+            voxels: &[glam::Vec3],
+        ) -> (Vec<BlockVertexData>, Vec<u32>) {
+            let block = Arc::new(RwLock::new(Block::new(
+                BLOCK_POSITION,
+                (0, 0),
+                BlockType::dirt(),
+            )));
+            face.create_face_data(block, &create_blocks(voxels))
+        }
+        // The two axes of the face plane
+        fn face_axes(face: FaceDirections) -> (glam::Vec3, glam::Vec3) {
+            match face {
+                FaceDirections::Top | FaceDirections::Bottom => (glam::Vec3::X, glam::Vec3::Z),
+                FaceDirections::Left | FaceDirections::Right => (glam::Vec3::Y, glam::Vec3::Z),
+                FaceDirections::Front | FaceDirections::Back => (glam::Vec3::X, glam::Vec3::Y),
+            }
+        }
+        // Corner of the vertex relative to the block's center
+        fn vertex_corner(vertex: &BlockVertexData) -> glam::Vec3 {
+            vertex.position() - BLOCK_POSITION
+        }
 
-            let vertex_position = vec3(0.5, 0.5, -0.5); // Belongs to voxel 0,0,0
+        #[test]
+        fn should_calculate_the_correct_vertex_ao() {
+            assert_eq!(calc_vertex_ao(false, false, false), 3);
+            assert_eq!(calc_vertex_ao(false, false, true), 2);
+            assert_eq!(calc_vertex_ao(true, false, true), 1);
+            assert_eq!(calc_vertex_ao(true, true, false), 0);
+        }
 
-            let vao = from_vertex_position(&vertex_position, &chunk_blocks);
+        #[test]
+        fn should_not_occlude_a_face_without_neighbours() {
+            for face in FaceDirections::all() {
+                let (vertex_data, _) = create_face(face, &[]);
+                assert!(vertex_data.iter().all(|v| v.ao() == 3), "{:?}", face);
+            }
+        }
 
-            assert_eq!(vao, 2);
+        #[test]
+        fn should_occlude_the_corners_next_to_a_side_neighbour() {
+            for face in FaceDirections::all() {
+                let (axis, _) = face_axes(face);
+                let side = BLOCK_POSITION + face.get_normal_vector() + axis;
+                let (vertex_data, _) = create_face(face, &[side]);
+
+                for vertex in vertex_data.iter() {
+                    let expected = if vertex_corner(vertex).dot(axis) > 0.0 {
+                        2
+                    } else {
+                        3
+                    };
+                    assert_eq!(vertex.ao(), expected, "{:?}", face);
+                }
+            }
+        }
 
-            let vertex_position = vec3(-5.5, 5.5, -0.5); // Belongs to voxel 0,0,0
+        #[test]
+        fn should_occlude_only_the_corner_next_to_a_diagonal_neighbour() {
+            for face in FaceDirections::all() {
+                let (axis1, axis2) = face_axes(face);
+                let corner = BLOCK_POSITION + face.get_normal_vector() + axis1 - axis2;
+                let (vertex_data, _) = create_face(face, &[corner]);
+
+                for vertex in vertex_data.iter() {
+                    let vertex_corner = vertex_corner(vertex);
+                    let expected =
+                        if vertex_corner.dot(axis1) > 0.0 && vertex_corner.dot(axis2) < 0.0 {
+                            2
+                        } else {
+                            3
+                        };
+                    assert_eq!(vertex.ao(), expected, "{:?}", face);
+                }
+            }
+        }
 
-            let vao = from_vertex_position(&vertex_position, &chunk_blocks);
+        #[test]
+        fn should_fully_occlude_a_corner_between_two_sides() {
+            for face in FaceDirections::all() {
+                let (axis1, axis2) = face_axes(face);
+                let plane = BLOCK_POSITION + face.get_normal_vector();
+                let (vertex_data, indices) = create_face(face, &[plane - axis1, plane - axis2]);
+
+                for vertex in vertex_data.iter() {
+                    let vertex_corner = vertex_corner(vertex);
+                    let expected = match (
+                        vertex_corner.dot(axis1) < 0.0,
+                        vertex_corner.dot(axis2) < 0.0,
+                    ) {
+                        (true, true) => 0,
+                        (false, false) => 3,
+                        _ => 2,
+                    };
+                    assert_eq!(vertex.ao(), expected, "{:?}", face);
+                }
+                // The quad is split along the diagonal that doesn't touch the dark corner
+                let dark_vertex = vertex_data.iter().position(|v| v.ao() == 0).unwrap() as u32;
+                let shared_vertices: Vec<u32> = indices[0..3]
+                    .iter()
+                    .filter(|i| indices[3..6].contains(i))
+                    .copied()
+                    .collect();
+                assert_eq!(shared_vertices.len(), 2, "{:?}", face);
+                assert!(!shared_vertices.contains(&dark_vertex), "{:?}", face);
+            }
+        }
 
-            assert_eq!(vao, 3);
+        #[test]
+        fn should_ignore_neighbours_outside_of_the_face_plane() {
+            for face in FaceDirections::all() {
+                let (axis1, axis2) = face_axes(face);
+                // Neighbours of the block itself, and behind the face
+                let voxels = [
+                    BLOCK_POSITION + axis1,
+                    BLOCK_POSITION + axis2,
+                    BLOCK_POSITION + axis1 + axis2,
+                    BLOCK_POSITION - face.get_normal_vector() + axis1,
+                ];
+                let (vertex_data, _) = create_face(face, &voxels);
+                assert!(vertex_data.iter().all(|v| v.ao() == 3), "{:?}", face);
+            }
         }
     }
 }
@@ -0,0 +1,484 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, RwLock};
+
+use glam::{vec3, Vec3};
+
+use crate::blocks::block::FaceDirections;
+use crate::blocks::block_type::BlockType;
+use crate::chunk::{BlockVec, Chunk};
+use crate::utils::{ChunkFromPosition, RelativeFromAbsolute};
+use crate::world::{CHUNK_HEIGHT, CHUNK_SIZE};
+
+pub const MAX_LIGHT: u8 = 15;
+const COLUMN_HEIGHT: usize = CHUNK_HEIGHT as usize + 1;
+
+/* Light level of every position of a chunk, one byte per block
+ * sky light (4 bits) << 4 | block light (4 bits)
+ */
+pub type LightVec = Arc<RwLock<Vec<u8>>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LightChannel {
+    // Comes from above, doesn't fade when going straight down
+    Sky,
+    // Emitted by blocks like torches
+    Block,
+}
+
+impl LightChannel {
+    pub fn all() -> [LightChannel; 2] {
+        [LightChannel::Sky, LightChannel::Block]
+    }
+    fn get(&self, light: u8) -> u8 {
+        match self {
+            LightChannel::Sky => light >> 4,
+            LightChannel::Block => light & 0xF,
+        }
+    }
+    fn set(&self, light: u8, value: u8) -> u8 {
+        match self {
+            LightChannel::Sky => (light & 0xF) | (value << 4),
+            LightChannel::Block => (light & 0xF0) | value,
+        }
+    }
+}
+
+pub fn create_light_vec() -> LightVec {
+    Arc::new(RwLock::new(vec![
+        0;
+        (CHUNK_SIZE * CHUNK_SIZE) as usize
+            * COLUMN_HEIGHT
+    ]))
+}
+// position: relative to the chunk
+fn light_index(position: &Vec3) -> usize {
+    ((position.x as usize * CHUNK_SIZE as usize) + position.z as usize) * COLUMN_HEIGHT
+        + position.y as usize
+}
+
+/* Blocks and light of a group of chunks, accessed by absolute positions.
+ * Light spreads between all the chunks of the group and remembers which sections it changed
+ */
+pub struct LightWorld {
+    chunks: HashMap<(i32, i32), (BlockVec, LightVec)>,
+    changed_sections: RefCell<HashSet<((i32, i32), usize)>>,
+}
+
+impl LightWorld {
+    pub fn new(chunks: Vec<((i32, i32), BlockVec, LightVec)>) -> Self {
+        Self {
+            chunks: chunks
+                .into_iter()
+                .map(|(coords, blocks, light)| (coords, (blocks, light)))
+                .collect(),
+            changed_sections: RefCell::new(HashSet::new()),
+        }
+    }
+    fn is_inside_bounds(position: &Vec3) -> bool {
+        position.y >= 0.0 && position.y < COLUMN_HEIGHT as f32
+    }
+    fn is_loaded(&self, position: &Vec3) -> bool {
+        Self::is_inside_bounds(position)
+            && self
+                .chunks
+                .contains_key(&position.get_chunk_from_position_absolute())
+    }
+    pub fn get_block_type(&self, position: &Vec3) -> Option<BlockType> {
+        if !Self::is_inside_bounds(position) {
+            return None;
+        }
+        let (blocks, _) = self
+            .chunks
+            .get(&position.get_chunk_from_position_absolute())?;
+        let position = position.relative_from_absolute();
+
+        let blocks = blocks.read().unwrap();
+        let y_blocks = &blocks[((position.x * CHUNK_SIZE as f32) + position.z) as usize];
+        let block = y_blocks.get(position.y as usize)?.as_ref()?;
+        let block_type = block.read().unwrap().block_type;
+        Some(block_type)
+    }
+    // Light outside of the world is full sky light, unloaded chunks count as open sky too
+    pub fn get_light(&self, position: &Vec3, channel: LightChannel) -> u8 {
+        let full_sky = match channel {
+            LightChannel::Sky => MAX_LIGHT,
+            LightChannel::Block => 0,
+        };
+        if position.y < 0.0 {
+            return 0;
+        }
+        if !Self::is_inside_bounds(position) {
+            return full_sky;
+        }
+        match self
+            .chunks
+            .get(&position.get_chunk_from_position_absolute())
+        {
+            Some((_, light)) => {
+                let position = position.relative_from_absolute();
+                channel.get(light.read().unwrap()[light_index(&position)])
+            }
+            None => full_sky,
+        }
+    }
+    fn set_light(&self, position: &Vec3, channel: LightChannel, value: u8) {
+        let chunk = position.get_chunk_from_position_absolute();
+        let Some((_, light)) = self.chunks.get(&chunk) else {
+            return;
+        };
+        let relative_position = position.relative_from_absolute();
+        let mut light = light.write().unwrap();
+        let index = light_index(&relative_position);
+        light[index] = channel.set(light[index], value);
+
+        // The faces of the neighbour blocks are lit by this position
+        let mut changed_sections = self.changed_sections.borrow_mut();
+        let offsets = FaceDirections::all().map(|face| face.get_normal_vector());
+        for offset in std::iter::once(Vec3::ZERO).chain(offsets) {
+            let neighbour = *position + offset;
+            if !Chunk::is_outside_bounds(&neighbour) {
+                changed_sections.insert((
+                    neighbour.get_chunk_from_position_absolute(),
+                    Chunk::get_section_index(neighbour.y),
+                ));
+            }
+        }
+    }
+    // Light can only spread through loaded positions without an opaque block
+    fn lets_light_through(&self, position: &Vec3) -> bool {
+        self.is_loaded(position)
+            && !self
+                .get_block_type(position)
+                .is_some_and(|block_type| block_type.is_opaque())
+    }
+    fn get_emission(&self, position: &Vec3) -> u8 {
+        self.get_block_type(position)
+            .map_or(0, |block_type| block_type.get_config().light_emission)
+    }
+    // Light that `level` gives to the neighbour in `direction`
+    fn spread_level(channel: LightChannel, direction: &Vec3, level: u8) -> u8 {
+        if channel == LightChannel::Sky && level == MAX_LIGHT && direction.y < 0.0 {
+            MAX_LIGHT
+        } else {
+            level.saturating_sub(1)
+        }
+    }
+    // Flood fills the light of the queued positions into their neighbours
+    pub fn propagate(&self, mut queue: VecDeque<Vec3>, channel: LightChannel) {
+        while let Some(position) = queue.pop_front() {
+            let level = self.get_light(&position, channel);
+            if level <= 1 {
+                continue;
+            }
+            for face in FaceDirections::all().iter() {
+                let direction = face.get_normal_vector();
+                let neighbour = position + direction;
+                if !self.lets_light_through(&neighbour) {
+                    continue;
+                }
+                let neighbour_level = Self::spread_level(channel, &direction, level);
+                if self.get_light(&neighbour, channel) < neighbour_level {
+                    self.set_light(&neighbour, channel, neighbour_level);
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+    }
+    /* Darkens the light that came from the queued positions (with their previous level),
+     * then fills the darkened area again from the light around it
+     */
+    fn remove(&self, mut queue: VecDeque<(Vec3, u8)>, channel: LightChannel) {
+        let mut refill = VecDeque::new();
+
+        while let Some((position, level)) = queue.pop_front() {
+            for face in FaceDirections::all().iter() {
+                let direction = face.get_normal_vector();
+                let neighbour = position + direction;
+                if !self.is_loaded(&neighbour) {
+                    continue;
+                }
+                let neighbour_level = self.get_light(&neighbour, channel);
+                if neighbour_level == 0 {
+                    continue;
+                }
+                if neighbour_level < level
+                    || (neighbour_level == MAX_LIGHT
+                        && Self::spread_level(channel, &direction, level) == MAX_LIGHT)
+                {
+                    self.set_light(&neighbour, channel, 0);
+                    queue.push_back((neighbour, neighbour_level));
+
+                    // Light sources keep their own light
+                    if channel == LightChannel::Block {
+                        let emission = self.get_emission(&neighbour);
+                        if emission > 0 {
+                            self.set_light(&neighbour, channel, emission);
+                            refill.push_back(neighbour);
+                        }
+                    }
+                } else {
+                    refill.push_back(neighbour);
+                }
+            }
+        }
+        self.propagate(refill, channel);
+    }
+    // Updates the light around a block that was placed or removed, the blocks must already be updated
+    pub fn update_block(&self, position: &Vec3) {
+        for channel in LightChannel::all() {
+            let previous_level = self.get_light(position, channel);
+            self.set_light(position, channel, 0);
+            self.remove(VecDeque::from([(*position, previous_level)]), channel);
+
+            let mut queue: VecDeque<Vec3> = FaceDirections::all()
+                .iter()
+                .map(|face| *position + face.get_normal_vector())
+                .collect();
+            if channel == LightChannel::Block {
+                let emission = self.get_emission(position);
+                if emission > 0 {
+                    self.set_light(position, channel, emission);
+                    queue.push_back(*position);
+                }
+            }
+            self.propagate(queue, channel);
+        }
+    }
+    /* Lights a chunk that was just created, only looking at its own blocks.
+     * The light coming from the other chunks is added with `propagate_between_chunks`
+     */
+    pub fn init_chunk(&self, chunk: (i32, i32)) {
+        let origin = vec3(
+            (chunk.0 * CHUNK_SIZE as i32) as f32,
+            0.0,
+            (chunk.1 * CHUNK_SIZE as i32) as f32,
+        );
+        // Lowest position of each column that gets direct sky light
+        let mut sky_heights = vec![0usize; (CHUNK_SIZE * CHUNK_SIZE) as usize];
+        let mut block_queue = VecDeque::new();
+
+        for x in 0..CHUNK_SIZE as usize {
+            for z in 0..CHUNK_SIZE as usize {
+                let column = origin + vec3(x as f32, 0.0, z as f32);
+                let mut sky_height = COLUMN_HEIGHT;
+
+                for y in (0..COLUMN_HEIGHT).rev() {
+                    let position = column + vec3(0.0, y as f32, 0.0);
+                    if sky_height == y + 1 && self.lets_light_through(&position) {
+                        self.set_light(&position, LightChannel::Sky, MAX_LIGHT);
+                        sky_height = y;
+                    }
+                    let emission = self.get_emission(&position);
+                    if emission > 0 {
+                        self.set_light(&position, LightChannel::Block, emission);
+                        block_queue.push_back(position);
+                    }
+                }
+                sky_heights[x * CHUNK_SIZE as usize + z] = sky_height;
+            }
+        }
+
+        // Sky light only needs to spread sideways from where the neighbour column is lower
+        let mut sky_queue = VecDeque::new();
+        for x in 0..CHUNK_SIZE as usize {
+            for z in 0..CHUNK_SIZE as usize {
+                let neighbours_height = [(1, 0), (-1, 0), (0, 1), (0, -1)]
+                    .iter()
+                    .filter_map(|(dx, dz)| {
+                        let (x, z) = (x as i32 + dx, z as i32 + dz);
+                        if x < 0 || x >= CHUNK_SIZE as i32 || z < 0 || z >= CHUNK_SIZE as i32 {
+                            return None;
+                        }
+                        Some(sky_heights[(x * CHUNK_SIZE as i32 + z) as usize])
+                    })
+                    .max()
+                    .unwrap_or(0);
+                for y in sky_heights[x * CHUNK_SIZE as usize + z]..neighbours_height {
+                    sky_queue.push_back(origin + vec3(x as f32, y as f32, z as f32));
+                }
+            }
+        }
+
+        self.propagate(sky_queue, LightChannel::Sky);
+        self.propagate(block_queue, LightChannel::Block);
+    }
+    // Spreads the light through the borders of the given chunks and their neighbours
+    pub fn propagate_between_chunks(&self, chunks: &[(i32, i32)]) {
+        let size = CHUNK_SIZE as i32;
+
+        for channel in LightChannel::all() {
+            let mut queue = VecDeque::new();
+
+            for chunk in chunks.iter() {
+                let origin = vec3((chunk.0 * size) as f32, 0.0, (chunk.1 * size) as f32);
+                for i in 0..size {
+                    // Border positions of the chunk and the direction to the neighbour chunk
+                    let borders = [
+                        (vec3(0.0, 0.0, i as f32), Vec3::NEG_X),
+                        (vec3((size - 1) as f32, 0.0, i as f32), Vec3::X),
+                        (vec3(i as f32, 0.0, 0.0), Vec3::NEG_Z),
+                        (vec3(i as f32, 0.0, (size - 1) as f32), Vec3::Z),
+                    ];
+                    for (border, direction) in borders.iter() {
+                        for y in 0..COLUMN_HEIGHT {
+                            let position = origin + *border + vec3(0.0, y as f32, 0.0);
+                            let neighbour = position + *direction;
+                            if !self.is_loaded(&neighbour) {
+                                continue;
+                            }
+                            let level = self.get_light(&position, channel);
+                            let neighbour_level = self.get_light(&neighbour, channel);
+                            if level > neighbour_level + 1 {
+                                queue.push_back(position);
+                            } else if neighbour_level > level + 1 {
+                                queue.push_back(neighbour);
+                            }
+                        }
+                    }
+                }
+            }
+            self.propagate(queue, channel);
+        }
+    }
+    /* Smooth light of a vertex, the average of the 4 positions in front of the face that touch the vertex
+     * position: absolute position of the vertex
+     */
+    pub fn get_vertex_light(&self, position: &Vec3, face: FaceDirections) -> (u8, u8) {
+        let normal = face.get_normal_vector();
+        let (axis1, axis2) = match face {
+            FaceDirections::Top | FaceDirections::Bottom => (Vec3::X, Vec3::Z),
+            FaceDirections::Left | FaceDirections::Right => (Vec3::Y, Vec3::Z),
+            FaceDirections::Front | FaceDirections::Back => (Vec3::X, Vec3::Y),
+        };
+        let center = *position + normal * 0.5;
+
+        let mut sky = 0u32;
+        let mut block = 0u32;
+        let mut count = 0u32;
+        for (s1, s2) in [(-0.5, -0.5), (-0.5, 0.5), (0.5, -0.5), (0.5, 0.5)] {
+            let sample = (center + axis1 * s1 + axis2 * s2 + 0.5).floor();
+            if self
+                .get_block_type(&sample)
+                .is_some_and(|block_type| block_type.is_opaque())
+            {
+                continue;
+            }
+            sky += self.get_light(&sample, LightChannel::Sky) as u32;
+            block += self.get_light(&sample, LightChannel::Block) as u32;
+            count += 1;
+        }
+        if count == 0 {
+            return (0, 0);
+        }
+        (
+            ((sky + count / 2) / count) as u8,
+            ((block + count / 2) / count) as u8,
+        )
+    }
+    // Sections whose light changed since the last call
+    pub fn take_changed_sections(&self) -> Vec<((i32, i32), usize)> {
+        self.changed_sections.borrow_mut().drain().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::block::Block;
+
+    fn create_light_world(blocks: &[(Vec3, BlockType)]) -> LightWorld {
+        let block_vec: BlockVec = Arc::new(RwLock::new(vec![
+            vec![];
+            (CHUNK_SIZE * CHUNK_SIZE) as usize
+        ]));
+        for (position, block_type) in blocks.iter() {
+            add_block(&block_vec, *position, *block_type);
+        }
+        let light_world = LightWorld::new(vec![((0, 0), block_vec, create_light_vec())]);
+        light_world.init_chunk((0, 0));
+        light_world
+    }
+    fn add_block(block_vec: &BlockVec, position: Vec3, block_type: BlockType) {
+        let mut blocks = block_vec.write().unwrap();
+        let y_blocks = &mut blocks[((position.x * CHUNK_SIZE as f32) + position.z) as usize];
+        for _ in y_blocks.len()..=position.y as usize {
+            y_blocks.push(None);
+        }
+        y_blocks[position.y as usize] = Some(Arc::new(RwLock::new(Block::new(
+            position,
+            (0, 0),
+            block_type,
+        ))));
+    }
+
+    #[test]
+    fn should_spread_sky_light_down_and_under_roofs() {
+        // A roof over (5, 3, 5)
+        let light_world = create_light_world(&[(vec3(5.0, 4.0, 5.0), BlockType::stone())]);
+
+        assert_eq!(
+            light_world.get_light(&vec3(4.0, 0.0, 5.0), LightChannel::Sky),
+            15
+        );
+        assert_eq!(
+            light_world.get_light(&vec3(5.0, 4.0, 5.0), LightChannel::Sky),
+            0
+        );
+        assert_eq!(
+            light_world.get_light(&vec3(5.0, 3.0, 5.0), LightChannel::Sky),
+            14
+        );
+    }
+
+    #[test]
+    fn should_fade_block_light_with_distance() {
+        let light_world = create_light_world(&[(vec3(8.0, 0.0, 8.0), BlockType::glowstone())]);
+
+        assert_eq!(
+            light_world.get_light(&vec3(8.0, 0.0, 8.0), LightChannel::Block),
+            15
+        );
+        assert_eq!(
+            light_world.get_light(&vec3(8.0, 1.0, 8.0), LightChannel::Block),
+            14
+        );
+        assert_eq!(
+            light_world.get_light(&vec3(10.0, 0.0, 11.0), LightChannel::Block),
+            10
+        );
+    }
+
+    #[test]
+    fn should_update_light_when_blocks_change() {
+        let light_world = create_light_world(&[]);
+        let (_, (block_vec, _)) = light_world.chunks.iter().next().unwrap();
+        let block_vec = block_vec.clone();
+
+        let torch = vec3(8.0, 0.0, 8.0);
+        add_block(&block_vec, torch, BlockType::torch());
+        light_world.update_block(&torch);
+        assert_eq!(
+            light_world.get_light(&vec3(8.0, 0.0, 11.0), LightChannel::Block),
+            11
+        );
+
+        block_vec.write().unwrap()[(torch.x * CHUNK_SIZE as f32 + torch.z) as usize][0] = None;
+        light_world.update_block(&torch);
+        assert_eq!(light_world.get_light(&torch, LightChannel::Block), 0);
+        assert_eq!(
+            light_world.get_light(&vec3(8.0, 0.0, 11.0), LightChannel::Block),
+            0
+        );
+
+        // Covering a position blocks the sky light that came from above
+        let roof = vec3(3.0, 1.0, 3.0);
+        add_block(&block_vec, roof, BlockType::stone());
+        light_world.update_block(&roof);
+        assert_eq!(
+            light_world.get_light(&vec3(3.0, 0.0, 3.0), LightChannel::Sky),
+            14
+        );
+        assert!(!light_world.take_changed_sections().is_empty());
+    }
+}
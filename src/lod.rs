@@ -0,0 +1,211 @@
+use std::sync::Arc;
+
+use crate::arena::MeshArena;
+use crate::blocks::block::{FaceDirections, TexturedBlock};
+use crate::blocks::block_type::BlockType;
+use crate::chunk::{Chunk, SectionMesh};
+use crate::culling::Aabb;
+use crate::mesher::MeshData;
+use crate::world::{NoiseData, CHUNK_SIZE};
+
+// Chunks further than this (in chunks) from the player aren't drawn at all
+pub const LOD_RADIUS: i32 = 16;
+// Chunks closer than this use the finer LOD level
+const HALF_LOD_RADIUS: i32 = 8;
+// How far the walls on the border of a LOD chunk go down, so there are no cracks with the neighbours
+const SKIRT_DEPTH: f32 = 16.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LodLevel {
+    // Cells of 2x2 blocks
+    Half,
+    // Cells of 4x4 blocks
+    Quarter,
+}
+
+impl LodLevel {
+    // distance: in chunks, from the player's chunk
+    pub fn from_distance(distance: i32) -> Option<LodLevel> {
+        if distance <= HALF_LOD_RADIUS {
+            Some(LodLevel::Half)
+        } else if distance <= LOD_RADIUS {
+            Some(LodLevel::Quarter)
+        } else {
+            None
+        }
+    }
+    // Width of a cell in blocks
+    pub fn cell_size(&self) -> u32 {
+        match self {
+            LodLevel::Half => 2,
+            LodLevel::Quarter => 4,
+        }
+    }
+}
+
+/* Simplified terrain of a chunk that is too far away to be loaded.
+ * It's built from the height map only, every cell is a column with the top block's texture
+ */
+pub struct LodChunk {
+    pub x: i32,
+    pub y: i32,
+    pub level: LodLevel,
+    pub mesh: SectionMesh,
+    pub aabb: Aabb,
+    // Index of the chunk's position in the arena
+    pub slot: u32,
+}
+
+impl LodChunk {
+    // mesh, max_height: built with build_lod_mesh
+    pub fn new(
+        x: i32,
+        y: i32,
+        level: LodLevel,
+        mesh: &MeshData,
+        max_height: f32,
+        arena: &mut MeshArena,
+    ) -> Self {
+        let min = glam::vec3(
+            (x * CHUNK_SIZE as i32) as f32,
+            0.0,
+            (y * CHUNK_SIZE as i32) as f32,
+        ) - 0.5;
+        let aabb = Aabb::new(
+            min,
+            min + glam::vec3(CHUNK_SIZE as f32, max_height + 1.0, CHUNK_SIZE as f32),
+        );
+
+        Self {
+            x,
+            y,
+            level,
+            mesh: arena.upload(&mesh.vertex, &mesh.indices),
+            aabb,
+            slot: arena.allocate_chunk((x, y)),
+        }
+    }
+    // Gives the space of the mesh and the slot back to the arena
+    pub fn free(&self, arena: &mut MeshArena) {
+        arena.free(&self.mesh);
+        arena.free_chunk(self.slot);
+    }
+}
+
+// Returns the mesh and the height of the highest cell
+pub fn build_lod_mesh(
+    x: i32,
+    y: i32,
+    level: LodLevel,
+    noise_data: Arc<NoiseData>,
+) -> (MeshData, f32) {
+    let cell_size = level.cell_size();
+    let cells_per_row = CHUNK_SIZE / cell_size;
+
+    // The highest block of the cell, so that the far hills keep their shape
+    let mut heights = vec![0u32; (cells_per_row * cells_per_row) as usize];
+    for cell_x in 0..cells_per_row {
+        for cell_z in 0..cells_per_row {
+            let mut height = 0;
+            for bx in 0..cell_size {
+                for bz in 0..cell_size {
+                    height = u32::max(
+                        height,
+                        Chunk::get_height_value(
+                            x,
+                            y,
+                            cell_x * cell_size + bx,
+                            cell_z * cell_size + bz,
+                            noise_data.clone(),
+                        ),
+                    );
+                }
+            }
+            heights[(cell_x * cells_per_row + cell_z) as usize] = height;
+        }
+    }
+    let get_height = |cell_x: i32, cell_z: i32| -> Option<u32> {
+        if cell_x < 0
+            || cell_z < 0
+            || cell_x >= cells_per_row as i32
+            || cell_z >= cells_per_row as i32
+        {
+            return None;
+        }
+        Some(heights[(cell_x as u32 * cells_per_row + cell_z as u32) as usize])
+    };
+
+    let mut mesh = MeshData::default();
+    for cell_x in 0..cells_per_row as i32 {
+        for cell_z in 0..cells_per_row as i32 {
+            let height = get_height(cell_x, cell_z).unwrap();
+            let block_type = BlockType::surface_from_y_position(height);
+            // Blocks are centered on their position
+            let min = glam::vec3(
+                (cell_x as u32 * cell_size) as f32,
+                0.0,
+                (cell_z as u32 * cell_size) as f32,
+            ) - 0.5;
+            let max = min + glam::vec3(cell_size as f32, 0.0, cell_size as f32);
+            let top = height as f32 + 0.5;
+
+            for face in FaceDirections::all().iter() {
+                let normal = face.get_normal_vector();
+                let (face_min, face_max) = match face {
+                    FaceDirections::Top => {
+                        (glam::vec3(min.x, top, min.z), glam::vec3(max.x, top, max.z))
+                    }
+                    FaceDirections::Bottom => continue,
+                    _ => {
+                        // Walls go down to the neighbour cell, or below it on the chunk's border
+                        let bottom =
+                            match get_height(cell_x + normal.x as i32, cell_z + normal.z as i32) {
+                                Some(neighbour_height) if neighbour_height >= height => continue,
+                                Some(neighbour_height) => neighbour_height as f32 + 0.5,
+                                None => f32::max(top - SKIRT_DEPTH, -0.5),
+                            };
+                        let (mut face_min, mut face_max) = (min, max);
+                        face_min.y = bottom;
+                        face_max.y = top;
+                        (face_min, face_max)
+                    }
+                };
+
+                let (face_vertex, face_indices) = face.create_cuboid_face_data(
+                    face_min,
+                    face_max,
+                    block_type.get_texture_index(*face),
+                );
+                mesh.append(face_vertex, &face_indices);
+            }
+        }
+    }
+
+    let max_height = heights.iter().copied().max().unwrap_or(0) as f32;
+    (mesh, max_height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_pick_coarser_levels_further_away() {
+        assert_eq!(LodLevel::from_distance(5), Some(LodLevel::Half));
+        assert_eq!(LodLevel::from_distance(12), Some(LodLevel::Quarter));
+        assert_eq!(LodLevel::from_distance(LOD_RADIUS + 1), None);
+    }
+
+    #[test]
+    fn should_have_a_top_face_for_every_cell() {
+        let noise_data = Arc::new(vec![0.0; (crate::world::NOISE_SIZE.pow(2)) as usize]);
+        let (mesh, max_height) = build_lod_mesh(3, -2, LodLevel::Quarter, noise_data);
+
+        // Flat terrain only has the top faces and the skirts on the chunk's border
+        let cells = 4 * 4;
+        let skirts = 4 * 4;
+        assert_eq!(mesh.indices.len(), (cells + skirts) * 6);
+        assert_eq!(mesh.vertex.len(), (cells + skirts) * 4);
+        assert_eq!(max_height, 9.0);
+    }
+}
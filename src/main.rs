@@ -4,6 +4,7 @@ use std::{
     io::BufReader,
     mem,
     ops::ControlFlow,
+    path::Path,
     process::exit,
     time::{Duration, Instant},
 };
@@ -11,6 +12,7 @@ use std::{
 use bytemuck::{Pod, Zeroable};
 use glam::vec2;
 use material::Texture;
+use persistence::{Loadable, DATA_DIR};
 use player::CameraController;
 use state::State;
 use tobj::{load_obj, load_obj_buf, LoadOptions};
@@ -30,15 +32,28 @@ const DEFAULT_WINDOW_HEIGHT: u32 = 800;
 #[macro_use]
 extern crate lazy_static;
 
+pub mod arena;
 pub mod blocks;
 pub mod chunk;
+pub mod clock;
+pub mod clouds;
 pub mod collision;
+pub mod culling;
 pub mod effects;
+pub mod light;
+pub mod lod;
 pub mod macros;
 pub mod material;
+pub mod mesher;
 pub mod persistence;
 pub mod pipeline;
+pub mod pipeline_manager;
 pub mod player;
+pub mod post;
+pub mod render_graph;
+pub mod resources;
+pub mod screenshot;
+pub mod shadow;
 pub mod state;
 pub mod structures;
 pub mod ui;
@@ -105,6 +120,13 @@ async fn run(event_loop: EventLoop<()>, window: Window) {
                     } => {
                         state.on_click(button);
                     }
+                    WindowEvent::MouseInput {
+                        state: ElementState::Released,
+                        button,
+                        ..
+                    } => {
+                        state.on_release(button);
+                    }
 
                     WindowEvent::CursorMoved { position, .. } => {
                         if !cursor_in {
@@ -128,7 +150,11 @@ async fn run(event_loop: EventLoop<()>, window: Window) {
 
                         if fps_counter.elapsed().as_secs() >= 3 {
                             fps_counter = Instant::now();
-                            println!("\x1b[32mFPS - {}\x1b[0m", frames / 3);
+                            println!(
+                                "\x1b[32mFPS - {} ({})\x1b[0m",
+                                frames / 3,
+                                state.culling_stats
+                            );
                             frames = 0;
                         }
 
@@ -155,7 +181,81 @@ async fn run(event_loop: EventLoop<()>, window: Window) {
         .unwrap()
 }
 
+/* Moves the camera to the position of the --camera argument, `x,y,z[,yaw,pitch]`.
+ * The angles are in degrees
+ */
+fn parse_camera(arg: &str, camera: &mut player::Camera) -> Result<(), String> {
+    let values = arg
+        .split(',')
+        .map(|value| value.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid camera {arg}: {e}"))?;
+    match values[..] {
+        [x, y, z] => camera.eye = glam::vec3(x, y, z),
+        [x, y, z, yaw, pitch] => {
+            camera.eye = glam::vec3(x, y, z);
+            camera.yaw = yaw.to_radians();
+            camera.pitch = pitch.to_radians();
+        }
+        _ => return Err(format!("Invalid camera {arg}, expected x,y,z[,yaw,pitch]")),
+    }
+    Ok(())
+}
+
+fn render_frame(
+    width: u32,
+    height: u32,
+    camera: player::Camera,
+    data_dir: &Path,
+) -> image::RgbaImage {
+    let mut state = pollster::block_on(State::new_headless(width, height, camera, data_dir));
+    let image = state.render_image().expect("Failed to render the frame");
+    state.dispose();
+    image
+}
+
+// Draws the saved world without opening a window and saves the frame to path
+fn render_headless(path: &str, camera_arg: Option<&str>) {
+    let data_dir = Path::new(DATA_DIR);
+    let mut camera = player::Camera::new(DEFAULT_WINDOW_WIDTH as f32, DEFAULT_WINDOW_HEIGHT as f32);
+    if let Ok(eye) = player::Camera::load(data_dir, Box::new(())) {
+        camera.eye = eye;
+    }
+    if let Some(arg) = camera_arg {
+        if let Err(e) = parse_camera(arg, &mut camera) {
+            eprintln!("{e}");
+            exit(1);
+        }
+    }
+    let image = render_frame(
+        DEFAULT_WINDOW_WIDTH,
+        DEFAULT_WINDOW_HEIGHT,
+        camera,
+        data_dir,
+    );
+    image.save(path).expect("Failed to save the frame");
+}
+
 fn main() {
+    env_logger::init();
+    // e.g. `minecraft --headless frame.png --camera 0,60,0,90,-30`
+    let args = std::env::args().collect::<Vec<_>>();
+    if let [_, flag, path, rest @ ..] = &args[..] {
+        if flag == "--headless" {
+            let camera = match rest {
+                [] => None,
+                [flag, camera] if flag == "--camera" => Some(camera.as_str()),
+                _ => {
+                    eprintln!(
+                        "Usage: minecraft --headless <file.png> [--camera x,y,z[,yaw,pitch]]"
+                    );
+                    exit(1);
+                }
+            };
+            return render_headless(path, camera);
+        }
+    }
+
     let event_loop = EventLoop::new().unwrap();
     let builder = winit::window::WindowBuilder::new();
     let window = builder
@@ -166,6 +266,59 @@ fn main() {
         .build(&event_loop)
         .unwrap();
 
-    env_logger::init();
     pollster::block_on(run(event_loop, window))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Regenerated by running the test with UPDATE_REFERENCE=1
+    const REFERENCE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/reference/headless.png");
+
+    #[test]
+    fn should_parse_the_camera_argument() {
+        let mut camera = player::Camera::new(4.0, 3.0);
+        parse_camera("1,2.5,-3", &mut camera).unwrap();
+        assert_eq!(camera.eye, glam::vec3(1.0, 2.5, -3.0));
+        parse_camera("0, 60, 0, 180, -90", &mut camera).unwrap();
+        assert_eq!(camera.eye, glam::vec3(0.0, 60.0, 0.0));
+        assert_eq!(camera.yaw, std::f32::consts::PI);
+        assert_eq!(camera.pitch, -std::f32::consts::FRAC_PI_2);
+        assert!(parse_camera("1,2", &mut camera).is_err());
+        assert!(parse_camera("1,2,a", &mut camera).is_err());
+    }
+
+    #[test]
+    fn should_render_the_same_world_as_the_reference() {
+        let instance = wgpu::Instance::default();
+        if pollster::block_on(instance.request_adapter(&Default::default())).is_none() {
+            eprintln!("No adapter, not rendering the reference world");
+            return;
+        }
+        // Nothing saved, the chunks and the clock are generated from scratch
+        let dir = std::env::temp_dir().join(format!("headless_reference_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut camera = player::Camera::new(4.0, 3.0);
+        parse_camera("8,70,8,45,-35", &mut camera).unwrap();
+        let image = render_frame(320, 240, camera, &dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        if std::env::var_os("UPDATE_REFERENCE").is_some() {
+            image.save(REFERENCE).unwrap();
+        }
+
+        let reference = image::open(REFERENCE).unwrap().to_rgba8();
+        assert_eq!(image.dimensions(), reference.dimensions());
+        // Different drivers don't rasterize and filter exactly the same
+        let different = image
+            .pixels()
+            .zip(reference.pixels())
+            .filter(|(a, b)| a.0.iter().zip(b.0).any(|(a, b)| a.abs_diff(b) > 24))
+            .count();
+        assert!(
+            different <= (image.width() * image.height()) as usize / 50,
+            "{different} pixels are different from the reference"
+        );
+    }
+}
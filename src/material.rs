@@ -1,37 +1,40 @@
-use image::{error::DecodingError, GenericImageView, ImageError};
+use image::{error::DecodingError, imageops, GenericImageView, ImageError, Rgba, RgbaImage};
 
 use crate::{state::State, utils::noise::perlin_noise};
 
 impl Texture {
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
-    pub fn create_depth_texture(state: &State) -> Self {
-        let size = wgpu::Extent3d {
-            width: state.surface_config.width,
-            height: state.surface_config.height,
-            depth_or_array_layers: 1,
-        };
-        let desc = wgpu::TextureDescriptor {
-            label: Some("depth_texture"),
-            size,
+    // Render target of the render graph, the sampler is used by the passes reading it
+    pub fn create_attachment(
+        device: &wgpu::Device,
+        name: &str,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+        samples: u32,
+        usage: wgpu::TextureUsages,
+    ) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(name),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count: samples,
             dimension: wgpu::TextureDimension::D2,
-            format: Self::DEPTH_FORMAT,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            format,
+            usage,
             view_formats: &[],
-        };
-        let texture = state.device.create_texture(&desc);
+        });
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = state.device.create_sampler(&wgpu::SamplerDescriptor {
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
             address_mode_w: wgpu::AddressMode::ClampToEdge,
             mag_filter: wgpu::FilterMode::Linear,
             min_filter: wgpu::FilterMode::Linear,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            compare: Some(wgpu::CompareFunction::LessEqual), // 5.
-            lod_min_clamp: 0.0,
-            lod_max_clamp: 100.0,
             ..Default::default()
         });
         Self {
@@ -39,7 +42,7 @@ impl Texture {
             texture,
             view,
             sampler,
-            name: String::from("depth_texture"),
+            name: String::from(name),
         }
     }
     //
@@ -115,29 +118,28 @@ impl Texture {
         }
     }
 
-    pub fn from_path(
-        path: &str,
-        name: String,
+    /* Splits the atlas into the layers of a texture array, one per tile, each with its own mip chain.
+     * Unlike the tiles of a single texture, the layers can't bleed into each other at their edges
+     */
+    pub fn block_array(
+        atlas: &RgbaImage,
+        tiles_per_row: u32,
+        tile_count: u32,
+        sampler: &SamplerSettings,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        let f = std::fs::File::open(path)?;
-        let reader = std::io::BufReader::new(f);
-        let image = image::load(reader, image::ImageFormat::Png)?;
-        let image = image.flipv();
-        let dimensions = image.dimensions();
-        let rgba = image.as_rgba8().unwrap();
-
-        let size = wgpu::Extent3d {
-            width: dimensions.0,
-            height: dimensions.1,
-            depth_or_array_layers: 1,
-        };
+    ) -> Self {
+        let tile_size = atlas.width() / tiles_per_row;
+        let mip_level_count = tile_size.ilog2() + 1;
 
         let texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some(&name.clone()),
-            size,
-            mip_level_count: 1,
+            label: Some("block_textures"),
+            size: wgpu::Extent3d {
+                width: tile_size,
+                height: tile_size,
+                depth_or_array_layers: tile_count,
+            },
+            mip_level_count,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             view_formats: &[],
@@ -145,33 +147,143 @@ impl Texture {
             format: wgpu::TextureFormat::Rgba8UnormSrgb,
         });
 
-        queue.write_texture(
-            wgpu::ImageCopyTexture {
-                aspect: wgpu::TextureAspect::All,
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            &rgba,
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(4 * dimensions.0),
-                rows_per_image: Some(dimensions.1),
-            },
-            size,
-        );
+        for layer in 0..tile_count {
+            let x = (layer % tiles_per_row) * tile_size;
+            let y = (layer / tiles_per_row) * tile_size;
+            // Upside down, the v coordinate of the faces goes up
+            let mut mip = imageops::flip_vertical(&*atlas.view(x, y, tile_size, tile_size));
+            for mip_level in 0..mip_level_count {
+                queue.write_texture(
+                    wgpu::ImageCopyTexture {
+                        aspect: wgpu::TextureAspect::All,
+                        texture: &texture,
+                        mip_level,
+                        origin: wgpu::Origin3d {
+                            x: 0,
+                            y: 0,
+                            z: layer,
+                        },
+                    },
+                    &mip,
+                    wgpu::ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: Some(4 * mip.width()),
+                        rows_per_image: Some(mip.height()),
+                    },
+                    wgpu::Extent3d {
+                        width: mip.width(),
+                        height: mip.height(),
+                        depth_or_array_layers: 1,
+                    },
+                );
+                mip = downsample(&mip);
+            }
+        }
 
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
             ..Default::default()
         });
 
-        Ok(Self {
+        Self {
             view,
-            sampler,
+            sampler: sampler.create_sampler(device),
             texture,
-            name,
+            name: "block_textures".to_string(),
             data: None,
+        }
+    }
+}
+
+// Halves the size of the image. Colours are weighted by alpha, so transparent pixels don't darken the edges
+fn downsample(image: &RgbaImage) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    RgbaImage::from_fn((width / 2).max(1), (height / 2).max(1), |x, y| {
+        let mut color = [0.0; 3];
+        let mut alpha = 0.0;
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let pixel = image.get_pixel((x * 2 + dx).min(width - 1), (y * 2 + dy).min(height - 1));
+            let a = pixel[3] as f32;
+            for i in 0..3 {
+                color[i] += pixel[i] as f32 * a;
+            }
+            alpha += a;
+        }
+        if alpha == 0.0 {
+            return Rgba([0; 4]);
+        }
+        let [r, g, b] = color.map(|c| (c / alpha).round() as u8);
+        Rgba([r, g, b, (alpha / 4.0).round() as u8])
+    })
+}
+
+// How the block textures are filtered
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SamplerSettings {
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+    pub mipmap_filter: wgpu::FilterMode,
+    // 1 to disable it, only works when all the filters are linear
+    pub anisotropy: u16,
+}
+
+impl Default for SamplerSettings {
+    // Sharp pixels up close, blended mips in the distance
+    fn default() -> Self {
+        Self {
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            anisotropy: 1,
+        }
+    }
+}
+
+impl SamplerSettings {
+    // Smooth textures, with anisotropic filtering when it's more than 1
+    pub fn linear(anisotropy: u16) -> Self {
+        Self {
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            anisotropy,
+        }
+    }
+    // Cycles through the default settings and the linear ones with more and more anisotropy
+    pub fn next(self) -> Self {
+        let presets = [
+            Self::default(),
+            Self::linear(1),
+            Self::linear(4),
+            Self::linear(16),
+        ];
+        let index = presets
+            .iter()
+            .position(|preset| *preset == self)
+            .map_or(0, |i| (i + 1) % presets.len());
+        presets[index]
+    }
+    pub fn create_sampler(&self, device: &wgpu::Device) -> wgpu::Sampler {
+        let linear = [self.mag_filter, self.min_filter, self.mipmap_filter]
+            .iter()
+            .all(|f| *f == wgpu::FilterMode::Linear);
+        if !linear && self.anisotropy > 1 {
+            log::warn!("Anisotropic filtering needs linear filters, it is disabled");
+        }
+        device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("block_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: self.mag_filter,
+            min_filter: self.min_filter,
+            mipmap_filter: self.mipmap_filter,
+            anisotropy_clamp: if linear {
+                self.anisotropy.clamp(1, 16)
+            } else {
+                1
+            },
+            ..Default::default()
         })
     }
 }
@@ -189,3 +301,20 @@ pub struct Texture {
 pub struct Material {
     pub diffuse: Texture,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_ignore_transparent_pixels_when_downsampling() {
+        let mut image = RgbaImage::from_pixel(2, 2, Rgba([0, 0, 0, 0]));
+        image.put_pixel(0, 0, Rgba([200, 100, 50, 255]));
+        image.put_pixel(1, 1, Rgba([100, 100, 50, 255]));
+
+        let mip = downsample(&image);
+        assert_eq!(mip.dimensions(), (1, 1));
+        assert_eq!(*mip.get_pixel(0, 0), Rgba([150, 100, 50, 128]));
+        assert_eq!(downsample(&mip).dimensions(), (1, 1));
+    }
+}
@@ -0,0 +1,327 @@
+use std::sync::Arc;
+
+use crate::blocks::block::{BlockVertexData, FaceDirections};
+use crate::blocks::block_type::BlockType;
+use crate::chunk::{BlockVec, Chunk, SECTION_HEIGHT};
+use crate::culling::SectionVisibility;
+use crate::light::{LightVec, LightWorld};
+use crate::world::{NoiseData, CHUNK_SIZE};
+
+/* The data of a chunk the mesher reads, without any GPU resource.
+ * The blocks and the light are shared with the chunk, so taking a snapshot is cheap
+ */
+#[derive(Clone, Debug)]
+pub struct ChunkSnapshot {
+    pub x: i32,
+    pub y: i32,
+    pub blocks: BlockVec,
+    pub light: LightVec,
+}
+
+impl ChunkSnapshot {
+    pub fn get_block_type_at(&self, position: &glam::Vec3) -> Option<BlockType> {
+        let blocks = self.blocks.read().unwrap();
+        let y_blocks =
+            blocks.get(((position.x as u32 * CHUNK_SIZE) + position.z as u32) as usize)?;
+        let block = y_blocks.get(position.y as usize)?.as_ref()?;
+        let block_type = block.read().unwrap().block_type;
+        Some(block_type)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MeshData {
+    pub vertex: Vec<BlockVertexData>,
+    pub indices: Vec<u32>,
+}
+
+impl MeshData {
+    // indices: relative to the appended vertices
+    pub fn append(&mut self, mut vertex: Vec<BlockVertexData>, indices: &[u32]) {
+        let offset = self.vertex.len() as u32;
+        self.vertex.append(&mut vertex);
+        self.indices.extend(indices.iter().map(|i| i + offset));
+    }
+}
+
+// The CPU side of a chunk section, uploaded with ChunkSection::new
+#[derive(Debug, Default)]
+pub struct SectionMeshData {
+    pub opaque: MeshData,
+    // Blocks that are blended with what's behind them
+    pub translucent: MeshData,
+    pub visibility: SectionVisibility,
+}
+
+/* Builds the mesh of the blocks that are inside the given section.
+ * neighbours: the loaded chunks around the chunk, used for the faces, ao and light on its border
+ * noise_data: used to guess the terrain of the neighbour chunks that aren't loaded
+ */
+pub fn build_section_mesh(
+    chunk: &ChunkSnapshot,
+    neighbours: &[ChunkSnapshot],
+    noise_data: &Arc<NoiseData>,
+    section: usize,
+) -> SectionMeshData {
+    let mut mesh = SectionMeshData::default();
+
+    let adjacent_chunks = std::iter::once(chunk)
+        .chain(neighbours.iter())
+        .map(|c| ((c.x, c.y), c.blocks.clone()))
+        .collect::<Vec<_>>();
+    let light_world = LightWorld::new(
+        std::iter::once(chunk)
+            .chain(neighbours.iter())
+            .map(|c| ((c.x, c.y), c.blocks.clone(), c.light.clone()))
+            .collect(),
+    );
+    let chunk_offset = glam::vec3(
+        (chunk.x * CHUNK_SIZE as i32) as f32,
+        0.0,
+        (chunk.y * CHUNK_SIZE as i32) as f32,
+    );
+
+    let section_start = section * SECTION_HEIGHT as usize;
+    let section_end = section_start + SECTION_HEIGHT as usize;
+    let mut opaque_cells = vec![false; (CHUNK_SIZE * CHUNK_SIZE * SECTION_HEIGHT) as usize];
+
+    for region in chunk.blocks.read().unwrap().iter() {
+        for y in section_start..usize::min(section_end, region.len()) {
+            let Some(block_ptr) = &region[y] else {
+                continue;
+            };
+            let block = block_ptr.read().unwrap();
+            let position = block.position;
+            opaque_cells[SectionVisibility::cell_index(
+                position.x as usize,
+                y - section_start,
+                position.z as usize,
+            )] = block.block_type.is_opaque();
+
+            let visible_faces = FaceDirections::all()
+                .into_iter()
+                .filter(|face| {
+                    is_face_visible(
+                        chunk,
+                        neighbours,
+                        noise_data,
+                        &block.block_type,
+                        position,
+                        face,
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            let (mut vertex_data, index_data) = block.get_shape().create_mesh_data(
+                block_ptr.clone(),
+                &visible_faces,
+                &adjacent_chunks,
+            );
+            for vertex in vertex_data.iter_mut() {
+                let (sky, block) = light_world
+                    .get_vertex_light(&(vertex.position() + chunk_offset), vertex.face());
+                vertex.set_light(sky, block);
+            }
+
+            if block.block_type.is_translucent() {
+                mesh.translucent.append(vertex_data, &index_data);
+            } else {
+                mesh.opaque.append(vertex_data, &index_data);
+            }
+        }
+    }
+
+    mesh.visibility = SectionVisibility::from_opaque_cells(&opaque_cells);
+    mesh
+}
+
+// position: chunk-local position of the block the face belongs to
+fn is_face_visible(
+    chunk: &ChunkSnapshot,
+    neighbours: &[ChunkSnapshot],
+    noise_data: &Arc<NoiseData>,
+    block_type: &BlockType,
+    position: glam::Vec3,
+    face: &FaceDirections,
+) -> bool {
+    let face_position = face.get_normal_vector() + position;
+
+    if Chunk::is_outside_bounds(&face_position) {
+        return false;
+    }
+    if !Chunk::is_outside_chunk(&face_position) {
+        return match chunk.get_block_type_at(&face_position) {
+            Some(neighbour) => !neighbour.hides_face_of(block_type),
+            None => true,
+        };
+    }
+
+    let target_chunk_x = chunk.x + (f32::floor(face_position.x / CHUNK_SIZE as f32) as i32);
+    let target_chunk_y = chunk.y + (f32::floor(face_position.z / CHUNK_SIZE as f32) as i32);
+    let target_block = glam::vec3(
+        (face_position.x + CHUNK_SIZE as f32) % CHUNK_SIZE as f32,
+        face_position.y,
+        (face_position.z + CHUNK_SIZE as f32) % CHUNK_SIZE as f32,
+    );
+
+    // If there's a chunk loaded in memory then check that, else it means we're on a edge and we can
+    // Calculate the block's height when the chunk gets generated
+    // TODO: Check for saved file chunk
+    match neighbours
+        .iter()
+        .find(|c| c.x == target_chunk_x && c.y == target_chunk_y)
+    {
+        Some(neighbour_chunk) => match neighbour_chunk.get_block_type_at(&target_block) {
+            Some(neighbour) => !neighbour.hides_face_of(block_type),
+            None => true,
+        },
+        None => {
+            face_position.y as u32
+                > Chunk::get_height_value(
+                    target_chunk_x,
+                    target_chunk_y,
+                    target_block.x as u32,
+                    target_block.z as u32,
+                    noise_data.clone(),
+                )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use super::*;
+    use crate::blocks::block::Block;
+    use crate::light::create_light_vec;
+    use crate::world::NOISE_SIZE;
+
+    fn create_chunk(x: i32, y: i32, blocks: &[(glam::Vec3, BlockType)]) -> ChunkSnapshot {
+        let mut columns = vec![vec![]; (CHUNK_SIZE * CHUNK_SIZE) as usize];
+        for (position, block_type) in blocks {
+            let column: &mut Vec<_> =
+                &mut columns[(position.x as u32 * CHUNK_SIZE + position.z as u32) as usize];
+            column.resize(usize::max(column.len(), position.y as usize + 1), None);
+            column[position.y as usize] = Some(Arc::new(RwLock::new(Block::new(
+                *position,
+                (x, y),
+                *block_type,
+            ))));
+        }
+        ChunkSnapshot {
+            x,
+            y,
+            blocks: Arc::new(RwLock::new(columns)),
+            light: create_light_vec(),
+        }
+    }
+
+    // Terrain that is 0 blocks high everywhere
+    fn flat_noise() -> Arc<NoiseData> {
+        Arc::new(vec![-1.0; (NOISE_SIZE * NOISE_SIZE) as usize])
+    }
+
+    #[test]
+    fn should_only_mesh_the_visible_faces() {
+        let chunk = create_chunk(
+            0,
+            0,
+            &[
+                (glam::vec3(4.0, 5.0, 4.0), BlockType::stone()),
+                (glam::vec3(5.0, 5.0, 4.0), BlockType::stone()),
+            ],
+        );
+        let mesh = build_section_mesh(&chunk, &[], &flat_noise(), 0);
+
+        // The faces touching each other are culled
+        assert_eq!(mesh.opaque.indices.len(), 10 * 6);
+        assert_eq!(mesh.opaque.vertex.len(), 10 * 4);
+        assert!(mesh.translucent.indices.is_empty());
+
+        // Nothing is in the next section
+        let mesh = build_section_mesh(&chunk, &[], &flat_noise(), 1);
+        assert!(mesh.opaque.indices.is_empty());
+    }
+
+    #[test]
+    fn should_cull_faces_against_the_neighbour_chunks() {
+        let chunk = create_chunk(0, 0, &[(glam::vec3(15.0, 5.0, 4.0), BlockType::stone())]);
+        let neighbour = create_chunk(1, 0, &[(glam::vec3(0.0, 5.0, 4.0), BlockType::stone())]);
+        let noise_data = flat_noise();
+
+        let mesh = build_section_mesh(&chunk, &[], &noise_data, 0);
+        assert_eq!(mesh.opaque.indices.len(), 6 * 6);
+        let mesh = build_section_mesh(&chunk, &[neighbour], &noise_data, 0);
+        assert_eq!(mesh.opaque.indices.len(), 5 * 6);
+    }
+
+    #[test]
+    fn should_put_translucent_blocks_in_their_own_mesh() {
+        let chunk = create_chunk(
+            0,
+            0,
+            &[
+                (glam::vec3(4.0, 5.0, 4.0), BlockType::water()),
+                (glam::vec3(5.0, 5.0, 4.0), BlockType::stone()),
+            ],
+        );
+        let mesh = build_section_mesh(&chunk, &[], &flat_noise(), 0);
+
+        // Water doesn't hide the stone, but the stone hides the water
+        assert_eq!(mesh.opaque.indices.len(), 6 * 6);
+        assert_eq!(mesh.translucent.indices.len(), 5 * 6);
+    }
+
+    #[test]
+    fn should_darken_the_corners_next_to_other_blocks() {
+        let chunk = create_chunk(
+            0,
+            0,
+            &[
+                (glam::vec3(4.0, 5.0, 4.0), BlockType::stone()),
+                (glam::vec3(5.0, 6.0, 4.0), BlockType::stone()),
+            ],
+        );
+        let mesh = build_section_mesh(&chunk, &[], &flat_noise(), 0);
+
+        let top_vertices = mesh
+            .opaque
+            .vertex
+            .iter()
+            .filter(|v| v.face() == FaceDirections::Top && v.position().y == 5.5)
+            .collect::<Vec<_>>();
+        assert_eq!(top_vertices.len(), 4);
+        // The corners of the lower block's top face touching the upper block are occluded
+        for vertex in top_vertices {
+            if vertex.position().x == 4.5 {
+                assert!(vertex.ao() < 3);
+            } else {
+                assert_eq!(vertex.ao(), 3);
+            }
+        }
+    }
+
+    #[test]
+    fn should_close_the_visibility_of_filled_sections() {
+        let blocks = (0..CHUNK_SIZE)
+            .flat_map(|x| {
+                (0..CHUNK_SIZE).flat_map(move |z| {
+                    (0..SECTION_HEIGHT).map(move |y| {
+                        (glam::vec3(x as f32, y as f32, z as f32), BlockType::stone())
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        let mesh = build_section_mesh(&create_chunk(0, 0, &blocks), &[], &flat_noise(), 0);
+
+        assert!(!mesh
+            .visibility
+            .is_connected(FaceDirections::Top, FaceDirections::Bottom));
+        // Only the top layer and the chunk's border above the flat terrain around it are visible
+        assert_eq!(
+            mesh.opaque.indices.len() as u32,
+            (CHUNK_SIZE * CHUNK_SIZE + 4 * CHUNK_SIZE * (SECTION_HEIGHT - 1)) * 6
+        );
+    }
+}
@@ -1,10 +1,15 @@
 use std::any::Any;
 use std::error::Error;
+use std::path::Path;
 
+// Folder the game saves the world and the player to, relative to the working directory
+pub const DATA_DIR: &str = "data";
+
+// dir: folder of the save, e.g. DATA_DIR
 pub trait Saveable<T> {
-    fn save(&self) -> Result<(), Box<dyn Error>>;
+    fn save(&self, dir: &Path) -> Result<(), Box<dyn Error>>;
 }
 
 pub trait Loadable<T> {
-    fn load(args: Box<dyn Any>) -> Result<T, Box<dyn Error>>;
+    fn load(dir: &Path, args: Box<dyn Any>) -> Result<T, Box<dyn Error>>;
 }
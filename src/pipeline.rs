@@ -7,10 +7,17 @@ use obj::Vertex;
 use wgpu::{include_wgsl, util::DeviceExt, BindGroup, Buffer, Face, RenderPipeline};
 
 use crate::{
-    blocks::block::Block,
+    arena::MeshArena,
+    blocks::{atlas, block::Block, block_type::BlockType},
+    clock::WorldClock,
+    lod::LOD_RADIUS,
     material::{Material, Texture},
     player::Camera,
+    post::{PostProcess, HDR_FORMAT},
+    render_graph::{Frame, RenderGraph, RenderNode},
+    shadow::ShadowMap,
     state::State,
+    world::CHUNK_SIZE,
 };
 
 struct Matrices {
@@ -34,12 +41,79 @@ impl From<&Camera> for Uniforms {
     }
 }
 
+// The edge of the LOD terrain is fully hidden by the fog
+const FOG_END: f32 = (LOD_RADIUS * CHUNK_SIZE as i32) as f32;
+
+// Lighting that depends on the time of the day
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable)]
+pub struct EnvironmentUniforms {
+    // xyz: direction towards the sun
+    pub sun_direction: [f32; 4],
+    pub sky_color: [f32; 4],
+    // Multiplies the sky light of the blocks
+    pub sky_light: f32,
+    // Strength of the directional sun light
+    pub sun_intensity: f32,
+    // Distances from the camera where the terrain starts and ends fading into the sky
+    pub fog_start: f32,
+    pub fog_end: f32,
+    // Seconds since the game started, drives the animations
+    pub time: f32,
+    pub _padding: [f32; 3],
+}
+
+impl From<&WorldClock> for EnvironmentUniforms {
+    fn from(clock: &WorldClock) -> Self {
+        Self {
+            sun_direction: clock.sun_direction().extend(0.0).to_array(),
+            sky_color: clock.sky_color().extend(1.0).to_array(),
+            sky_light: clock.sky_light_multiplier(),
+            sun_intensity: clock.daylight(),
+            fog_start: FOG_END * 0.6,
+            fog_end: FOG_END,
+            time: 0.0,
+            _padding: [0.0; 3],
+        }
+    }
+}
+
+// Animation and glow of every tile of the atlas, indexed by the tile of the first frame
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable)]
+pub struct AnimationUniforms {
+    // x: number of frames, y: seconds per frame, z: light emitted by the block, 0..1
+    pub tiles: [[f32; 4]; atlas::MAX_TILES],
+}
+// The shaders are read at runtime, `animations` in shader.wgsl has the size written in it
+const _: () = assert!(atlas::MAX_TILES == 64);
+
+impl From<&[BlockType]> for AnimationUniforms {
+    fn from(block_types: &[BlockType]) -> Self {
+        let mut tiles = [[1.0, 1.0, 0.0, 0.0]; atlas::MAX_TILES];
+        for block_type in block_types {
+            let config = block_type.get_config();
+            let emission = config.light_emission as f32 / 15.0;
+            if config.animation.is_none() && emission == 0.0 {
+                continue;
+            }
+            let (frames, frame_time) = config
+                .animation
+                .map_or((1.0, 1.0), |a| (a.frames as f32, a.frame_time));
+            let textures = config.textures;
+            for tile in [textures.side, textures.top, textures.bottom] {
+                tiles[tile as usize] = [frames, frame_time, emission, 0.0];
+            }
+        }
+        Self { tiles }
+    }
+}
+
 impl Pipeline {
     pub fn new(state: &State) -> Self {
-        let swapchain_capabilities = state.surface.get_capabilities(&state.adapter);
-        let swapchain_format = swapchain_capabilities.formats[0];
+        let swapchain_format = state.surface_config.format;
 
-        let shader_source = std::fs::read_to_string("src/shaders/shader.wgsl").unwrap();
+        let shader_source = state.resources.shader("shader.wgsl");
 
         let shader = state
             .device
@@ -68,6 +142,25 @@ impl Pipeline {
                 usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             });
 
+        let environment_buffer =
+            state
+                .device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("environment"),
+                    contents: bytemuck::cast_slice(&[EnvironmentUniforms::from(
+                        &state.world.clock,
+                    )]),
+                    usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                });
+        let block_types = BlockType::all().collect::<Vec<_>>();
+        let animation_buffer = state
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("animations"),
+                contents: bytemuck::cast_slice(&[AnimationUniforms::from(block_types.as_slice())]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+
         // Bind groups
         let bind_group_0_layout =
             state
@@ -95,30 +188,64 @@ impl Pipeline {
                             },
                             count: None,
                         },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 2,
+                            visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        // Shadow map, see ShadowMap
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 3,
+                            visibility: wgpu::ShaderStages::VERTEX,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 4,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                sample_type: wgpu::TextureSampleType::Depth,
+                                view_dimension: wgpu::TextureViewDimension::D2,
+                                multisampled: false,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 5,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                            count: None,
+                        },
+                        // Animated textures, see AnimationUniforms
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 6,
+                            visibility: wgpu::ShaderStages::VERTEX,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
                     ],
                 });
-        let bind_group_0 = state.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &bind_group_0_layout,
-            label: None,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: projection_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: view_buffer.as_entire_binding(),
-                },
-            ],
-        });
-
-        let texture_atlas = Texture::from_path(
-            "assets/tex_atlas.png",
-            "tex_atlas".to_string(),
+        let texture_atlas = Texture::block_array(
+            &state.resources.block_atlas(),
+            atlas::TILES_PER_ROW,
+            atlas::TILE_COUNT,
+            &state.config.block_sampler,
             &state.device,
             &state.queue,
-        )
-        .unwrap();
+        );
 
         let bind_group_1_layout =
             state
@@ -131,7 +258,7 @@ impl Pipeline {
                             visibility: wgpu::ShaderStages::FRAGMENT,
                             ty: wgpu::BindingType::Texture {
                                 sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                                view_dimension: wgpu::TextureViewDimension::D2,
+                                view_dimension: wgpu::TextureViewDimension::D2Array,
                                 multisampled: false,
                             },
                             count: None,
@@ -160,8 +287,45 @@ impl Pipeline {
             ],
         });
 
-        // Textures
-        let depth_texture = Texture::create_depth_texture(state);
+        let shadow_map = ShadowMap::new(
+            &state.device,
+            &state.resources.shader("shadow.wgsl"),
+            &bind_group_1_layout,
+        );
+        let bind_group_0 = state.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_0_layout,
+            label: None,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: projection_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: view_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: environment_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: shadow_map.light_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&shadow_map.texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Sampler(&shadow_map.texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: animation_buffer.as_entire_binding(),
+                },
+            ],
+        });
 
         // Pipeline layouts
         let pipeline_layout =
@@ -169,60 +333,182 @@ impl Pipeline {
                 .device
                 .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                     label: None,
-                    bind_group_layouts: &[
-                        &bind_group_0_layout,
-                        &bind_group_1_layout,
-                        &state.world.chunk_data_layout,
-                    ],
+                    bind_group_layouts: &[&bind_group_0_layout, &bind_group_1_layout],
                     push_constant_ranges: &[],
                 });
 
-        let render_pipeline =
+        let sky_pipeline_layout =
             state
                 .device
-                .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                    label: None,
-                    layout: Some(&pipeline_layout),
-                    vertex: wgpu::VertexState {
-                        module: &shader,
-                        entry_point: "vs_main",
-                        buffers: &[Block::get_vertex_data_layout()],
-                    },
-                    fragment: Some(wgpu::FragmentState {
-                        module: &shader,
-                        entry_point: "fs_main",
-                        targets: &[Some(swapchain_format.into())],
-                    }),
-
-                    primitive: wgpu::PrimitiveState {
-                        polygon_mode: state.config.polygon_mode,
-                        cull_mode: Some(Face::Front),
-                        ..Default::default()
-                    },
-                    depth_stencil: Some(wgpu::DepthStencilState {
-                        format: Texture::DEPTH_FORMAT,
-                        depth_write_enabled: true,
-                        depth_compare: wgpu::CompareFunction::Less,
-                        stencil: wgpu::StencilState::default(),
-                        bias: wgpu::DepthBiasState::default(),
-                    }),
-                    multisample: wgpu::MultisampleState::default(),
-                    multiview: None,
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("sky_pipeline_layout"),
+                    bind_group_layouts: &[&bind_group_0_layout],
+                    push_constant_ranges: &[],
                 });
+        let sky_pipeline = Self::create_sky_pipeline(state, &sky_pipeline_layout);
+
+        let render_pipeline = Self::create_render_pipeline(state, &pipeline_layout, &shader, false);
+        let translucent_pipeline =
+            Self::create_render_pipeline(state, &pipeline_layout, &shader, true);
+
+        let post_process = PostProcess::new(
+            &state.device,
+            &state.resources.shader("post.wgsl"),
+            &state.config.post,
+            swapchain_format,
+            &state.render_graph,
+        );
 
         Self {
             view_buffer,
             projection_buffer,
+            environment_buffer,
+            animation_buffer,
             pipeline_type: PipelineType::WORLD,
-            depth_texture,
             bind_group_0,
             bind_group_1,
             pipeline: render_pipeline,
+            translucent_pipeline,
+            sky_pipeline,
+            shadow_map,
+            post_process,
         }
     }
+    // Draws the sky dome behind the terrain, without writing to the depth buffer
+    fn create_sky_pipeline(
+        state: &State,
+        pipeline_layout: &wgpu::PipelineLayout,
+    ) -> wgpu::RenderPipeline {
+        let shader_source = state.resources.shader("sky.wgsl");
+        let shader = state
+            .device
+            .create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("sky_shader"),
+                source: wgpu::ShaderSource::Wgsl(shader_source.into()),
+            });
+
+        state
+            .device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("sky_pipeline"),
+                layout: Some(pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: HDR_FORMAT,
+                        blend: None,
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: Texture::DEPTH_FORMAT,
+                    depth_write_enabled: false,
+                    depth_compare: wgpu::CompareFunction::Always,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState {
+                    count: state.config.post.anti_aliasing.samples(),
+                    ..Default::default()
+                },
+                multiview: None,
+            })
+    }
+    // Translucent blocks are blended on top of the opaque ones, without writing to the depth buffer
+    fn create_render_pipeline(
+        state: &State,
+        pipeline_layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+        translucent: bool,
+    ) -> wgpu::RenderPipeline {
+        state
+            .device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(if translucent {
+                    "translucent_pipeline"
+                } else {
+                    "world_pipeline"
+                }),
+                layout: Some(pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: shader,
+                    entry_point: "vs_main",
+                    buffers: &[Block::get_vertex_data_layout(), MeshArena::chunk_layout()],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: shader,
+                    entry_point: "fs_main",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: HDR_FORMAT,
+                        blend: translucent.then_some(wgpu::BlendState::ALPHA_BLENDING),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+
+                primitive: wgpu::PrimitiveState {
+                    polygon_mode: state.config.polygon_mode,
+                    // Translucent faces can be seen from both sides (e.g. the water surface from below)
+                    cull_mode: if translucent { None } else { Some(Face::Front) },
+                    ..Default::default()
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: Texture::DEPTH_FORMAT,
+                    depth_write_enabled: !translucent,
+                    depth_compare: wgpu::CompareFunction::Less,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState {
+                    count: state.config.post.anti_aliasing.samples(),
+                    ..Default::default()
+                },
+                multiview: None,
+            })
+    }
+}
+
+// Node of the render graph drawing the sky, then the opaque and translucent terrain
+pub fn draw_world_pass<'a>(
+    _node: &'a RenderNode,
+    pipeline: &'a dyn PipelineTrait,
+    frame: &'a Frame<'a>,
+    rpass: &mut wgpu::RenderPass<'a>,
+) {
+    rpass.set_bind_group(0, pipeline.bind_group_0(), &[]);
+    if let Some(sky_pipeline) = pipeline.sky_pipeline() {
+        rpass.set_pipeline(sky_pipeline);
+        rpass.draw(0..3, 0..1);
+    }
+
+    rpass.set_pipeline(pipeline.pipeline());
+    rpass.set_bind_group(1, pipeline.bind_group_1(), &[]);
+
+    let arena = &frame.state.world.arena;
+    arena.bind(rpass);
+    arena.draw_batch(rpass, frame.opaque_draws, frame.draw_offsets[0]);
+
+    if let Some(translucent_pipeline) = pipeline.translucent_pipeline() {
+        rpass.set_pipeline(translucent_pipeline);
+        arena.draw_batch(rpass, frame.translucent_draws, frame.draw_offsets[1]);
+    }
 }
 
 impl PipelineTrait for Pipeline {
+    fn post_process(&self) -> Option<&PostProcess> {
+        Some(&self.post_process)
+    }
+
+    fn resize(&mut self, device: &wgpu::Device, graph: &RenderGraph) {
+        self.post_process.resize(device, graph);
+    }
+
     fn projection_buffer(&self) -> &Buffer {
         &self.projection_buffer
     }
@@ -231,16 +517,26 @@ impl PipelineTrait for Pipeline {
         &self.pipeline
     }
 
-    fn view_buffer(&self) -> &Buffer {
-        &self.view_buffer
+    fn translucent_pipeline(&self) -> Option<&RenderPipeline> {
+        Some(&self.translucent_pipeline)
     }
 
-    fn depth_texture(&self) -> &Texture {
-        &self.depth_texture
+    fn sky_pipeline(&self) -> Option<&RenderPipeline> {
+        Some(&self.sky_pipeline)
     }
-    fn set_depth_texture(&mut self, texture: Texture) {
-        self.depth_texture = texture;
+
+    fn shadow_map(&self) -> Option<&ShadowMap> {
+        Some(&self.shadow_map)
     }
+
+    fn environment_buffer(&self) -> Option<&Buffer> {
+        Some(&self.environment_buffer)
+    }
+
+    fn view_buffer(&self) -> &Buffer {
+        &self.view_buffer
+    }
+
     fn bind_group_0(&self) -> &BindGroup {
         &self.bind_group_0
     }
@@ -256,27 +552,53 @@ impl PipelineTrait for Pipeline {
 pub trait PipelineTrait {
     fn projection_buffer(&self) -> &wgpu::Buffer;
     fn pipeline(&self) -> &wgpu::RenderPipeline;
+    // Pipeline used to draw translucent geometry, if the pipeline has any
+    fn translucent_pipeline(&self) -> Option<&wgpu::RenderPipeline> {
+        None
+    }
+    // Pipeline drawing the sky behind everything else, if the pipeline has any
+    fn sky_pipeline(&self) -> Option<&wgpu::RenderPipeline> {
+        None
+    }
+    // Shadows cast by the sun, if the pipeline draws any
+    fn shadow_map(&self) -> Option<&ShadowMap> {
+        None
+    }
+    // Effects applied to the frame, if the pipeline draws them
+    fn post_process(&self) -> Option<&PostProcess> {
+        None
+    }
+    // Called when the attachments of the render graph are recreated
+    fn resize(&mut self, _device: &wgpu::Device, _graph: &RenderGraph) {}
     fn view_buffer(&self) -> &wgpu::Buffer;
+    // Time of the day dependent lighting, if the pipeline uses it
+    fn environment_buffer(&self) -> Option<&wgpu::Buffer> {
+        None
+    }
     fn bind_group_0(&self) -> &wgpu::BindGroup;
     fn bind_group_1(&self) -> &wgpu::BindGroup;
-    fn depth_texture(&self) -> &Texture;
-    fn set_depth_texture(&mut self, texture: Texture);
 
     fn get_type(&self) -> PipelineType;
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum PipelineType {
     WORLD,
     UI,
+    CLOUDS,
 }
 
 pub struct Pipeline {
     pub projection_buffer: wgpu::Buffer,
     pub view_buffer: wgpu::Buffer,
+    pub environment_buffer: wgpu::Buffer,
+    pub animation_buffer: wgpu::Buffer,
     pub pipeline: wgpu::RenderPipeline,
+    pub translucent_pipeline: wgpu::RenderPipeline,
+    pub sky_pipeline: wgpu::RenderPipeline,
+    pub shadow_map: ShadowMap,
+    pub post_process: PostProcess,
     pub bind_group_0: wgpu::BindGroup,
     pub bind_group_1: wgpu::BindGroup,
-    pub depth_texture: Texture,
     pub pipeline_type: PipelineType,
 }
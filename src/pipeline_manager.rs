@@ -0,0 +1,108 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::clouds::CloudSettings;
+use crate::material::SamplerSettings;
+use crate::post::PostSettings;
+use crate::resources::ResourcePacks;
+use crate::state::Config;
+
+// How often the shader files are checked for changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Tells when the render pipelines are out of date, because a shader file or a setting they are built with changed
+pub struct PipelineManager {
+    files: Vec<PathBuf>,
+    // Modification time of every file when the pipelines were built, None when it doesn't exist
+    modified: Vec<Option<SystemTime>>,
+    polygon_mode: wgpu::PolygonMode,
+    block_sampler: SamplerSettings,
+    post: PostSettings,
+    clouds: CloudSettings,
+    last_check: Instant,
+}
+
+impl PipelineManager {
+    pub fn new(resources: &ResourcePacks, config: &Config) -> Self {
+        let files = resources.shader_files();
+        Self {
+            modified: Self::modification_times(&files),
+            files,
+            polygon_mode: config.polygon_mode,
+            block_sampler: config.block_sampler,
+            post: config.post,
+            clouds: config.clouds,
+            last_check: Instant::now(),
+        }
+    }
+    fn modification_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+        files
+            .iter()
+            .map(|f| std::fs::metadata(f).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+    pub fn needs_rebuild(&mut self, config: &Config) -> bool {
+        if config.polygon_mode != self.polygon_mode
+            || config.block_sampler != self.block_sampler
+            || config.post != self.post
+            || config.clouds != self.clouds
+        {
+            return true;
+        }
+        if self.last_check.elapsed() < CHECK_INTERVAL {
+            return false;
+        }
+        self.last_check = Instant::now();
+        Self::modification_times(&self.files) != self.modified
+    }
+    // Call after building the pipelines, whether it succeeded or not, so that a broken shader
+    // is only reported once
+    pub fn on_rebuild(&mut self, resources: &ResourcePacks, config: &Config) {
+        *self = Self::new(resources, config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_rebuild_when_a_shader_changes() {
+        let dir = std::env::temp_dir().join(format!("pipeline_manager_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("shaders")).unwrap();
+        let resources = ResourcePacks {
+            packs: vec![crate::resources::ResourcePack::open(&dir)],
+        };
+        let mut config = Config {
+            polygon_mode: wgpu::PolygonMode::Fill,
+            block_sampler: Default::default(),
+            post: Default::default(),
+            clouds: Default::default(),
+        };
+        let mut manager = PipelineManager::new(&resources, &config);
+        manager.last_check -= CHECK_INTERVAL;
+        assert!(!manager.needs_rebuild(&config));
+
+        // A pack shader that didn't exist
+        std::fs::write(dir.join("shaders/sky.wgsl"), "").unwrap();
+        manager.last_check -= CHECK_INTERVAL;
+        assert!(manager.needs_rebuild(&config));
+        manager.on_rebuild(&resources, &config);
+        assert!(!manager.needs_rebuild(&config));
+
+        config.polygon_mode = wgpu::PolygonMode::Line;
+        assert!(manager.needs_rebuild(&config));
+        manager.on_rebuild(&resources, &config);
+
+        // The sampler is rebuilt with the pipelines
+        config.block_sampler = config.block_sampler.next();
+        assert!(manager.needs_rebuild(&config));
+        manager.on_rebuild(&resources, &config);
+        assert!(!manager.needs_rebuild(&config));
+        // Back to the default after the last preset
+        let sampler = (0..3).fold(config.block_sampler, |sampler, _| sampler.next());
+        assert_eq!(sampler, SamplerSettings::default());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,12 +1,14 @@
 use std::any::Any;
 use std::error::Error;
 use std::f32::consts;
+use std::path::Path;
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 
 use glam::{vec2, vec3, Mat2, Vec2, Vec3};
 
 use crate::blocks::block::{Block, FaceDirections};
+use crate::blocks::block_type::BlockType;
 use crate::collision::{CollisionPoint, RayResult};
 use crate::persistence::{Loadable, Saveable};
 use crate::{
@@ -23,6 +25,65 @@ lazy_static! {
     static ref JUMP_DURATION: Duration = Duration::from_secs_f32(0.1);
 }
 const JUMP_HEIGHT: f32 = 1.5;
+// Ids of the block types that can be selected with the number keys
+pub const HOTBAR: [u32; 10] = [0, 3, 4, 2, 11, 6, 7, 8, 9, 12];
+// Pause after breaking a block before the next one starts breaking, in seconds
+const MINING_COOLDOWN: f32 = 0.25;
+
+// Breaking of the facing block, kept between the clicks so that they can't skip the cooldown
+#[derive(Debug, Default)]
+pub struct Mining {
+    // Whether the left mouse button is held
+    pub is_held: bool,
+    // Absolute position of the block being broken
+    pub block: Option<Vec3>,
+    // 0..1, the block breaks at 1
+    pub progress: f32,
+    cooldown: f32,
+}
+
+impl Mining {
+    /* target: absolute position and break time of the facing block.
+     * Returns true when the block breaks
+     */
+    pub fn update(&mut self, target: Option<(Vec3, f32)>, delta_time: f32) -> bool {
+        if self.cooldown > 0.0 {
+            self.cooldown -= delta_time;
+            return false;
+        }
+        if !self.is_held {
+            return false;
+        }
+        let Some((position, break_time)) = target else {
+            self.block = None;
+            self.progress = 0.0;
+            return false;
+        };
+        // Looking at another block starts over
+        if self.block != Some(position) {
+            self.block = Some(position);
+            self.progress = 0.0;
+        }
+        self.progress += if break_time > 0.0 {
+            delta_time / break_time
+        } else {
+            1.0
+        };
+        if self.progress < 1.0 {
+            return false;
+        }
+        self.block = None;
+        self.progress = 0.0;
+        self.cooldown = MINING_COOLDOWN;
+        true
+    }
+    // Letting go of the button loses the progress, the cooldown keeps running until the next click
+    pub fn release(&mut self) {
+        self.is_held = false;
+        self.block = None;
+        self.progress = 0.0;
+    }
+}
 
 pub struct CameraController {
     pub movement_vector: Vec3,
@@ -44,6 +105,10 @@ pub struct Player {
     pub is_ghost: bool,
     pub facing_block: Option<Arc<RwLock<Block>>>,
     pub facing_face: Option<FaceDirections>,
+    // Block type that gets placed on right click
+    pub selected_block: BlockType,
+    // None until the first left click, Mining::is_held tells whether the button is still held
+    pub mining: Option<Mining>,
 }
 impl Player {
     // Position relative to the chunk
@@ -229,14 +294,9 @@ pub struct Camera {
 
 impl Camera {
     pub fn new(surface_width: f32, surface_height: f32) -> Camera {
-        let eye = if let Ok(eye) = Camera::load(Box::new(())) {
-            eye
-        } else {
-            glam::vec3(-4.0, 50.0, 4.0)
-        };
         Self {
             aspect_ratio: surface_width / surface_height,
-            eye,
+            eye: glam::vec3(-4.0, 50.0, 4.0),
             yaw: consts::FRAC_PI_2,
             pitch: 0.0,
 
@@ -263,6 +323,21 @@ impl Camera {
         direction.normalize()
     }
 
+    // Horizontal direction the camera looks the most towards
+    pub fn horizontal_facing(&self) -> FaceDirections {
+        let direction = self.calc_target();
+        if direction.x.abs() > direction.z.abs() {
+            if direction.x > 0.0 {
+                FaceDirections::Right
+            } else {
+                FaceDirections::Left
+            }
+        } else if direction.z > 0.0 {
+            FaceDirections::Back
+        } else {
+            FaceDirections::Front
+        }
+    }
     // target only moves in y and x direction
     pub fn move_target(&mut self, direction: &Vec2) {
         self.yaw -= direction.x * SENSITIVITY;
@@ -273,22 +348,21 @@ impl Camera {
 }
 
 impl Saveable<glam::Vec3> for Camera {
-    fn save(&self) -> Result<(), Box<dyn Error>> {
-        if let Ok(_) = std::fs::create_dir("data") {
+    fn save(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        if let Ok(_) = std::fs::create_dir_all(dir) {
             println!("Created dir");
         }
         let data = format!("{},{},{}", self.eye.x, self.eye.y, self.eye.z);
 
-        let player_file_name = "data/player";
-        std::fs::write(player_file_name, data.as_bytes())?;
+        std::fs::write(dir.join("player"), data.as_bytes())?;
 
         Ok(())
     }
 }
 
 impl Loadable<glam::Vec3> for Camera {
-    fn load(_: Box<dyn Any>) -> Result<Vec3, Box<dyn Error>> {
-        let data = String::from_utf8(std::fs::read("data/player")?)?;
+    fn load(dir: &Path, _: Box<dyn Any>) -> Result<Vec3, Box<dyn Error>> {
+        let data = String::from_utf8(std::fs::read(dir.join("player"))?)?;
         let mut data = data.split(",");
         let x = data.next().unwrap().parse::<f32>().unwrap();
         let y = data.next().unwrap().parse::<f32>().unwrap();
@@ -297,3 +371,60 @@ impl Loadable<glam::Vec3> for Camera {
         return Ok(glam::vec3(x, y, z));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_face_where_the_camera_looks_horizontally() {
+        let mut camera = Camera::new(4.0, 3.0);
+        // Looking down a lot still faces somewhere
+        camera.pitch = -1.4;
+        camera.yaw = 0.0;
+        assert_eq!(camera.horizontal_facing(), FaceDirections::Right);
+        camera.yaw = consts::PI;
+        assert_eq!(camera.horizontal_facing(), FaceDirections::Left);
+        camera.yaw = consts::FRAC_PI_2 + 0.3;
+        assert_eq!(camera.horizontal_facing(), FaceDirections::Back);
+        camera.yaw = -consts::FRAC_PI_2;
+        assert_eq!(camera.horizontal_facing(), FaceDirections::Front);
+    }
+
+    #[test]
+    fn should_break_the_block_after_its_break_time() {
+        let mut mining = Mining {
+            is_held: true,
+            ..Default::default()
+        };
+        let stone = Some((vec3(1.0, 2.0, 3.0), 2.0));
+        let other_stone = Some((vec3(1.0, 3.0, 3.0), 2.0));
+        assert!(!mining.update(stone, 1.0));
+        // Turning to another block loses the progress
+        assert!(!mining.update(other_stone, 1.0));
+        assert!(!mining.update(other_stone, 0.5));
+        assert!(mining.update(other_stone, 0.5));
+        assert_eq!(mining.block, None);
+
+        // Blocks without a break time break at once, after the cooldown
+        let flower = Some((vec3(0.0, 0.0, 0.0), 0.0));
+        assert!(!mining.update(flower, MINING_COOLDOWN));
+        assert!(mining.update(flower, 0.01));
+
+        // Clicking again doesn't skip the cooldown
+        mining.release();
+        assert!(!mining.update(flower, 0.01));
+        mining.is_held = true;
+        assert!(!mining.update(flower, MINING_COOLDOWN - 0.01));
+        assert!(mining.update(flower, 0.01));
+
+        // Clicking the same block again starts over
+        assert!(!mining.update(stone, MINING_COOLDOWN));
+        assert!(!mining.update(stone, 1.5));
+        mining.release();
+        assert_eq!(mining.progress, 0.0);
+        mining.is_held = true;
+        assert!(!mining.update(stone, 1.5));
+        assert!(mining.update(stone, 0.5));
+    }
+}
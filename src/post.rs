@@ -0,0 +1,393 @@
+use bytemuck::{Pod, Zeroable};
+use wgpu::util::DeviceExt;
+
+use crate::pipeline::{PipelineTrait, PipelineType};
+use crate::render_graph::{
+    AttachmentDesc, AttachmentSize, Frame, RenderGraph, RenderNode, SURFACE,
+};
+
+// The world is drawn with colors brighter than white, brought back to the screen by the tone mapping
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+// The bloom is blurred at half the resolution of the screen
+const BLOOM_SCALE: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AntiAliasing {
+    None,
+    // Blurs the edges found in the final image
+    Fxaa,
+    // Number of samples per pixel of the world pass
+    Msaa(u32),
+}
+
+impl AntiAliasing {
+    pub fn next(self) -> Self {
+        match self {
+            AntiAliasing::None => AntiAliasing::Fxaa,
+            AntiAliasing::Fxaa => AntiAliasing::Msaa(4),
+            AntiAliasing::Msaa(_) => AntiAliasing::None,
+        }
+    }
+    pub fn samples(self) -> u32 {
+        match self {
+            AntiAliasing::Msaa(samples) => samples,
+            _ => 1,
+        }
+    }
+}
+
+// Effects applied to the frame, toggled from the settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PostSettings {
+    pub anti_aliasing: AntiAliasing,
+    pub tone_mapping: bool,
+    // Glow around the emissive blocks and the sun
+    pub bloom: bool,
+}
+
+impl Default for PostSettings {
+    fn default() -> Self {
+        Self {
+            anti_aliasing: AntiAliasing::Fxaa,
+            tone_mapping: true,
+            bloom: true,
+        }
+    }
+}
+
+// Whether the world pass can be drawn with that many samples
+pub fn supports_msaa(adapter: &wgpu::Adapter, samples: u32) -> bool {
+    [HDR_FORMAT, crate::material::Texture::DEPTH_FORMAT]
+        .iter()
+        .all(|format| {
+            adapter
+                .get_texture_format_features(*format)
+                .flags
+                .sample_count_supported(samples)
+        })
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable)]
+pub struct PostUniforms {
+    pub tone_mapping: u32,
+    pub bloom: u32,
+    pub encode_gamma: u32,
+    pub _padding: u32,
+}
+
+impl PostUniforms {
+    // output_format: of the surface the frame is presented on
+    pub fn new(settings: &PostSettings, output_format: wgpu::TextureFormat) -> Self {
+        Self {
+            tone_mapping: settings.tone_mapping as u32,
+            bloom: settings.bloom as u32,
+            encode_gamma: !output_format.is_srgb() as u32,
+            _padding: 0,
+        }
+    }
+}
+
+// A full screen pass of the chain, drawing one fragment entry point of post.wgsl
+struct Step {
+    name: &'static str,
+    entry_point: &'static str,
+    // Sampled attachments, the second one is the bloom added by the tone mapping
+    inputs: &'static [&'static str],
+    output: &'static str,
+}
+
+// Steps of the chain, from the HDR frame to the surface
+fn steps(settings: &PostSettings) -> Vec<Step> {
+    let mut steps = vec![];
+    if settings.bloom {
+        steps.push(Step {
+            name: "bloom_extract",
+            entry_point: "fs_bloom_extract",
+            inputs: &["hdr"],
+            output: "bloom",
+        });
+        steps.push(Step {
+            name: "bloom_blur_horizontal",
+            entry_point: "fs_blur_horizontal",
+            inputs: &["bloom"],
+            output: "bloom_blur",
+        });
+        steps.push(Step {
+            name: "bloom_blur_vertical",
+            entry_point: "fs_blur_vertical",
+            inputs: &["bloom_blur"],
+            output: "bloom",
+        });
+    }
+    let fxaa = settings.anti_aliasing == AntiAliasing::Fxaa;
+    steps.push(Step {
+        name: "tone_mapping",
+        entry_point: "fs_tone_mapping",
+        inputs: if settings.bloom {
+            &["hdr", "bloom"]
+        } else {
+            &["hdr"]
+        },
+        output: if fxaa { "ldr" } else { SURFACE },
+    });
+    if fxaa {
+        steps.push(Step {
+            name: "fxaa",
+            entry_point: "fs_fxaa",
+            inputs: &["ldr"],
+            output: SURFACE,
+        });
+    }
+    steps
+}
+
+/* Adds the attachments and passes of the chain to the graph, after the world pass drew the "hdr"
+ * attachment
+ */
+pub fn add_passes(
+    graph: &mut RenderGraph,
+    settings: &PostSettings,
+    output_format: wgpu::TextureFormat,
+) {
+    for (name, format, size) in [
+        ("bloom", HDR_FORMAT, AttachmentSize::Scaled(BLOOM_SCALE)),
+        (
+            "bloom_blur",
+            HDR_FORMAT,
+            AttachmentSize::Scaled(BLOOM_SCALE),
+        ),
+        ("ldr", output_format, AttachmentSize::Surface),
+    ] {
+        graph.add_attachment(AttachmentDesc {
+            name,
+            format,
+            size,
+            samples: 1,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+    }
+    for step in steps(settings) {
+        graph.add_node(RenderNode {
+            name: step.name,
+            pipeline: PipelineType::WORLD,
+            color: Some(step.output),
+            resolve: None,
+            depth: None,
+            reads: step.inputs,
+            draw: draw_post_pass,
+        });
+    }
+}
+
+// Pipelines of the post-process chain, owned by the world pipeline like the shadow map
+pub struct PostProcess {
+    // (node name, pipeline, bind group)
+    steps: Vec<(&'static str, wgpu::RenderPipeline, wgpu::BindGroup)>,
+    inputs: Vec<&'static [&'static str]>,
+    bind_group_layout: wgpu::BindGroupLayout,
+    uniform_buffer: wgpu::Buffer,
+}
+
+impl PostProcess {
+    pub fn new(
+        device: &wgpu::Device,
+        shader_source: &str,
+        settings: &PostSettings,
+        output_format: wgpu::TextureFormat,
+        graph: &RenderGraph,
+    ) -> Self {
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("post_settings"),
+            contents: bytemuck::cast_slice(&[PostUniforms::new(settings, output_format)]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("post_bind_group"),
+            entries: &[
+                texture_entry(0),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                texture_entry(2),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("post_pipeline_layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("post_shader"),
+            source: wgpu::ShaderSource::Wgsl(shader_source.into()),
+        });
+
+        let steps = steps(settings);
+        let pipelines = steps
+            .iter()
+            .map(|step| {
+                let format = if step.output == SURFACE || step.output == "ldr" {
+                    output_format
+                } else {
+                    HDR_FORMAT
+                };
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some(step.name),
+                    layout: Some(&pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: "vs_main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader,
+                        entry_point: step.entry_point,
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format,
+                            blend: None,
+                            write_mask: wgpu::ColorWrites::ALL,
+                        })],
+                    }),
+                    primitive: wgpu::PrimitiveState::default(),
+                    depth_stencil: None,
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut post_process = Self {
+            steps: vec![],
+            inputs: steps.iter().map(|step| step.inputs).collect(),
+            bind_group_layout,
+            uniform_buffer,
+        };
+        let bind_groups = post_process.create_bind_groups(device, graph);
+        post_process.steps = steps
+            .iter()
+            .zip(pipelines)
+            .zip(bind_groups)
+            .map(|((step, pipeline), bind_group)| (step.name, pipeline, bind_group))
+            .collect();
+        post_process
+    }
+    fn create_bind_groups(
+        &self,
+        device: &wgpu::Device,
+        graph: &RenderGraph,
+    ) -> Vec<wgpu::BindGroup> {
+        self.inputs
+            .iter()
+            .map(|inputs| {
+                let attachment = |name| graph.attachment(name).expect("Missing post-process input");
+                // Steps without bloom bind their input twice
+                let input = attachment(inputs[0]);
+                let bloom = attachment(inputs[inputs.len() - 1]);
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("post_bind_group"),
+                    layout: &self.bind_group_layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: wgpu::BindingResource::TextureView(&input.view),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: wgpu::BindingResource::Sampler(&input.sampler),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: wgpu::BindingResource::TextureView(&bloom.view),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 3,
+                            resource: self.uniform_buffer.as_entire_binding(),
+                        },
+                    ],
+                })
+            })
+            .collect()
+    }
+    // The attachments are recreated when the window is resized
+    pub fn resize(&mut self, device: &wgpu::Device, graph: &RenderGraph) {
+        let bind_groups = self.create_bind_groups(device, graph);
+        for (step, bind_group) in self.steps.iter_mut().zip(bind_groups) {
+            step.2 = bind_group;
+        }
+    }
+}
+
+// Node of the render graph drawing a step of the chain, found by the name of the node
+pub fn draw_post_pass<'a>(
+    node: &'a RenderNode,
+    pipeline: &'a dyn PipelineTrait,
+    _frame: &'a Frame<'a>,
+    rpass: &mut wgpu::RenderPass<'a>,
+) {
+    let Some(post_process) = pipeline.post_process() else {
+        return;
+    };
+    let Some((_, pipeline, bind_group)) = post_process.steps.iter().find(|s| s.0 == node.name)
+    else {
+        return;
+    };
+    rpass.set_pipeline(pipeline);
+    rpass.set_bind_group(0, bind_group, &[]);
+    rpass.draw(0..3, 0..1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_only_add_the_enabled_steps() {
+        let names = |settings: &PostSettings| {
+            steps(settings)
+                .iter()
+                .map(|step| (step.name, step.output))
+                .collect::<Vec<_>>()
+        };
+        let settings = PostSettings::default();
+        assert_eq!(
+            names(&settings),
+            [
+                ("bloom_extract", "bloom"),
+                ("bloom_blur_horizontal", "bloom_blur"),
+                ("bloom_blur_vertical", "bloom"),
+                ("tone_mapping", "ldr"),
+                ("fxaa", SURFACE),
+            ]
+        );
+        // Without FXAA the tone mapping is the last step
+        let settings = PostSettings {
+            anti_aliasing: AntiAliasing::Msaa(4),
+            bloom: false,
+            ..settings
+        };
+        assert_eq!(names(&settings), [("tone_mapping", SURFACE)]);
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+
+use wgpu::util::DrawIndexedIndirect;
+
+use crate::material::Texture;
+use crate::pipeline::{PipelineTrait, PipelineType};
+use crate::state::State;
+
+// Attachment of the frame being drawn, the window's surface or the offscreen target
+pub const SURFACE: &str = "surface";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttachmentSize {
+    // Follows the size of the window
+    Surface,
+    // The size of the window divided by it, e.g. for blurring
+    Scaled(u32),
+    Fixed(u32, u32),
+}
+
+// Texture owned by the graph, recreated when the window is resized
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AttachmentDesc {
+    pub name: &'static str,
+    pub format: wgpu::TextureFormat,
+    pub size: AttachmentSize,
+    // Multisampled attachments are resolved into a single sampled one, see RenderNode::resolve
+    pub samples: u32,
+    // On top of RENDER_ATTACHMENT, e.g. TEXTURE_BINDING to be sampled by a later pass
+    pub usage: wgpu::TextureUsages,
+}
+
+// What the passes of a frame draw
+pub struct Frame<'a> {
+    pub state: &'a State,
+    pub opaque_draws: &'a [DrawIndexedIndirect],
+    pub translucent_draws: &'a [DrawIndexedIndirect],
+    pub shadow_draws: &'a [DrawIndexedIndirect],
+    // Offsets of the batches above, returned by MeshArena::write_draws
+    pub draw_offsets: &'a [u64],
+    // Color attachments are cleared with it by the first pass drawing to them
+    pub clear_color: wgpu::Color,
+}
+
+pub type DrawPass =
+    for<'a> fn(&'a RenderNode, &'a dyn PipelineTrait, &'a Frame<'a>, &mut wgpu::RenderPass<'a>);
+
+/* A render pass of the graph, the passes run in the order they are added.
+ * Attachments are named, either one of the graph's or one imported for the frame (see execute)
+ */
+pub struct RenderNode {
+    pub name: &'static str,
+    // Skipped when the State has no such pipeline
+    pub pipeline: PipelineType,
+    pub color: Option<&'static str>,
+    // Where the multisampled color attachment is resolved to
+    pub resolve: Option<&'static str>,
+    pub depth: Option<&'static str>,
+    // Attachments sampled by the pass, written by the passes before it
+    pub reads: &'static [&'static str],
+    pub draw: DrawPass,
+}
+
+impl RenderNode {
+    fn writes(&self, name: &str) -> bool {
+        [self.color, self.resolve, self.depth].contains(&Some(name))
+    }
+    fn uses(&self, name: &str) -> bool {
+        self.writes(name) || self.reads.contains(&name)
+    }
+}
+
+// How a pass loads and stores an attachment, from the passes using it before and after
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct AttachmentOps {
+    clear: bool,
+    store: bool,
+}
+
+/* Passes of a frame and the attachments they draw to.
+ * Attachments only live from the first pass using them to the last one, so the ones that are
+ * never used at the same time share a texture
+ */
+pub struct RenderGraph {
+    pub attachments: Vec<AttachmentDesc>,
+    pub nodes: Vec<RenderNode>,
+    // Index in textures of every attachment
+    aliases: HashMap<&'static str, usize>,
+    textures: Vec<Texture>,
+    // (node, attachment)
+    ops: HashMap<(usize, &'static str), AttachmentOps>,
+    surface_size: (u32, u32),
+}
+
+impl RenderGraph {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            attachments: vec![],
+            nodes: vec![],
+            aliases: HashMap::new(),
+            textures: vec![],
+            ops: HashMap::new(),
+            surface_size: (width, height),
+        }
+    }
+    pub fn add_attachment(&mut self, desc: AttachmentDesc) {
+        self.attachments.push(desc);
+    }
+    pub fn add_node(&mut self, node: RenderNode) {
+        self.nodes.push(node);
+    }
+    // Call once every attachment and node is added
+    pub fn build(&mut self, device: &wgpu::Device) {
+        for (i, node) in self.nodes.iter().enumerate() {
+            for name in node.reads {
+                let drawn = self.nodes[..i].iter().any(|n| n.writes(name));
+                if !drawn {
+                    log::warn!("The pass {} reads {name} before it's drawn", node.name);
+                }
+            }
+        }
+        let lifetimes = self.lifetimes();
+        self.ops = attachment_ops(&self.nodes);
+        self.aliases = alias_attachments(&self.attachments, &lifetimes);
+        self.create_textures(device);
+    }
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        self.surface_size = (width, height);
+        self.create_textures(device);
+    }
+    // Texture of an attachment of the graph, shared with the attachments aliasing it
+    pub fn attachment(&self, name: &str) -> Option<&Texture> {
+        self.aliases.get(name).map(|i| &self.textures[*i])
+    }
+    // First and last node using every attachment of the graph
+    fn lifetimes(&self) -> HashMap<&'static str, (usize, usize)> {
+        let mut lifetimes = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let names = [node.color, node.resolve, node.depth]
+                .into_iter()
+                .flatten()
+                .chain(node.reads.iter().copied());
+            for name in names {
+                lifetimes
+                    .entry(name)
+                    .and_modify(|l: &mut (usize, usize)| l.1 = i)
+                    .or_insert((i, i));
+            }
+        }
+        lifetimes
+    }
+    fn create_textures(&mut self, device: &wgpu::Device) {
+        let mut textures = HashMap::new();
+        for desc in self.attachments.iter() {
+            let Some(index) = self.aliases.get(desc.name) else {
+                continue;
+            };
+            let (width, height) = match desc.size {
+                AttachmentSize::Surface => self.surface_size,
+                AttachmentSize::Scaled(divisor) => (
+                    (self.surface_size.0 / divisor).max(1),
+                    (self.surface_size.1 / divisor).max(1),
+                ),
+                AttachmentSize::Fixed(width, height) => (width, height),
+            };
+            textures.entry(*index).or_insert_with(|| {
+                Texture::create_attachment(
+                    device,
+                    desc.name,
+                    desc.format,
+                    width,
+                    height,
+                    desc.samples,
+                    wgpu::TextureUsages::RENDER_ATTACHMENT | desc.usage,
+                )
+            });
+        }
+        let mut textures = textures.into_iter().collect::<Vec<_>>();
+        textures.sort_by_key(|(i, _)| *i);
+        self.textures = textures.into_iter().map(|(_, t)| t).collect();
+    }
+    /* Records every pass.
+     * imports: attachments owned outside of the graph for this frame, at least SURFACE
+     */
+    pub fn execute<'a>(
+        &'a self,
+        encoder: &mut wgpu::CommandEncoder,
+        imports: &[(&str, &'a wgpu::TextureView)],
+        frame: &'a Frame<'a>,
+    ) {
+        let view = |name: &str| {
+            imports
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, view)| *view)
+                .or_else(|| self.attachment(name).map(|t| &t.view))
+                .unwrap_or_else(|| panic!("Unknown attachment {name}"))
+        };
+        let pipelines = &frame.state.pipelines;
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            let Some(pipeline) = pipelines.get(&node.pipeline) else {
+                continue;
+            };
+            let ops = |name: &'static str| self.ops[&(i, name)];
+            let store = |ops: AttachmentOps| {
+                if ops.store {
+                    wgpu::StoreOp::Store
+                } else {
+                    wgpu::StoreOp::Discard
+                }
+            };
+            let color_attachment = node.color.map(|name| {
+                let ops = ops(name);
+                wgpu::RenderPassColorAttachment {
+                    view: view(name),
+                    resolve_target: node.resolve.map(view),
+                    ops: wgpu::Operations {
+                        load: if ops.clear {
+                            wgpu::LoadOp::Clear(frame.clear_color)
+                        } else {
+                            wgpu::LoadOp::Load
+                        },
+                        store: store(ops),
+                    },
+                }
+            });
+            let depth_attachment = node.depth.map(|name| {
+                let ops = ops(name);
+                wgpu::RenderPassDepthStencilAttachment {
+                    view: view(name),
+                    depth_ops: Some(wgpu::Operations {
+                        load: if ops.clear {
+                            wgpu::LoadOp::Clear(1.0)
+                        } else {
+                            wgpu::LoadOp::Load
+                        },
+                        store: store(ops),
+                    }),
+                    stencil_ops: None,
+                }
+            });
+
+            // Depth only passes have no color target at all
+            let color_attachments = color_attachment.into_iter().map(Some).collect::<Vec<_>>();
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some(node.name),
+                color_attachments: &color_attachments,
+                depth_stencil_attachment: depth_attachment,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            (node.draw)(node, pipeline.as_ref(), frame, &mut rpass);
+        }
+    }
+}
+
+/* The first pass drawing to an attachment clears it, the next ones load it.
+ * It's stored when a later pass uses it, or when it's the surface, which is presented
+ */
+fn attachment_ops(nodes: &[RenderNode]) -> HashMap<(usize, &'static str), AttachmentOps> {
+    let mut ops = HashMap::new();
+    for (i, node) in nodes.iter().enumerate() {
+        for name in node.color.iter().chain(node.depth.iter()) {
+            let used_before = nodes[..i].iter().any(|n| n.writes(name));
+            let used_after = nodes[i + 1..].iter().any(|n| n.uses(name));
+            ops.insert(
+                (i, *name),
+                AttachmentOps {
+                    clear: !used_before,
+                    store: used_after || *name == SURFACE,
+                },
+            );
+        }
+    }
+    ops
+}
+
+/* Index of the texture of every attachment used by a pass.
+ * An attachment reuses the texture of another one with the same description when their
+ * lifetimes don't overlap
+ */
+fn alias_attachments(
+    attachments: &[AttachmentDesc],
+    lifetimes: &HashMap<&'static str, (usize, usize)>,
+) -> HashMap<&'static str, usize> {
+    let mut used = attachments
+        .iter()
+        .filter_map(|desc| Some((desc, *lifetimes.get(desc.name)?)))
+        .collect::<Vec<_>>();
+    used.sort_by_key(|(_, lifetime)| lifetime.0);
+
+    // Description of every texture and the last node using it
+    let mut textures: Vec<(&AttachmentDesc, usize)> = vec![];
+    let mut aliases = HashMap::new();
+    for (desc, (first, last)) in used {
+        let free = textures.iter().position(|(texture, texture_last)| {
+            *texture_last < first
+                && (texture.format, texture.size, texture.samples, texture.usage)
+                    == (desc.format, desc.size, desc.samples, desc.usage)
+        });
+        let index = match free {
+            Some(index) => {
+                textures[index].1 = last;
+                index
+            }
+            None => {
+                textures.push((desc, last));
+                textures.len() - 1
+            }
+        };
+        aliases.insert(desc.name, index);
+    }
+    aliases
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_draw<'a>(
+        _: &'a RenderNode,
+        _: &'a dyn PipelineTrait,
+        _: &'a Frame<'a>,
+        _: &mut wgpu::RenderPass<'a>,
+    ) {
+    }
+
+    fn node(color: Option<&'static str>, depth: Option<&'static str>) -> RenderNode {
+        RenderNode {
+            name: "test",
+            pipeline: PipelineType::WORLD,
+            color,
+            resolve: None,
+            depth,
+            reads: &[],
+            draw: no_draw,
+        }
+    }
+
+    #[test]
+    fn should_share_textures_between_attachments_used_at_different_times() {
+        let depth = |name| AttachmentDesc {
+            name,
+            format: Texture::DEPTH_FORMAT,
+            size: AttachmentSize::Surface,
+            samples: 1,
+            usage: wgpu::TextureUsages::empty(),
+        };
+        let attachments = [depth("depth"), depth("ui_depth"), depth("unused")];
+        let nodes = [
+            node(Some(SURFACE), Some("depth")),
+            node(Some(SURFACE), Some("depth")),
+            node(Some(SURFACE), Some("ui_depth")),
+        ];
+        let lifetimes = HashMap::from([("depth", (0, 1)), ("ui_depth", (2, 2))]);
+
+        let aliases = alias_attachments(&attachments, &lifetimes);
+        assert_eq!(aliases.len(), 2);
+        assert_eq!(aliases["depth"], aliases["ui_depth"]);
+
+        let ops = attachment_ops(&nodes);
+        let (clear, load) = (true, false);
+        assert_eq!(ops[&(0, "depth")].clear, clear);
+        assert_eq!(ops[&(1, "depth")].clear, load);
+        // Nothing uses the depth after the last pass, but the surface is presented
+        assert!(!ops[&(2, "ui_depth")].store);
+        assert!(ops[&(2, SURFACE)].store);
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use image::{imageops, RgbaImage};
+
+use crate::blocks::atlas;
+use crate::blocks::block_shape::{self, ShapeBox};
+use crate::blocks::block_type::BlockType;
+
+// Folder where the resource packs are looked for, each is a directory or a zip file
+pub const PACKS_DIR: &str = "resourcepacks";
+
+// Source of the built-in shaders, read from the disk in debug builds so that they can be edited
+// without rebuilding
+const SHADERS_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/shaders");
+// Used for the assets that no pack overrides
+pub const BUILTIN_SHADERS: [(&str, &str); 6] = [
+    ("shader.wgsl", include_str!("shaders/shader.wgsl")),
+    ("sky.wgsl", include_str!("shaders/sky.wgsl")),
+    ("shadow.wgsl", include_str!("shaders/shadow.wgsl")),
+    ("ui_shader.wgsl", include_str!("shaders/ui_shader.wgsl")),
+    ("post.wgsl", include_str!("shaders/post.wgsl")),
+    ("clouds.wgsl", include_str!("shaders/clouds.wgsl")),
+];
+// Textures drawn over the blocks, they aren't part of the block atlas
+pub const BUILTIN_OVERLAYS: [(&str, &[u8]); 1] = [(
+    "crack",
+    include_bytes!("../assets/textures/overlays/crack.png"),
+)];
+
+// Archive of a zip pack and the modification time of its file
+type OpenArchive = (SystemTime, zip::ZipArchive<fs::File>);
+
+pub enum PackSource {
+    Directory(PathBuf),
+    // The archive is opened on the first read and kept
+    Zip(PathBuf, Mutex<Option<OpenArchive>>),
+}
+
+/* Assets that replace the built-in ones, with the same layout in a directory or a zip file:
+ * textures/blocks/<texture>.png (same names as in assets/textures/blocks)
+ * textures/overlays/<texture>.png (same names as in assets/textures/overlays)
+ * shaders/<shader>.wgsl
+ * models/<block>.txt (see parse_block_model)
+ */
+pub struct ResourcePack {
+    pub name: String,
+    pub source: PackSource,
+}
+
+impl ResourcePack {
+    pub fn open(path: &Path) -> Self {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let source = if path.extension().is_some_and(|e| e == "zip") {
+            PackSource::Zip(path.to_path_buf(), Mutex::new(None))
+        } else {
+            PackSource::Directory(path.to_path_buf())
+        };
+        Self { name, source }
+    }
+    // Packs of the directory, sorted by name
+    pub fn find_all(dir: &str) -> Vec<ResourcePack> {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return vec![];
+        };
+        let mut paths = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_dir() || p.extension().is_some_and(|e| e == "zip"))
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths.iter().map(|p| Self::open(p)).collect()
+    }
+    // path: relative to the root of the pack, e.g. "shaders/shader.wgsl"
+    pub fn read(&self, path: &str) -> Option<Vec<u8>> {
+        match &self.source {
+            PackSource::Directory(dir) => fs::read(dir.join(path)).ok(),
+            PackSource::Zip(file, archive) => {
+                let modified = fs::metadata(file).and_then(|m| m.modified()).ok()?;
+                let mut archive = archive.lock().unwrap();
+                // Opened again when the file changes, its shaders are reloaded
+                if !matches!(&*archive, Some((m, _)) if *m == modified) {
+                    let opened = zip::ZipArchive::new(fs::File::open(file).ok()?).ok()?;
+                    *archive = Some((modified, opened));
+                }
+                let (_, archive) = archive.as_mut()?;
+                let mut entry = archive.by_name(path).ok()?;
+                let mut bytes = vec![];
+                entry.read_to_end(&mut bytes).ok()?;
+                Some(bytes)
+            }
+        }
+    }
+}
+
+// Enabled packs, the first ones override the ones after them
+#[derive(Default)]
+pub struct ResourcePacks {
+    pub packs: Vec<ResourcePack>,
+}
+
+impl ResourcePacks {
+    pub fn read(&self, path: &str) -> Option<Vec<u8>> {
+        self.packs.iter().find_map(|pack| pack.read(path))
+    }
+    // name: file name of the shader, e.g. "shader.wgsl"
+    pub fn shader(&self, name: &str) -> String {
+        if let Some(bytes) = self.read(&format!("shaders/{name}")) {
+            match String::from_utf8(bytes) {
+                Ok(source) => return source,
+                Err(e) => log::warn!("Ignoring the shader {name} of the resource packs: {e}"),
+            }
+        }
+        if cfg!(debug_assertions) {
+            if let Ok(source) = fs::read_to_string(Path::new(SHADERS_DIR).join(name)) {
+                return source;
+            }
+        }
+        BUILTIN_SHADERS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .unwrap_or_else(|| panic!("Unknown shader {name}"))
+            .1
+            .to_string()
+    }
+    // Files that the shaders can be read from, a zip pack is a single file
+    pub fn shader_files(&self) -> Vec<PathBuf> {
+        let mut files = self
+            .packs
+            .iter()
+            .flat_map(|pack| match &pack.source {
+                PackSource::Directory(dir) => BUILTIN_SHADERS
+                    .iter()
+                    .map(|(name, _)| dir.join("shaders").join(name))
+                    .collect(),
+                PackSource::Zip(file, _) => vec![file.clone()],
+            })
+            .collect::<Vec<_>>();
+        if cfg!(debug_assertions) {
+            files.extend(
+                BUILTIN_SHADERS
+                    .iter()
+                    .map(|(name, _)| Path::new(SHADERS_DIR).join(name)),
+            );
+        }
+        files
+    }
+    // The built-in atlas, with the tiles of the textures overridden by the packs replaced
+    pub fn block_atlas(&self) -> RgbaImage {
+        let mut atlas = image::load_from_memory(atlas::ATLAS_PNG)
+            .expect("Invalid built-in atlas")
+            .to_rgba8();
+        let tile_size = atlas.width() / atlas::TILES_PER_ROW;
+
+        for (name, first_tile, frames) in atlas::TEXTURES {
+            let Some(bytes) = self.read(&format!("textures/blocks/{name}.png")) else {
+                continue;
+            };
+            let image = match image::load_from_memory(&bytes) {
+                Ok(image) => image.to_rgba8(),
+                Err(e) => {
+                    log::warn!("Ignoring the texture {name} of the resource packs: {e}");
+                    continue;
+                }
+            };
+            // The tiles are fixed at build time, missing frames repeat the last one
+            let size = image.width();
+            let image_frames = (image.height() / size).max(1);
+            for frame in 0..frames {
+                let y = frame.min(image_frames - 1) * size;
+                let tile = imageops::crop_imm(&image, 0, y, size, size);
+                let tile = imageops::resize(
+                    &tile.to_image(),
+                    tile_size,
+                    tile_size,
+                    imageops::FilterType::Nearest,
+                );
+                let index = first_tile + frame;
+                imageops::replace(
+                    &mut atlas,
+                    &tile,
+                    ((index % atlas::TILES_PER_ROW) * tile_size) as i64,
+                    ((index / atlas::TILES_PER_ROW) * tile_size) as i64,
+                );
+            }
+        }
+        atlas
+    }
+    // name: name of the overlay texture, e.g. "crack"
+    pub fn overlay(&self, name: &str) -> RgbaImage {
+        if let Some(bytes) = self.read(&format!("textures/overlays/{name}.png")) {
+            match image::load_from_memory(&bytes) {
+                Ok(image) => return image.to_rgba8(),
+                Err(e) => log::warn!("Ignoring the overlay {name} of the resource packs: {e}"),
+            }
+        }
+        let (_, bytes) = BUILTIN_OVERLAYS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .unwrap_or_else(|| panic!("Unknown overlay {name}"));
+        image::load_from_memory(bytes)
+            .expect("Invalid built-in overlay")
+            .to_rgba8()
+    }
+    // Boxes of the blocks that have a model in the packs, by block id
+    pub fn block_models(&self) -> HashMap<u32, Vec<ShapeBox>> {
+        BlockType::all()
+            .filter_map(|block_type| {
+                let name = block_type.name();
+                let bytes = self.read(&format!("models/{name}.txt"))?;
+                match block_shape::parse_block_model(&String::from_utf8_lossy(&bytes)) {
+                    Ok(boxes) => Some((block_type.to_id(), boxes)),
+                    Err(e) => {
+                        log::warn!("Ignoring the model {name} of the resource packs: {e}");
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn should_override_the_textures_of_the_builtin_atlas() {
+        let dir = std::env::temp_dir().join(format!("resource_pack_{}", std::process::id()));
+        fs::create_dir_all(dir.join("textures/blocks")).unwrap();
+        RgbaImage::from_pixel(16, 16, Rgba([1, 2, 3, 255]))
+            .save(dir.join("textures/blocks/stone.png"))
+            .unwrap();
+        let resources = ResourcePacks {
+            packs: vec![ResourcePack::open(&dir)],
+        };
+
+        let atlas = resources.block_atlas();
+        let tile_size = atlas.width() / atlas::TILES_PER_ROW;
+        let tile_position = |tile: u32| {
+            (
+                (tile % atlas::TILES_PER_ROW) * tile_size,
+                (tile / atlas::TILES_PER_ROW) * tile_size,
+            )
+        };
+        let (x, y) = tile_position(atlas::STONE.side);
+        assert_eq!(*atlas.get_pixel(x + tile_size - 1, y), Rgba([1, 2, 3, 255]));
+        let builtin = ResourcePacks::default().block_atlas();
+        let (x, y) = tile_position(atlas::DIRT.side);
+        assert_eq!(atlas.get_pixel(x, y), builtin.get_pixel(x, y));
+        // Shaders that aren't in the pack are the built-in ones
+        assert_eq!(resources.shader("sky.wgsl"), BUILTIN_SHADERS[1].1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_read_a_zip_pack_again_when_it_changes() {
+        let path = std::env::temp_dir().join(format!("resource_pack_{}.zip", std::process::id()));
+        let write_zip = |source: &str| {
+            let mut zip = zip::ZipWriter::new(fs::File::create(&path).unwrap());
+            zip.start_file("shaders/sky.wgsl", Default::default())
+                .unwrap();
+            std::io::Write::write_all(&mut zip, source.as_bytes()).unwrap();
+            zip.finish().unwrap();
+        };
+        write_zip("// first");
+        let pack = ResourcePack::open(&path);
+        assert_eq!(pack.read("shaders/sky.wgsl"), Some(b"// first".to_vec()));
+        assert_eq!(pack.read("shaders/shader.wgsl"), None);
+
+        write_zip("// second");
+        // The modification time can have a coarse resolution
+        let later = SystemTime::now() + std::time::Duration::from_secs(2);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(pack.read("shaders/sky.wgsl"), Some(b"// second".to_vec()));
+
+        fs::remove_file(path).unwrap();
+    }
+}
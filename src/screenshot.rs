@@ -0,0 +1,116 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use image::RgbaImage;
+
+// Folder where the screenshots taken in game are saved
+pub const SCREENSHOTS_DIR: &str = "screenshots";
+
+// File for a new screenshot, named after the time it was taken
+pub fn new_screenshot_path() -> PathBuf {
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    Path::new(SCREENSHOTS_DIR).join(format!("{}.png", time.as_millis()))
+}
+
+/* Reads the texture back from the GPU.
+ * It needs the COPY_SRC usage and an 8 bit RGBA or BGRA format, like the surface and the offscreen target
+ */
+pub fn capture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+) -> Result<RgbaImage, String> {
+    let format = texture.format();
+    let is_bgra = match format {
+        wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Rgba8UnormSrgb => false,
+        wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb => true,
+        _ => return Err(format!("Can't capture a texture of format {format:?}")),
+    };
+    if !texture.usage().contains(wgpu::TextureUsages::COPY_SRC) {
+        return Err(String::from("The texture can't be copied"));
+    }
+
+    let (width, height) = (texture.width(), texture.height());
+    // Rows of a texture copy are aligned
+    let padded_row = (4 * width).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("screenshot"),
+        size: (padded_row * height) as u64,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("screenshot"),
+    });
+    encoder.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_row),
+                rows_per_image: Some(height),
+            },
+        },
+        texture.size(),
+    );
+    queue.submit(Some(encoder.finish()));
+
+    let (sender, receiver) = mpsc::channel();
+    let slice = buffer.slice(..);
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        sender.send(result).unwrap();
+    });
+    device.poll(wgpu::Maintain::Wait);
+    receiver
+        .recv()
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let image = to_image(
+        &slice.get_mapped_range(),
+        width,
+        height,
+        padded_row,
+        is_bgra,
+    );
+    buffer.unmap();
+    Ok(image)
+}
+
+// data: rows of padded_row bytes
+fn to_image(data: &[u8], width: u32, height: u32, padded_row: u32, is_bgra: bool) -> RgbaImage {
+    let mut pixels = data
+        .chunks(padded_row as usize)
+        .take(height as usize)
+        .flat_map(|row| &row[..4 * width as usize])
+        .copied()
+        .collect::<Vec<_>>();
+    if is_bgra {
+        pixels.chunks_mut(4).for_each(|pixel| pixel.swap(0, 2));
+    }
+    RgbaImage::from_raw(width, height, pixels).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_remove_the_row_padding() {
+        // 2x2 BGRA, rows padded to 12 bytes
+        let data = [
+            [3, 2, 1, 255, 6, 5, 4, 255, 0, 0, 0, 0],
+            [9, 8, 7, 255, 12, 11, 10, 255, 0, 0, 0, 0],
+        ]
+        .concat();
+        let image = to_image(&data, 2, 2, 12, true);
+        assert_eq!(
+            image.into_raw(),
+            [1, 2, 3, 255, 4, 5, 6, 255, 7, 8, 9, 255, 10, 11, 12, 255]
+        );
+    }
+}
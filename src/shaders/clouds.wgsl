@@ -0,0 +1,73 @@
+struct VertexInput {
+    // Relative to the corner of the box
+    @location(0) position: vec3<f32>,
+    // Corner of the box in the cloud layer
+    @location(1) cell: vec2<f32>,
+    @location(2) shade: f32,
+}
+
+struct VertexOutput {
+    // Both passes must compute the same depth for the Equal test
+    @builtin(position) @invariant clip_position: vec4<f32>,
+    @location(0) shade: f32,
+    // How much the fragment is hidden by the fog, 0..1
+    @location(1) fog: f32,
+}
+
+@group(0) @binding(0)
+var<uniform> projection: mat4x4<f32>;
+@group(0) @binding(1)
+var<uniform> view: mat4x4<f32>;
+struct Environment {
+    sun_direction: vec4<f32>,
+    sky_color: vec4<f32>,
+    sky_light: f32,
+    sun_intensity: f32,
+    fog_start: f32,
+    fog_end: f32,
+    // Seconds since the game started
+    time: f32,
+}
+@group(0) @binding(2)
+var<uniform> environment: Environment;
+
+struct Clouds {
+    altitude: f32,
+    // Blocks per second along x
+    speed: f32,
+    // Width of the cloud layer before it repeats
+    period: f32,
+}
+@group(1) @binding(0)
+var<uniform> clouds: Clouds;
+
+const opacity = 0.8;
+// Light of the clouds at night
+const min_light = 0.15;
+
+@vertex
+fn vs_main(in: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    // The view matrix only rotates and translates, so its inverse rotation is the transpose
+    let rotation = mat3x3<f32>(view[0].xyz, view[1].xyz, view[2].xyz);
+    let eye = -(transpose(rotation) * view[3].xyz);
+
+    // The layer repeats, the copy of the box nearest to the camera is drawn
+    var corner = in.cell + vec2<f32>(environment.time * clouds.speed, 0.0);
+    corner -= clouds.period * floor((corner - eye.xz) / clouds.period + 0.5);
+    let position = vec3<f32>(corner.x, clouds.altitude, corner.y) + in.position;
+
+    let view_position = view * vec4<f32>(position, 1.0);
+    out.clip_position = projection * view_position;
+    out.shade = in.shade;
+    out.fog = smoothstep(environment.fog_start, environment.fog_end, length(view_position.xyz));
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let light = max(environment.sky_light, min_light);
+    let color = mix(vec3<f32>(in.shade * light), environment.sky_color.rgb, in.fog);
+    // Fades out towards the sky behind it
+    return vec4<f32>(color, opacity * (1.0 - in.fog));
+}
@@ -0,0 +1,141 @@
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// Fixed when the pipelines are built, see PostUniforms
+struct Settings {
+    tone_mapping: u32,
+    bloom: u32,
+    // The output isn't an sRGB texture, so the gamma is applied by the shader
+    encode_gamma: u32,
+}
+
+// Output of the previous step
+@group(0) @binding(0)
+var input: texture_2d<f32>;
+@group(0) @binding(1)
+var input_sampler: sampler;
+// Blurred bright parts of the frame, only read by the tone mapping
+@group(0) @binding(2)
+var bloom: texture_2d<f32>;
+@group(0) @binding(3)
+var<uniform> settings: Settings;
+
+// Only what is brighter than white glows
+const bloom_threshold = 1.0;
+const bloom_intensity = 0.4;
+const exposure = 1.0;
+const fxaa_reduce_min = 0.0078125;
+const fxaa_reduce_mul = 0.125;
+// In texels
+const fxaa_span_max = 8.0;
+
+// A single triangle covering the whole screen
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    var out: VertexOutput;
+    let position = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    out.clip_position = vec4<f32>(position * 2.0 - 1.0, 0.0, 1.0);
+    out.uv = vec2<f32>(position.x, 1.0 - position.y);
+    return out;
+}
+
+fn sample_input(uv: vec2<f32>) -> vec3<f32> {
+    return textureSampleLevel(input, input_sampler, uv, 0.0).rgb;
+}
+
+@fragment
+fn fs_bloom_extract(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = sample_input(in.uv);
+    let brightness = max(color.r, max(color.g, color.b));
+    let glow = max(brightness - bloom_threshold, 0.0) / max(brightness, 0.0001);
+    return vec4<f32>(color * glow, 1.0);
+}
+
+// Separable gaussian blur over 9 texels
+fn blur(uv: vec2<f32>, direction: vec2<f32>) -> vec4<f32> {
+    var weights = array<f32, 5>(0.227027, 0.1945946, 0.1216216, 0.054054, 0.016216);
+    let texel = direction / vec2<f32>(textureDimensions(input));
+    var color = sample_input(uv) * weights[0];
+    for (var i = 1; i < 5; i++) {
+        let offset = texel * f32(i);
+        color += (sample_input(uv + offset) + sample_input(uv - offset)) * weights[i];
+    }
+    return vec4<f32>(color, 1.0);
+}
+
+@fragment
+fn fs_blur_horizontal(in: VertexOutput) -> @location(0) vec4<f32> {
+    return blur(in.uv, vec2<f32>(1.0, 0.0));
+}
+
+@fragment
+fn fs_blur_vertical(in: VertexOutput) -> @location(0) vec4<f32> {
+    return blur(in.uv, vec2<f32>(0.0, 1.0));
+}
+
+// Fitted ACES filmic curve, maps the HDR colors to 0..1
+fn aces(x: vec3<f32>) -> vec3<f32> {
+    let color = (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14);
+    return clamp(color, vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+@fragment
+fn fs_tone_mapping(in: VertexOutput) -> @location(0) vec4<f32> {
+    var color = sample_input(in.uv);
+    if settings.bloom != 0u {
+        color += textureSampleLevel(bloom, input_sampler, in.uv, 0.0).rgb * bloom_intensity;
+    }
+    if settings.tone_mapping != 0u {
+        color = aces(color * exposure);
+    } else {
+        color = clamp(color, vec3<f32>(0.0), vec3<f32>(1.0));
+    }
+    // The curves above work on linear colors
+    if settings.encode_gamma != 0u {
+        color = pow(color, vec3<f32>(1.0 / 2.2));
+    }
+    return vec4<f32>(color, 1.0);
+}
+
+// FXAA works on perceptual values, the input is linear unless the gamma was encoded by the shader
+fn luma(color: vec3<f32>) -> f32 {
+    let luminance = dot(color, vec3<f32>(0.299, 0.587, 0.114));
+    if settings.encode_gamma != 0u {
+        return luminance;
+    }
+    return sqrt(luminance);
+}
+
+// Blurs the edges along their direction, found from the contrast with the 4 diagonal neighbours
+@fragment
+fn fs_fxaa(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(input));
+    let luma_nw = luma(sample_input(in.uv + vec2<f32>(-1.0, -1.0) * texel));
+    let luma_ne = luma(sample_input(in.uv + vec2<f32>(1.0, -1.0) * texel));
+    let luma_sw = luma(sample_input(in.uv + vec2<f32>(-1.0, 1.0) * texel));
+    let luma_se = luma(sample_input(in.uv + vec2<f32>(1.0, 1.0) * texel));
+    let luma_m = luma(sample_input(in.uv));
+    let luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    let luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    var direction = vec2<f32>(
+        (luma_sw + luma_se) - (luma_nw + luma_ne),
+        (luma_nw + luma_sw) - (luma_ne + luma_se),
+    );
+    let reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * fxaa_reduce_mul, fxaa_reduce_min);
+    let scale = 1.0 / (min(abs(direction.x), abs(direction.y)) + reduce);
+    direction = clamp(direction * scale, vec2<f32>(-fxaa_span_max), vec2<f32>(fxaa_span_max)) * texel;
+
+    let inner = 0.5 * (sample_input(in.uv + direction * (1.0 / 3.0 - 0.5))
+        + sample_input(in.uv + direction * (2.0 / 3.0 - 0.5)));
+    let outer = inner * 0.5 + 0.25 * (sample_input(in.uv - direction * 0.5)
+        + sample_input(in.uv + direction * 0.5));
+    // The wider blur went past the edge
+    let luma_outer = luma(outer);
+    if luma_outer < luma_min || luma_outer > luma_max {
+        return vec4<f32>(inner, 1.0);
+    }
+    return vec4<f32>(outer, 1.0);
+}
@@ -2,15 +2,15 @@
 
 struct VertexInput {
     @builtin(vertex_index) vertex_index: u32,
-    @location(0) position: vec3<f32>,
-    @location(1) normal: vec3<f32>,
-    @location(2) tex_coords: vec2<f32>,
-    @location(3) ao: f32,
-
+    // x (9 bits) | y (13 bits) | z (9 bits), in 1/16 of a block
+    @location(0) position: u32,
+    // normal (3 bits) | ao (2 bits) | u (5 bits) | v (5 bits) | texture layer (8 bits)
+    // | sky light (4 bits) | block light (4 bits)
+    @location(1) data: u32,
 }
 struct InstanceInput {
-    // @location(2) instance_transform: vec3<f32>,
-    @builtin(instance_index) instance_index: u32,
+    // Position of the chunk in the mesh arena
+    @location(2) chunk_position: vec2<i32>,
 };
  
 
@@ -20,7 +20,16 @@ struct VertexOutput {
     @location(1) normals: vec3<f32>,
     @location(2) chunk_position: vec2<i32>,
     @location(3) block_type: u32,
-    @location(4) ao: f32
+    @location(4) ao: f32,
+    // sky and block light, 0..1
+    @location(5) light: vec2<f32>,
+    // How much the fragment is hidden by the fog, 0..1
+    @location(6) fog: f32,
+    @location(7) shadow_position: vec4<f32>,
+    // Layer of the block texture array
+    @location(8) @interpolate(flat) texture_layer: u32,
+    // Light emitted by the block, 0..1
+    @location(9) @interpolate(flat) emission: f32,
 }
 
 
@@ -28,27 +37,86 @@ struct VertexOutput {
 var<uniform> projection: mat4x4<f32>;
 @group(0) @binding(1) 
 var<uniform> view: mat4x4<f32>;
-@group(2) @binding(0)
-var <uniform> current_chunk: vec2<i32>;
+// Changes with the time of the day
+struct Environment {
+    sun_direction: vec4<f32>,
+    sky_color: vec4<f32>,
+    sky_light: f32,
+    sun_intensity: f32,
+    fog_start: f32,
+    fog_end: f32,
+    // Seconds since the game started
+    time: f32,
+}
+@group(0) @binding(2)
+var<uniform> environment: Environment;
+// View-projection of the sun and the depth of the terrain seen from it
+@group(0) @binding(3)
+var<uniform> light_matrix: mat4x4<f32>;
+@group(0) @binding(4)
+var shadow_map: texture_depth_2d;
+@group(0) @binding(5)
+var shadow_sampler: sampler_comparison;
+// Indexed by the layer of the first frame, x: number of frames, y: seconds per frame, z: emission
+// 64 is atlas::MAX_TILES
+@group(0) @binding(6)
+var<uniform> animations: array<vec4<f32>, 64>;
+
+const position_scale = 16.0;
+const max_light = 15.0;
+const shadow_normal_offset = 0.05;
+const shadow_map_size = 2048.0;
 
 
 @vertex
 fn vs_main(in: VertexInput, instance_data: InstanceInput) -> VertexOutput {
     var out: VertexOutput;
-
+    // Indexed by FaceDirections::to_id
+    var normals = array<vec3<f32>, 6>(
+        vec3<f32>(0.0, 0.0, 1.0),
+        vec3<f32>(0.0, -1.0, 0.0),
+        vec3<f32>(0.0, 1.0, 0.0),
+        vec3<f32>(0.0, 0.0, -1.0),
+        vec3<f32>(-1.0, 0.0, 0.0),
+        vec3<f32>(1.0, 0.0, 0.0),
+    );
+
+    let current_chunk = instance_data.chunk_position;
     let chunk_offset = vec4<f32>(f32(current_chunk.x) * 16.0, 0.0, f32(current_chunk.y) * 16.0, 0.0);
 
-    out.clip_position = projection * view * (vec4<f32>(in.position.xyz, 1.0) + chunk_offset);
-    out.normals = in.normal;
-    out.tex_coords = in.tex_coords;
-    out.ao = in.ao;
+    let position = vec3<f32>(
+        f32(in.position & 0x1FFu),
+        f32((in.position >> 9u) & 0x1FFFu),
+        f32((in.position >> 22u) & 0x1FFu),
+    ) / position_scale - 0.5;
+
+    let normal_index = in.data & 0x7u;
+    let ao = (in.data >> 3u) & 0x3u;
+    let uv = vec2<f32>(f32((in.data >> 5u) & 0x1Fu), f32((in.data >> 10u) & 0x1Fu)) / position_scale;
+    let light = vec2<f32>(f32((in.data >> 23u) & 0xFu), f32((in.data >> 27u) & 0xFu)) / max_light;
+
+    let view_position = view * (vec4<f32>(position, 1.0) + chunk_offset);
+    out.clip_position = projection * view_position;
+    out.fog = smoothstep(environment.fog_start, environment.fog_end, length(view_position.xyz));
+    // Moved along the normal, so that the face isn't in its own shadow
+    let world_position = position + chunk_offset.xyz + normals[normal_index] * shadow_normal_offset;
+    out.shadow_position = light_matrix * vec4<f32>(world_position, 1.0);
+    out.normals = normals[normal_index];
+    out.tex_coords = uv;
+    // The frames of animated textures are in the layers following the first one
+    let first_layer = (in.data >> 15u) & 0xFFu;
+    let animation = animations[first_layer];
+    out.texture_layer = first_layer + u32(environment.time / animation.y) % u32(animation.x);
+    out.emission = animation.z;
+    out.ao = f32(ao) / 3.0;
+    out.light = light;
 
     return out;
 }
 
 
 @group(1) @binding(0)
-var diffuse: texture_2d<f32>;
+var diffuse: texture_2d_array<f32>;
 @group(1) @binding(1)
 var t_sampler: sampler;
 
@@ -57,20 +125,66 @@ struct FragmentInput {
         @location(1) normals: vec3<f32>,
         @location(2) current_chunk: vec2<i32>,
         @location(3) block_type: u32,
-        @location(4) ao: f32
+        @location(4) ao: f32,
+        @location(5) light: vec2<f32>,
+        @location(6) fog: f32,
+        @location(7) shadow_position: vec4<f32>,
+        @location(8) @interpolate(flat) texture_layer: u32,
+        @location(9) @interpolate(flat) emission: f32,
 }
 
-const light_direction = vec3<f32>(0.25, 1.0, -0.5);
 const ambient_light = 0.005;
+const block_light_color = vec3<f32>(1.0, 0.85, 0.6);
+// Emissive blocks are brighter than white, so that they glow with the bloom
+const emission_strength = 1.5;
 
-@fragment
-fn fs_main(in: FragmentInput) -> @location(0) vec4<f32> {
-    var color: vec4<f32>;
+// Each light level is 80% as bright as the one above it
+fn light_brightness(level: f32) -> f32 {
+    return pow(0.8, (1.0 - level) * max_light);
+}
 
-    color = textureSample(diffuse, t_sampler, in.tex_coords);
-    color *= max(dot(in.normals, normalize(light_direction)), 0.2);
-    color += vec4<f32>(vec3<f32>(ambient_light), 0.0);
-    color *= 1.0 - in.ao;
+// 1 when the fragment is lit by the sun, 0 when it's in the shade
+fn sun_visibility(shadow_position: vec4<f32>) -> f32 {
+    let position = shadow_position.xyz / shadow_position.w;
+    let uv = position.xy * vec2<f32>(0.5, -0.5) + 0.5;
+    // Outside of the shadow map
+    if any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) || position.z > 1.0 {
+        return 1.0;
+    }
+
+    // Percentage closer filtering, so the shadows have soft edges
+    var visibility = 0.0;
+    for (var x = -1; x <= 1; x++) {
+        for (var y = -1; y <= 1; y++) {
+            let offset = vec2<f32>(f32(x), f32(y)) / shadow_map_size;
+            visibility += textureSampleCompareLevel(shadow_map, shadow_sampler, uv + offset, position.z);
+        }
+    }
+    return visibility / 9.0;
+}
 
-    return color;
+@fragment
+fn fs_main(in: FragmentInput) -> @location(0) vec4<f32> {
+    let texture_color = textureSample(diffuse, t_sampler, in.tex_coords, in.texture_layer);
+    // Cutout blocks (e.g. plants)
+    if texture_color.a < 0.5 {
+        discard;
+    }
+    var color = texture_color.rgb;
+
+    // Faces turned away from the sun only get the light scattered by the sky
+    var sun_light = max(dot(in.normals, environment.sun_direction.xyz), 0.0) * environment.sun_intensity;
+    if sun_light > 0.0 {
+        sun_light *= sun_visibility(in.shadow_position);
+    }
+    let sky_light = light_brightness(in.light.x) * environment.sky_light * mix(0.6, 1.0, sun_light);
+    let block_light = light_brightness(in.light.y) * block_light_color;
+    color *= max(vec3<f32>(sky_light), block_light);
+    color += vec3<f32>(ambient_light);
+    color *= in.ao;
+    color += texture_color.rgb * in.emission * emission_strength;
+    // Far away terrain fades into the sky's horizon
+    color = mix(color, environment.sky_color.rgb, in.fog);
+
+    return vec4<f32>(color, texture_color.a);
 }
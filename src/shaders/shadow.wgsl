@@ -0,0 +1,52 @@
+struct VertexInput {
+    // x (9 bits) | y (13 bits) | z (9 bits), in 1/16 of a block
+    @location(0) position: u32,
+    // normal (3 bits) | ao (2 bits) | u (5 bits) | v (5 bits) | texture layer (8 bits)
+    // | sky light (4 bits) | block light (4 bits)
+    @location(1) data: u32,
+    @location(2) chunk_position: vec2<i32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) @interpolate(flat) texture_layer: u32,
+}
+
+// View-projection of the sun
+@group(0) @binding(0)
+var<uniform> light_matrix: mat4x4<f32>;
+@group(1) @binding(0)
+var diffuse: texture_2d_array<f32>;
+@group(1) @binding(1)
+var t_sampler: sampler;
+
+const position_scale = 16.0;
+
+@vertex
+fn vs_main(in: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    let current_chunk = in.chunk_position;
+    let chunk_offset = vec3<f32>(f32(current_chunk.x) * 16.0, 0.0, f32(current_chunk.y) * 16.0);
+
+    let position = vec3<f32>(
+        f32(in.position & 0x1FFu),
+        f32((in.position >> 9u) & 0x1FFFu),
+        f32((in.position >> 22u) & 0x1FFu),
+    ) / position_scale - 0.5;
+
+    let uv = vec2<f32>(f32((in.data >> 5u) & 0x1Fu), f32((in.data >> 10u) & 0x1Fu)) / position_scale;
+
+    out.clip_position = light_matrix * vec4<f32>(position + chunk_offset, 1.0);
+    out.tex_coords = uv;
+    out.texture_layer = (in.data >> 15u) & 0xFFu;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) {
+    // Cutout blocks only cast the shadow of their opaque pixels
+    if textureSample(diffuse, t_sampler, in.tex_coords, in.texture_layer).a < 0.5 {
+        discard;
+    }
+}
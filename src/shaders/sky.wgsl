@@ -0,0 +1,65 @@
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    // Direction of the pixel in world space, not normalized so that it can be interpolated
+    @location(0) ray: vec3<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> projection: mat4x4<f32>;
+@group(0) @binding(1)
+var<uniform> view: mat4x4<f32>;
+struct Environment {
+    sun_direction: vec4<f32>,
+    sky_color: vec4<f32>,
+    sky_light: f32,
+    sun_intensity: f32,
+    fog_start: f32,
+    fog_end: f32,
+    // Seconds since the game started
+    time: f32,
+}
+@group(0) @binding(2)
+var<uniform> environment: Environment;
+
+const sun_size = 0.9995;
+const moon_size = 0.9997;
+const sun_color = vec3<f32>(1.0, 0.95, 0.8);
+const moon_color = vec3<f32>(0.75, 0.8, 0.9);
+
+// A single triangle covering the whole screen
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    var out: VertexOutput;
+    let ndc = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u)) * 2.0 - 1.0;
+    // Behind everything else
+    out.clip_position = vec4<f32>(ndc, 1.0, 1.0);
+
+    // The view matrix only rotates and translates, so its inverse rotation is the transpose
+    let view_ray = vec3<f32>(ndc.x / projection[0][0], ndc.y / projection[1][1], 1.0);
+    let rotation = mat3x3<f32>(view[0].xyz, view[1].xyz, view[2].xyz);
+    out.ray = transpose(rotation) * view_ray;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let direction = normalize(in.ray);
+
+    // The horizon has the same colour as the fog, the zenith is deeper
+    let horizon_color = environment.sky_color.rgb;
+    let zenith_color = horizon_color * vec3<f32>(0.55, 0.7, 1.0);
+    var color = mix(horizon_color, zenith_color, pow(max(direction.y, 0.0), 0.6));
+    // Under the horizon
+    color = mix(color, horizon_color * 0.8, clamp(-direction.y * 4.0, 0.0, 1.0));
+
+    let sun_direction = normalize(environment.sun_direction.xyz);
+    let sun_dot = dot(direction, sun_direction);
+    let sun_glow = pow(max(sun_dot, 0.0), 64.0) * 0.4 * environment.sun_intensity;
+    color += sun_color * (smoothstep(sun_size - 0.0002, sun_size, sun_dot) + sun_glow);
+
+    // The moon is opposite to the sun and fades in at night
+    let moon_dot = dot(direction, -sun_direction);
+    color += moon_color * smoothstep(moon_size - 0.0002, moon_size, moon_dot) * (1.0 - environment.sun_intensity);
+
+    return vec4<f32>(color, 1.0);
+}
@@ -1,12 +1,14 @@
 struct VertexInput {
-    @builtin(vertex_index) vertex_index: u32,
     @location(0) position: vec3<f32>,
-
+    @location(1) uv: vec2<f32>,
+    @location(2) layer: u32,
 }
 
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) @interpolate(flat) layer: u32,
 }
 
 
@@ -20,33 +22,27 @@ var<uniform> view: mat4x4<f32>;
 fn vs_main(in: VertexInput) -> VertexOutput {
     var out: VertexOutput;
 
-
     out.clip_position = projection * view * vec4<f32>(in.position, 1.0);
+    out.uv = in.uv;
+    out.layer = in.layer;
 
     return out;
 }
 
 
 @group(1) @binding(0)
-var diffuse: texture_2d<f32>;
+var crack: texture_2d_array<f32>;
 @group(1) @binding(1)
 var t_sampler: sampler;
 
-struct FragmentInput {
-        @location(0) tex_coords: vec2<f32>,
-        @location(1) normals: vec3<f32>,
-        @location(2) current_chunk: vec2<i32>,
-        @location(3) block_type: u32
+// Edges of the selection box
+@fragment
+fn fs_outline(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(0.0, 0.0, 0.0, 0.6);
 }
 
-const light_direction = vec3<f32>(0.25, 1.0, -0.5);
-const ambient_light = 0.005;
-
+// Stage of the crack of the block being broken
 @fragment
-fn fs_main() -> @location(0) vec4<f32> {
-    var color: vec4<f32>;
-
-    color = vec4<f32>(1.0, 0.0, 0.0 , 0.2);
-
-    return color;
+fn fs_crack(in: VertexOutput) -> @location(0) vec4<f32> {
+    return textureSample(crack, t_sampler, in.uv, in.layer);
 }
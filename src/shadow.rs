@@ -0,0 +1,243 @@
+use glam::{Mat4, Vec3};
+use wgpu::util::DeviceExt;
+
+use crate::arena::MeshArena;
+use crate::blocks::block::Block;
+use crate::material::Texture;
+use crate::pipeline::PipelineTrait;
+use crate::player::Camera;
+use crate::render_graph::{Frame, RenderNode};
+
+// Width and height of the shadow map in texels
+pub const SHADOW_MAP_SIZE: u32 = 2048;
+// How far from the camera shadows are drawn, in blocks
+pub const SHADOW_DISTANCE: f32 = 96.0;
+// How far behind the visible area blocks can still cast a shadow into it
+const CASTER_DISTANCE: f32 = 128.0;
+
+/* Orthographic view-projection of the sun, fitted around the part of the camera's frustum that has shadows.
+ * The box is the frustum's bounding sphere snapped to the texels, so the shadows don't flicker
+ * when the camera moves or turns
+ */
+pub fn light_matrix(camera: &Camera, sun_direction: Vec3) -> Mat4 {
+    let projection = Mat4::perspective_lh(
+        camera.fovy,
+        camera.aspect_ratio,
+        camera.znear,
+        SHADOW_DISTANCE,
+    );
+    let inverse = (projection * camera.build_view_matrix()).inverse();
+    let corners = [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)]
+        .iter()
+        .flat_map(|(x, y)| [0.0, 1.0].map(|z| inverse.project_point3(glam::vec3(*x, *y, z))))
+        .collect::<Vec<_>>();
+
+    let center = corners.iter().sum::<Vec3>() / corners.len() as f32;
+    let radius = corners
+        .iter()
+        .map(|c| c.distance(center))
+        .fold(0.0, f32::max)
+        .ceil();
+
+    // The sun never goes straight up, so Z can be used as the up vector
+    let light_view = Mat4::look_to_lh(Vec3::ZERO, -sun_direction.normalize(), Vec3::Z);
+    let texel_size = 2.0 * radius / SHADOW_MAP_SIZE as f32;
+    let mut center = light_view.transform_point3(center);
+    center.x = (center.x / texel_size).floor() * texel_size;
+    center.y = (center.y / texel_size).floor() * texel_size;
+
+    let projection = Mat4::orthographic_lh(
+        center.x - radius,
+        center.x + radius,
+        center.y - radius,
+        center.y + radius,
+        center.z - radius - CASTER_DISTANCE,
+        center.z + radius,
+    );
+    projection * light_view
+}
+
+// Depth of the terrain seen from the sun, sampled by the world shader to know what is in the shade
+pub struct ShadowMap {
+    pub texture: Texture,
+    pub light_buffer: wgpu::Buffer,
+    pub pipeline: wgpu::RenderPipeline,
+    // Used by the shadow pass, the world shader binds the buffer and texture itself
+    pub bind_group_0: wgpu::BindGroup,
+}
+
+impl ShadowMap {
+    // atlas_layout: same bind group 1 as the world pipeline
+    pub fn new(
+        device: &wgpu::Device,
+        shader_source: &str,
+        atlas_layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("shadow_map"),
+            size: wgpu::Extent3d {
+                width: SHADOW_MAP_SIZE,
+                height: SHADOW_MAP_SIZE,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Texture::DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        // Linear filtering compares 4 texels at once, on top of the PCF done in the shader
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("shadow_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("light_matrix"),
+            contents: bytemuck::cast_slice(&[Mat4::IDENTITY.to_cols_array()]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let bind_group_0_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("shadow_bind_group_0"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+        let bind_group_0 = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("shadow_bind_group_0"),
+            layout: &bind_group_0_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: light_buffer.as_entire_binding(),
+            }],
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("shadow_shader"),
+            source: wgpu::ShaderSource::Wgsl(shader_source.into()),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("shadow_pipeline_layout"),
+            bind_group_layouts: &[&bind_group_0_layout, atlas_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("shadow_pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[Block::get_vertex_data_layout(), MeshArena::chunk_layout()],
+            },
+            // Only used to discard the transparent pixels of plants and leaves
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[],
+            }),
+            primitive: wgpu::PrimitiveState {
+                // Both sides cast shadows, so thin shapes like plants work
+                cull_mode: None,
+                ..Default::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                // Pushes the depth away from the sun, so faces don't shadow themselves
+                bias: wgpu::DepthBiasState {
+                    constant: 2,
+                    slope_scale: 2.0,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        Self {
+            texture: Texture {
+                texture,
+                view,
+                sampler,
+                name: String::from("shadow_map"),
+                data: None,
+            },
+            light_buffer,
+            pipeline,
+            bind_group_0,
+        }
+    }
+}
+
+// Node of the render graph drawing the shadow map, before the world pass samples it
+pub fn draw_shadow_pass<'a>(
+    _node: &'a RenderNode,
+    pipeline: &'a dyn PipelineTrait,
+    frame: &'a Frame<'a>,
+    rpass: &mut wgpu::RenderPass<'a>,
+) {
+    let Some(shadow_map) = pipeline.shadow_map() else {
+        return;
+    };
+    rpass.set_pipeline(&shadow_map.pipeline);
+    rpass.set_bind_group(0, &shadow_map.bind_group_0, &[]);
+    rpass.set_bind_group(1, pipeline.bind_group_1(), &[]);
+    let arena = &frame.state.world.arena;
+    arena.bind(rpass);
+    arena.draw_batch(rpass, frame.shadow_draws, frame.draw_offsets[2]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts;
+
+    #[test]
+    fn should_fit_the_shadowed_part_of_the_view() {
+        let camera = Camera {
+            aspect_ratio: 16.0 / 9.0,
+            eye: glam::vec3(20.0, 70.0, -35.0),
+            yaw: 0.3,
+            pitch: -0.4,
+            fovy: consts::FRAC_PI_4,
+            znear: 0.1,
+            zfar: 1000.0,
+            needs_update: false,
+        };
+        let sun_direction = glam::vec3(0.4, 0.8, 0.3);
+        let matrix = light_matrix(&camera, sun_direction);
+
+        let target = camera.calc_target().normalize();
+        for distance in [1.0, SHADOW_DISTANCE / 2.0, SHADOW_DISTANCE - 1.0] {
+            let position = matrix.project_point3(camera.eye + target * distance);
+            assert!(position.x.abs() <= 1.0 && position.y.abs() <= 1.0);
+            assert!((0.0..=1.0).contains(&position.z));
+        }
+
+        // Blocks between the sun and the view cast shadows into it
+        let caster = camera.eye + sun_direction.normalize() * 60.0;
+        let position = matrix.project_point3(caster);
+        assert!((0.0..=1.0).contains(&position.z));
+        // and are closer to the sun
+        assert!(position.z < matrix.project_point3(camera.eye).z);
+    }
+}
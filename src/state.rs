@@ -1,16 +1,30 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::{Mutex, RwLock};
 use std::{f32::consts, sync::Arc};
 
+use crate::arena::MeshArena;
 use crate::blocks::block::Block;
+use crate::blocks::block_shape;
 use crate::blocks::block_type::BlockType;
-use crate::collision::CollisionBox;
-use crate::persistence::Saveable;
-use crate::pipeline::{Pipeline, PipelineTrait};
+use crate::clouds::{self, CloudPipeline, CloudSettings, CloudStyle, Clouds};
+use crate::culling::{find_reachable_sections, CullingStats, Frustum};
+use crate::persistence::{Saveable, DATA_DIR};
+use crate::pipeline::{Pipeline, PipelineTrait, PipelineType};
+use crate::pipeline_manager::PipelineManager;
+use crate::post::{self, AntiAliasing, PostSettings};
+use crate::render_graph::{
+    AttachmentDesc, AttachmentSize, Frame, RenderGraph, RenderNode, SURFACE,
+};
+use crate::resources::{ResourcePack, ResourcePacks, PACKS_DIR};
+use crate::screenshot;
+use crate::shadow;
+use crate::ui;
 use crate::utils::{ChunkFromPosition, RelativeFromAbsolute};
 use crate::{
-    material::Texture,
-    pipeline::{self, Uniforms},
-    player::{Camera, CameraController, Player},
+    material::{SamplerSettings, Texture},
+    pipeline::{self, EnvironmentUniforms, Uniforms},
+    player::{Camera, CameraController, Mining, Player, HOTBAR},
     ui::{UIPipeline, UI},
     world::World,
 };
@@ -23,6 +37,19 @@ use winit::{
     window::Window,
 };
 
+const HOTBAR_KEYS: [KeyCode; 10] = [
+    KeyCode::Digit1,
+    KeyCode::Digit2,
+    KeyCode::Digit3,
+    KeyCode::Digit4,
+    KeyCode::Digit5,
+    KeyCode::Digit6,
+    KeyCode::Digit7,
+    KeyCode::Digit8,
+    KeyCode::Digit9,
+    KeyCode::Digit0,
+];
+
 impl State {
     pub async fn new(window: Arc<Mutex<Window>>) -> Self {
         let windowbrw = window.lock().unwrap();
@@ -37,27 +64,15 @@ impl State {
             })
             .await
             .expect("Failed to find an appropriate adapter");
+        let (device, queue) = Self::request_device(&adapter).await;
 
-        // Create the logical device and command queue
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    label: None,
-                    features: wgpu::Features::POLYGON_MODE_LINE,
-                    limits: wgpu::Limits::default(),
-                },
-                None,
-            )
-            .await
-            .expect("Failed to create device");
-
-        let device = Arc::new(device);
-        let queue = Arc::new(queue);
         let swapchain_capabilities = surface.get_capabilities(&adapter);
         let swapchain_format = swapchain_capabilities.formats[0];
 
         let surface_config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            // Read back for the screenshots, when the platform allows it
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                | (swapchain_capabilities.usages & wgpu::TextureUsages::COPY_SRC),
             format: swapchain_format,
             width: size.width,
             height: size.height,
@@ -65,6 +80,8 @@ impl State {
             alpha_mode: swapchain_capabilities.alpha_modes[0],
             view_formats: vec![],
         };
+        surface.configure(&device, &surface_config);
+        drop(windowbrw);
 
         let camera = Camera {
             aspect_ratio: surface_config.width as f32 / surface_config.height as f32,
@@ -77,6 +94,95 @@ impl State {
             zfar: 1000.,
             needs_update: false,
         };
+        let target = RenderTarget::Window { window, surface };
+        let world = World::init_world(device, queue, PathBuf::from(DATA_DIR));
+        Self::from_target(instance, adapter, world, target, surface_config, camera)
+    }
+    /* Draws to an offscreen texture instead of a window, with a software adapter if there is one.
+     * The world is the one saved in data_dir, seen from the camera
+     */
+    pub async fn new_headless(width: u32, height: u32, camera: Camera, data_dir: &Path) -> Self {
+        let instance = wgpu::Instance::default();
+        let mut adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                force_fallback_adapter: true,
+                compatible_surface: None,
+            })
+            .await;
+        if adapter.is_none() {
+            log::warn!("No software adapter, rendering with the default one");
+            adapter = instance
+                .request_adapter(&wgpu::RequestAdapterOptions::default())
+                .await;
+        }
+        let adapter = adapter.expect("Failed to find an appropriate adapter");
+        let (device, queue) = Self::request_device(&adapter).await;
+
+        // Not a real surface, only describes the offscreen texture
+        let surface_config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            width,
+            height,
+            present_mode: wgpu::PresentMode::Fifo,
+            alpha_mode: wgpu::CompositeAlphaMode::Auto,
+            view_formats: vec![],
+        };
+        let texture = Self::create_offscreen_texture(&device, &surface_config);
+        let target = RenderTarget::Offscreen(texture);
+        let camera = Camera {
+            aspect_ratio: width as f32 / height as f32,
+            ..camera
+        };
+        let world = World::init_world(device, queue, data_dir.to_path_buf());
+        Self::from_target(instance, adapter, world, target, surface_config, camera)
+    }
+    async fn request_device(adapter: &wgpu::Adapter) -> (Arc<wgpu::Device>, Arc<wgpu::Queue>) {
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: None,
+                    // Software adapters don't always have the wireframe mode
+                    features: adapter.features()
+                        & (wgpu::Features::POLYGON_MODE_LINE | MeshArena::MULTI_DRAW_FEATURES),
+                    limits: wgpu::Limits::default(),
+                },
+                None,
+            )
+            .await
+            .expect("Failed to create device");
+        (Arc::new(device), Arc::new(queue))
+    }
+    fn create_offscreen_texture(
+        device: &wgpu::Device,
+        surface_config: &wgpu::SurfaceConfiguration,
+    ) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("offscreen_target"),
+            size: wgpu::Extent3d {
+                width: surface_config.width,
+                height: surface_config.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: surface_config.format,
+            usage: surface_config.usage,
+            view_formats: &[],
+        })
+    }
+    fn from_target(
+        instance: wgpu::Instance,
+        adapter: wgpu::Adapter,
+        mut world: World,
+        target: RenderTarget,
+        surface_config: wgpu::SurfaceConfiguration,
+        camera: Camera,
+    ) -> Self {
+        let device = world.device.clone();
+        let queue = world.queue.clone();
         let player = Player {
             camera,
             current_chunk: (0, 0),
@@ -86,45 +192,217 @@ impl State {
             facing_face: None,
             jump_action_start: None,
             is_ghost: false,
+            selected_block: BlockType::dirt(),
+            mining: None,
         };
 
-        surface.configure(&device, &surface_config);
         let config = Config {
             polygon_mode: wgpu::PolygonMode::Fill,
+            block_sampler: SamplerSettings::default(),
+            post: PostSettings::default(),
+            clouds: CloudSettings::default(),
         };
 
-        let mut world = World::init_world(device.clone(), queue.clone());
+        let pipeline_manager = PipelineManager::new(&ResourcePacks::default(), &config);
+
         world.init_chunks();
-        let ui = UI::new(device.clone(), queue.clone());
+        let ui = UI::new(device.clone());
+        let clouds = Clouds::new(&device, config.clouds);
+        // Built with the pipelines
+        let render_graph = RenderGraph::new(surface_config.width, surface_config.height);
 
         let mut state = Self {
             config,
+            resources: ResourcePacks::default(),
+            pipeline_manager,
             player,
             ui,
-            pipelines: vec![],
+            clouds,
+            pipelines: HashMap::new(),
+            render_graph,
             surface_config,
             instance,
-            window: window.clone(),
+            target,
+            screenshot: None,
             device,
             world,
             queue,
-            surface,
             adapter,
             camera_controller: CameraController::default(),
+            culling_stats: CullingStats::default(),
         };
 
-        let world_pipeline = Box::new(Pipeline::new(&state));
-        let ui_pipeline = Box::new(UIPipeline::new(&state));
-
-        state.pipelines.push(world_pipeline);
-        state.pipelines.push(ui_pipeline);
+        state.create_pipelines();
+        assert!(!state.pipelines.is_empty(), "Failed to build the pipelines");
 
         state
     }
+    // Keeps the previous pipelines if the new ones can't be built, e.g. because of a shader error
+    fn create_pipelines(&mut self) {
+        // The post-process settings change the passes, which the pipelines are built for
+        let render_graph =
+            Self::create_render_graph(&self.device, &self.surface_config, &self.config);
+        let previous_graph = std::mem::replace(&mut self.render_graph, render_graph);
+
+        self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let world_pipeline: Box<dyn PipelineTrait> = Box::new(Pipeline::new(self));
+        let ui_pipeline = UIPipeline::new(self);
+        let crack_frames = ui_pipeline.crack_frames;
+        let ui_pipeline: Box<dyn PipelineTrait> = Box::new(ui_pipeline);
+        let cloud_pipeline: Box<dyn PipelineTrait> = Box::new(CloudPipeline::new(self));
+        let error = pollster::block_on(self.device.pop_error_scope());
+        self.pipeline_manager
+            .on_rebuild(&self.resources, &self.config);
+
+        if let Some(error) = error {
+            log::error!("Failed to build the pipelines: {error}");
+            self.render_graph = previous_graph;
+            return;
+        }
+        self.ui.crack_frames = crack_frames;
+        self.pipelines = HashMap::from([
+            (world_pipeline.get_type(), world_pipeline),
+            (ui_pipeline.get_type(), ui_pipeline),
+            (cloud_pipeline.get_type(), cloud_pipeline),
+        ]);
+    }
+    /* Passes drawing every frame, in order.
+     * The shadow map is owned by the world pipeline, it's imported with the surface in draw
+     */
+    fn create_render_graph(
+        device: &wgpu::Device,
+        surface_config: &wgpu::SurfaceConfiguration,
+        config: &Config,
+    ) -> RenderGraph {
+        let mut graph = RenderGraph::new(surface_config.width, surface_config.height);
+        let samples = config.post.anti_aliasing.samples();
+        for (name, format, samples, usage) in [
+            (
+                "depth",
+                Texture::DEPTH_FORMAT,
+                samples,
+                wgpu::TextureUsages::empty(),
+            ),
+            (
+                "hdr",
+                post::HDR_FORMAT,
+                1,
+                wgpu::TextureUsages::TEXTURE_BINDING,
+            ),
+            (
+                "hdr_multisampled",
+                post::HDR_FORMAT,
+                samples,
+                wgpu::TextureUsages::empty(),
+            ),
+        ] {
+            graph.add_attachment(AttachmentDesc {
+                name,
+                format,
+                size: AttachmentSize::Surface,
+                samples,
+                usage,
+            });
+        }
+        graph.add_node(RenderNode {
+            name: "shadow_pass",
+            pipeline: PipelineType::WORLD,
+            color: None,
+            resolve: None,
+            depth: Some("shadow_map"),
+            reads: &[],
+            draw: shadow::draw_shadow_pass,
+        });
+        graph.add_node(RenderNode {
+            name: "world_pass",
+            pipeline: PipelineType::WORLD,
+            // Resolved into the HDR target with MSAA
+            color: Some(if samples > 1 {
+                "hdr_multisampled"
+            } else {
+                "hdr"
+            }),
+            resolve: (samples > 1).then_some("hdr"),
+            depth: Some("depth"),
+            reads: &["shadow_map"],
+            draw: pipeline::draw_world_pass,
+        });
+        // Over the terrain and the water, the clouds are depth tested against both
+        if config.clouds.style != CloudStyle::Off {
+            graph.add_node(RenderNode {
+                name: "cloud_pass",
+                pipeline: PipelineType::CLOUDS,
+                color: Some(if samples > 1 {
+                    "hdr_multisampled"
+                } else {
+                    "hdr"
+                }),
+                resolve: (samples > 1).then_some("hdr"),
+                depth: Some("depth"),
+                reads: &[],
+                draw: clouds::draw_cloud_pass,
+            });
+        }
+        // Tested against the depth of the world, and tone mapped with it
+        graph.add_node(RenderNode {
+            name: "selection_pass",
+            pipeline: PipelineType::UI,
+            color: Some(if samples > 1 {
+                "hdr_multisampled"
+            } else {
+                "hdr"
+            }),
+            resolve: (samples > 1).then_some("hdr"),
+            depth: Some("depth"),
+            reads: &[],
+            draw: ui::draw_ui_pass,
+        });
+        post::add_passes(&mut graph, &config.post, surface_config.format);
+        graph.build(device);
+        graph
+    }
+    // Reloads the textures, shaders and block models from the given packs
+    pub fn set_resource_packs(&mut self, resources: ResourcePacks) {
+        self.resources = resources;
+        let was_opaque = BlockType::all().map(|b| b.is_opaque()).collect::<Vec<_>>();
+        block_shape::set_block_models(self.resources.block_models());
+        // A model can let the light through a block that stopped it, or the other way around
+        let opacity_changed = BlockType::all()
+            .zip(was_opaque)
+            .filter(|(block_type, was_opaque)| block_type.is_opaque() != *was_opaque)
+            .map(|(block_type, _)| block_type.to_id())
+            .collect::<Vec<_>>();
+        self.world.relight_chunks_containing(&opacity_changed);
+        self.create_pipelines();
+        // The models change the meshes
+        self.world.render_all_chunks();
+    }
+    // Enables the next pack of PACKS_DIR, or none after the last one
+    fn cycle_resource_pack(&mut self) {
+        let mut available = ResourcePack::find_all(PACKS_DIR);
+        let current = self.resources.packs.first().map(|p| p.name.clone());
+        let next = match current {
+            Some(name) => available
+                .iter()
+                .position(|p| p.name == name)
+                .map_or(0, |i| i + 1),
+            None => 0,
+        };
+        let packs = if next < available.len() {
+            vec![available.swap_remove(next)]
+        } else {
+            vec![]
+        };
+        match packs.first() {
+            Some(pack) => log::info!("Resource pack: {}", pack.name),
+            None => log::info!("Resource pack: none"),
+        }
+        self.set_resource_packs(ResourcePacks { packs });
+    }
     pub fn save_state(&mut self) {
         self.player
             .camera
-            .save()
+            .save(&self.world.data_dir)
             .expect("Failed to save camera state");
         self.world.save_state();
     }
@@ -162,12 +440,15 @@ impl State {
             KeyEvent {
                 physical_key: PhysicalKey::Code(KeyCode::KeyK),
                 ..
-            } => self
-                .window
-                .lock()
-                .unwrap()
-                .set_cursor_grab(CursorGrabMode::Confined)
-                .unwrap(),
+            } => {
+                if let RenderTarget::Window { window, .. } = &self.target {
+                    window
+                        .lock()
+                        .unwrap()
+                        .set_cursor_grab(CursorGrabMode::Confined)
+                        .unwrap()
+                }
+            }
             KeyEvent {
                 physical_key: PhysicalKey::Code(KeyCode::Space),
                 state: winit::event::ElementState::Pressed,
@@ -185,6 +466,40 @@ impl State {
             } => {
                 self.player.is_ghost = !self.player.is_ghost;
             }
+            KeyEvent {
+                physical_key: PhysicalKey::Code(code),
+                state: winit::event::ElementState::Pressed,
+                ..
+            } if HOTBAR_KEYS.contains(&code) => {
+                let slot = HOTBAR_KEYS.iter().position(|k| *k == code).unwrap();
+                self.player.selected_block = BlockType::from_id(HOTBAR[slot]);
+            }
+            KeyEvent {
+                physical_key: PhysicalKey::Code(KeyCode::KeyT),
+                state: winit::event::ElementState::Pressed,
+                ..
+            } => self.world.clock.toggle_pause(),
+            KeyEvent {
+                physical_key: PhysicalKey::Code(KeyCode::KeyN),
+                state: winit::event::ElementState::Pressed,
+                ..
+            } => self.world.clock.skip_to_next_quarter(),
+            KeyEvent {
+                physical_key: PhysicalKey::Code(KeyCode::BracketLeft),
+                state: winit::event::ElementState::Pressed,
+                ..
+            } => {
+                let speed = self.world.clock.speed;
+                self.world.clock.set_speed(speed / 2.0);
+            }
+            KeyEvent {
+                physical_key: PhysicalKey::Code(KeyCode::BracketRight),
+                state: winit::event::ElementState::Pressed,
+                ..
+            } => {
+                let speed = self.world.clock.speed;
+                self.world.clock.set_speed(speed * 2.0);
+            }
             KeyEvent {
                 physical_key: PhysicalKey::Code(KeyCode::KeyF),
                 state: winit::event::ElementState::Pressed,
@@ -192,38 +507,110 @@ impl State {
             } => {
                 if self.config.polygon_mode == wgpu::PolygonMode::Line {
                     self.config.polygon_mode = wgpu::PolygonMode::Fill
-                } else {
+                } else if self
+                    .device
+                    .features()
+                    .contains(wgpu::Features::POLYGON_MODE_LINE)
+                {
                     self.config.polygon_mode = wgpu::PolygonMode::Line
+                } else {
+                    log::warn!("The wireframe mode isn't supported by the adapter");
                 }
             }
+            KeyEvent {
+                physical_key: PhysicalKey::Code(KeyCode::F2),
+                state: winit::event::ElementState::Pressed,
+                ..
+            } => self.screenshot = Some(screenshot::new_screenshot_path()),
+            KeyEvent {
+                physical_key: PhysicalKey::Code(KeyCode::F4),
+                state: winit::event::ElementState::Pressed,
+                ..
+            } => {
+                let mut anti_aliasing = self.config.post.anti_aliasing.next();
+                if let AntiAliasing::Msaa(samples) = anti_aliasing {
+                    if !post::supports_msaa(&self.adapter, samples) {
+                        log::warn!("MSAA x{samples} isn't supported by the adapter");
+                        anti_aliasing = anti_aliasing.next();
+                    }
+                }
+                log::info!("Anti-aliasing: {anti_aliasing:?}");
+                self.config.post.anti_aliasing = anti_aliasing;
+            }
+            KeyEvent {
+                physical_key: PhysicalKey::Code(KeyCode::F5),
+                state: winit::event::ElementState::Pressed,
+                ..
+            } => self.config.post.tone_mapping = !self.config.post.tone_mapping,
+            KeyEvent {
+                physical_key: PhysicalKey::Code(KeyCode::F6),
+                state: winit::event::ElementState::Pressed,
+                ..
+            } => self.config.post.bloom = !self.config.post.bloom,
+            KeyEvent {
+                physical_key: PhysicalKey::Code(KeyCode::F7),
+                state: winit::event::ElementState::Pressed,
+                ..
+            } => {
+                self.config.clouds.style = self.config.clouds.style.next();
+                log::info!("Clouds: {:?}", self.config.clouds.style);
+            }
+            // The sampler and the bind groups that use it are rebuilt with the pipelines
+            KeyEvent {
+                physical_key: PhysicalKey::Code(KeyCode::F8),
+                state: winit::event::ElementState::Pressed,
+                ..
+            } => {
+                self.config.block_sampler = self.config.block_sampler.next();
+                log::info!("Block textures filtering: {:?}", self.config.block_sampler);
+            }
+            KeyEvent {
+                physical_key: PhysicalKey::Code(KeyCode::KeyP),
+                state: winit::event::ElementState::Pressed,
+                ..
+            } => self.cycle_resource_pack(),
             _ => {}
         }
     }
     pub fn on_click(&mut self, button: MouseButton) {
+        // The block is broken in update once it has been mined long enough
+        if button == MouseButton::Left {
+            self.player
+                .mining
+                .get_or_insert_with(Mining::default)
+                .is_held = true;
+            return;
+        }
+        if button != MouseButton::Right {
+            return;
+        }
         if let Some(facing_block) = self.player.facing_block.as_ref() {
             let facing_face = self
                 .player
                 .facing_face
                 .expect("Cannot be not facing a face if it's facing a block");
-            match button {
-                MouseButton::Left => {
-                    self.world.remove_block(facing_block.clone());
-                }
-                MouseButton::Right => {
-                    let block_borrow = facing_block.read().unwrap();
-                    let new_block_abs_position =
-                        block_borrow.absolute_position + facing_face.get_normal_vector();
+            let block_borrow = facing_block.read().unwrap();
+            let new_block_abs_position =
+                block_borrow.absolute_position + facing_face.get_normal_vector();
 
-                    let chunk = new_block_abs_position.get_chunk_from_position_absolute();
-                    let position = new_block_abs_position.relative_from_absolute();
+            let chunk = new_block_abs_position.get_chunk_from_position_absolute();
+            let position = new_block_abs_position.relative_from_absolute();
 
-                    let new_block =
-                        Arc::new(RwLock::new(Block::new(position, chunk, BlockType::dirt())));
+            // Stairs go up away from the player
+            let new_block = Arc::new(RwLock::new(Block {
+                facing: self.player.camera.horizontal_facing(),
+                ..Block::new(position, chunk, self.player.selected_block)
+            }));
 
-                    self.world.place_block(new_block);
-                }
-                _ => {}
-            }
+            self.world.place_block(new_block);
+        }
+    }
+    pub fn on_release(&mut self, button: MouseButton) {
+        if button != MouseButton::Left {
+            return;
+        }
+        if let Some(mining) = self.player.mining.as_mut() {
+            mining.release();
         }
     }
     pub fn handle_mouse(&mut self, delta: &glam::Vec2) {
@@ -234,22 +621,45 @@ impl State {
         if new_size.width > 0 && new_size.height > 0 {
             self.surface_config.width = new_size.width.max(1);
             self.surface_config.height = new_size.height.max(1);
-            self.surface.configure(&self.device, &self.surface_config);
-            let new_depth = Texture::create_depth_texture(&self);
-            self.pipelines[0].set_depth_texture(new_depth);
+            match &mut self.target {
+                RenderTarget::Window { surface, .. } => {
+                    surface.configure(&self.device, &self.surface_config)
+                }
+                RenderTarget::Offscreen(texture) => {
+                    *texture = Self::create_offscreen_texture(&self.device, &self.surface_config)
+                }
+            }
+            self.render_graph
+                .resize(&self.device, new_size.width, new_size.height);
+            for pipeline in self.pipelines.values_mut() {
+                pipeline.resize(&self.device, &self.render_graph);
+            }
         }
     }
     pub fn update(&mut self, delta_time: f32, total_time: f32) {
+        if self.pipeline_manager.needs_rebuild(&self.config) {
+            log::info!("Rebuilding the pipelines");
+            self.create_pipelines();
+        }
         let mut collisions = vec![];
+        let mut selection_boxes = vec![];
         if let Some(nearby_blocks) = self.world.get_blocks_nearby(&self.player) {
             for block in nearby_blocks.iter() {
                 let block = block.read().unwrap();
-                let collision = CollisionBox::from_block_position(
-                    block.absolute_position.x,
-                    block.position.y,
-                    block.absolute_position.z,
+                let shape = block.get_shape();
+
+                collisions.extend(
+                    shape
+                        .get_collision_boxes()
+                        .iter()
+                        .map(|b| b.to_collision_box(block.absolute_position)),
+                );
+                selection_boxes.extend(
+                    shape
+                        .get_selection_boxes()
+                        .iter()
+                        .map(|b| b.to_collision_box(block.absolute_position)),
                 );
-                collisions.push(collision);
             }
         };
         self.player.move_camera(
@@ -257,8 +667,10 @@ impl State {
             delta_time,
             &collisions,
         );
-        if let Some((block, face_dir)) = self.player.get_facing_block(&collisions) {
-            let block = self.world.get_blocks_absolute(&block.to_block_position());
+        if let Some((block, face_dir)) = self.player.get_facing_block(&selection_boxes) {
+            let block = self
+                .world
+                .get_blocks_absolute(&block.to_block_position().floor());
 
             self.player.facing_block = block;
             self.player.facing_face = Some(face_dir);
@@ -266,22 +678,44 @@ impl State {
             self.player.facing_block = None;
             self.player.facing_face = None;
         }
+        if let Some(mining) = self.player.mining.as_mut() {
+            let target = self.player.facing_block.as_ref().map(|block| {
+                let block = block.read().unwrap();
+                (
+                    block.absolute_position,
+                    block.block_type.get_config().break_time,
+                )
+            });
+            if mining.update(target, delta_time) {
+                let block = self.player.facing_block.clone().unwrap();
+                self.world.remove_block(block);
+            }
+        }
 
         let uniforms = Uniforms::from(&self.player.camera);
+        self.world.clock.update(delta_time);
+        let environment = EnvironmentUniforms {
+            time: total_time,
+            ..EnvironmentUniforms::from(&self.world.clock)
+        };
 
-        for pipeline in self.pipelines.iter() {
+        for pipeline in self.pipelines.values() {
             self.queue.write_buffer(
                 pipeline.view_buffer(),
                 0,
                 bytemuck::cast_slice(&[uniforms.view]),
-            )
+            );
+            if let Some(environment_buffer) = pipeline.environment_buffer() {
+                self.queue.write_buffer(
+                    environment_buffer,
+                    0,
+                    bytemuck::cast_slice(&[environment]),
+                );
+            }
         }
 
-        self.world.update(
-            &mut self.player,
-            Arc::clone(&self.queue),
-            Arc::clone(&self.device),
-        );
+        self.world.update(&mut self.player);
+        self.clouds.update(&self.device, self.config.clouds);
         self.ui.update(
             &mut self.player,
             Arc::clone(&self.queue),
@@ -289,13 +723,20 @@ impl State {
         );
     }
     pub fn draw(&mut self) {
-        let frame = self
-            .surface
-            .get_current_texture()
-            .expect("Failed to acquire next swapchain texture");
-        let view = frame
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
+        let output = match &self.target {
+            RenderTarget::Window { surface, .. } => Some(
+                surface
+                    .get_current_texture()
+                    .expect("Failed to acquire next swapchain texture"),
+            ),
+            RenderTarget::Offscreen(_) => None,
+        };
+        let target_texture = match (&output, &self.target) {
+            (Some(output), _) => &output.texture,
+            (None, RenderTarget::Offscreen(texture)) => texture,
+            (None, RenderTarget::Window { .. }) => unreachable!(),
+        };
+        let view = target_texture.create_view(&wgpu::TextureViewDescriptor::default());
 
         let mut encoder = self
             .device
@@ -309,119 +750,219 @@ impl State {
             .iter()
             .map(|f| f.read().unwrap())
             .collect::<Vec<_>>();
+        let sky_color = self.world.clock.sky_color();
+
+        let frustum = Frustum::from_camera(&self.player.camera);
+        let chunk_refs = chunks.iter().map(|c| &**c).collect::<Vec<_>>();
+        let reachable_sections =
+            find_reachable_sections(&chunk_refs, self.player.camera.eye, &frustum);
+        let mut culling_stats = CullingStats::default();
+        // (chunk, section index) of the sections with geometry inside of the view
+        let mut visible_sections = vec![];
+        for (chunk_index, chunk) in chunks.iter().enumerate() {
+            let sections = chunk
+                .sections
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| s.opaque.indices > 0 || s.translucent.indices > 0)
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
+            culling_stats.chunks_total += 1;
+            culling_stats.sections_total += sections.len() as u32;
+
+            if !frustum.intersects(&chunk.aabb) {
+                culling_stats.chunks_culled += 1;
+                culling_stats.sections_culled += sections.len() as u32;
+                continue;
+            }
+            for section in sections {
+                if !frustum.intersects(&chunk.get_section_aabb(section)) {
+                    culling_stats.sections_culled += 1;
+                } else if reachable_sections
+                    .as_ref()
+                    .is_some_and(|r| !r.contains(&(chunk_index, section)))
+                {
+                    culling_stats.sections_culled += 1;
+                    culling_stats.sections_occluded += 1;
+                } else {
+                    visible_sections.push((chunk, section));
+                }
+            }
+        }
+        self.culling_stats = culling_stats;
 
-        {
-            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
-                        }),
-                        store: wgpu::StoreOp::Store,
-                    },
-                })],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.pipelines[0].depth_texture().view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: wgpu::StoreOp::Store,
-                    }),
-                    stencil_ops: None,
-                }),
-                timestamp_writes: None,
-                occlusion_query_set: None,
-            });
-            let pipeline = &self.pipelines[0];
+        let opaque_draws = visible_sections
+            .iter()
+            .map(|(chunk, section)| (&chunk.sections[*section].opaque, chunk.slot))
+            // The far away terrain, past the loaded chunks
+            .chain(
+                self.world
+                    .lod_chunks
+                    .iter()
+                    .filter(|lod_chunk| frustum.intersects(&lod_chunk.aabb))
+                    .map(|lod_chunk| (&lod_chunk.mesh, lod_chunk.slot)),
+            )
+            .filter(|(mesh, _)| mesh.indices > 0)
+            .map(|(mesh, slot)| MeshArena::draw_command(mesh, slot))
+            .collect::<Vec<_>>();
 
-            rpass.set_pipeline(pipeline.pipeline());
+        // Translucent sections are drawn back to front so they blend correctly with each other
+        let eye = self.player.camera.eye;
+        let mut translucent_sections = visible_sections
+            .iter()
+            .filter(|(chunk, section)| chunk.sections[*section].translucent.indices > 0)
+            .map(|(chunk, section)| {
+                let distance = chunk.get_section_center(*section).distance_squared(eye);
+                (chunk, &chunk.sections[*section], distance)
+            })
+            .collect::<Vec<_>>();
+        translucent_sections.sort_by(|a, b| b.2.total_cmp(&a.2));
+        let translucent_draws = translucent_sections
+            .iter()
+            .map(|(chunk, section, _)| MeshArena::draw_command(&section.translucent, chunk.slot))
+            .collect::<Vec<_>>();
 
-            rpass.set_bind_group(0, pipeline.bind_group_0(), &[]);
-            rpass.set_bind_group(1, pipeline.bind_group_1(), &[]);
+        // Blocks casting a shadow can be outside of the view, so they are culled with the sun's box
+        let light_matrix =
+            shadow::light_matrix(&self.player.camera, self.world.clock.sun_direction());
+        let light_frustum = Frustum::from_matrix(light_matrix);
+        let shadow_draws = chunks
+            .iter()
+            .filter(|chunk| light_frustum.intersects(&chunk.aabb))
+            .flat_map(|chunk| {
+                chunk
+                    .sections
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, s)| {
+                        s.opaque.indices > 0
+                            && light_frustum.intersects(&chunk.get_section_aabb(*i))
+                    })
+                    .map(|(_, s)| MeshArena::draw_command(&s.opaque, chunk.slot))
+            })
+            .chain(
+                self.world
+                    .lod_chunks
+                    .iter()
+                    .filter(|lod_chunk| {
+                        lod_chunk.mesh.indices > 0 && light_frustum.intersects(&lod_chunk.aabb)
+                    })
+                    .map(|lod_chunk| MeshArena::draw_command(&lod_chunk.mesh, lod_chunk.slot)),
+            )
+            .collect::<Vec<_>>();
 
-            for chunk in chunks.iter() {
-                rpass.set_bind_group(2, &chunk.chunk_bind_group, &[]);
-                rpass.set_vertex_buffer(
-                    0,
-                    chunk
-                        .chunk_vertex_buffer
-                        .as_ref()
-                        .expect("Vertex buffer not initiated")
-                        .slice(..),
-                );
-                rpass.set_index_buffer(
-                    chunk
-                        .chunk_index_buffer
-                        .as_ref()
-                        .expect("Index buffer not initiated")
-                        .slice(..),
-                    wgpu::IndexFormat::Uint32,
-                );
-                rpass.draw_indexed(0..chunk.indices, 0, 0..1);
-            }
-        }
-        {
-            let mut ui_renderpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Load,
-                        store: wgpu::StoreOp::Store,
-                    },
-                })],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.pipelines[0].depth_texture().view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: wgpu::StoreOp::Store,
-                    }),
-                    stencil_ops: None,
-                }),
-                timestamp_writes: None,
-                occlusion_query_set: None,
-            });
+        let draw_offsets =
+            self.world
+                .arena
+                .write_draws(&[&opaque_draws, &translucent_draws, &shadow_draws]);
+
+        let world_pipeline = &self.pipelines[&PipelineType::WORLD];
+        let shadow_map = world_pipeline
+            .shadow_map()
+            .expect("The world pipeline has no shadow map");
+        self.queue.write_buffer(
+            &shadow_map.light_buffer,
+            0,
+            bytemuck::cast_slice(&[light_matrix.to_cols_array()]),
+        );
 
-            let pipeline = &self.pipelines[1];
-            ui_renderpass.set_pipeline(pipeline.pipeline());
+        let frame = Frame {
+            state: self,
+            opaque_draws: &opaque_draws,
+            translucent_draws: &translucent_draws,
+            shadow_draws: &shadow_draws,
+            draw_offsets: &draw_offsets,
+            clear_color: wgpu::Color {
+                r: sky_color.x as f64,
+                g: sky_color.y as f64,
+                b: sky_color.z as f64,
+                a: 1.0,
+            },
+        };
+        self.render_graph.execute(
+            &mut encoder,
+            &[(SURFACE, &view), ("shadow_map", &shadow_map.texture.view)],
+            &frame,
+        );
 
-            ui_renderpass.set_bind_group(0, pipeline.bind_group_0(), &[]);
-            ui_renderpass.set_bind_group(1, pipeline.bind_group_1(), &[]);
+        self.queue.submit(Some(encoder.finish()));
 
-            ui_renderpass.set_vertex_buffer(0, self.ui.vertex_buffer.slice(..));
-            ui_renderpass
-                .set_index_buffer(self.ui.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-            ui_renderpass.draw_indexed(0..self.ui.indices as u32, 0, 0..1);
+        if let Some(path) = self.screenshot.take() {
+            if let Err(e) = Self::save_screenshot(&self.device, &self.queue, target_texture, &path)
+            {
+                log::error!("Failed to save the screenshot {}: {e}", path.display());
+            } else {
+                log::info!("Saved a screenshot to {}", path.display());
+            }
         }
-
-        self.queue.submit(Some(encoder.finish()));
-        frame.present();
+        if let Some(output) = output {
+            output.present();
+        }
+    }
+    fn save_screenshot(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+        path: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let image = screenshot::capture(device, queue, texture)?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        image.save(path)?;
+        Ok(())
     }
+    // Draws a frame offscreen and reads it back, for the headless renderer
+    pub fn render_image(&mut self) -> Result<image::RgbaImage, String> {
+        self.update(0.0, 0.0);
+        self.draw();
+        match &self.target {
+            RenderTarget::Offscreen(texture) => {
+                screenshot::capture(&self.device, &self.queue, texture)
+            }
+            RenderTarget::Window { .. } => Err(String::from("Not an offscreen renderer")),
+        }
+    }
+}
+
+// What the frames are drawn to
+pub enum RenderTarget {
+    Window {
+        window: Arc<Mutex<Window>>,
+        surface: wgpu::Surface,
+    },
+    // Headless, see State::new_headless
+    Offscreen(wgpu::Texture),
 }
 
 pub struct Config {
     pub polygon_mode: wgpu::PolygonMode,
+    // Filtering of the block textures
+    pub block_sampler: SamplerSettings,
+    pub post: PostSettings,
+    pub clouds: CloudSettings,
 }
 
 pub struct State {
-    pub surface: wgpu::Surface,
+    pub target: RenderTarget,
     pub instance: wgpu::Instance,
     pub adapter: wgpu::Adapter,
     pub device: Arc<wgpu::Device>,
     pub queue: Arc<wgpu::Queue>,
-    pub window: Arc<Mutex<Window>>,
+    // Where the next frame is saved to once it's drawn
+    pub screenshot: Option<PathBuf>,
     pub surface_config: wgpu::SurfaceConfiguration,
-    pub pipelines: Vec<Box<dyn PipelineTrait>>,
+    pub pipelines: HashMap<PipelineType, Box<dyn PipelineTrait>>,
+    pub render_graph: RenderGraph,
     pub player: Player,
     pub world: World,
     pub ui: UI,
+    pub clouds: Clouds,
     pub config: Config,
+    pub resources: ResourcePacks,
+    pub pipeline_manager: PipelineManager,
     pub camera_controller: CameraController,
+    // Chunks and sections skipped by the last frame
+    pub culling_stats: CullingStats,
     // pub model: Rc<RefCell<Model>>,
 }
@@ -1,86 +1,107 @@
-use crate::blocks::block::FaceDirections;
+use crate::blocks::block_shape::ShapeBox;
 use crate::material::Texture;
 use crate::pipeline::{PipelineTrait, PipelineType, Uniforms};
 use crate::player::Player;
+use crate::post::HDR_FORMAT;
+use crate::render_graph::{Frame, RenderNode};
 use crate::state::State;
-use std::collections::HashMap;
+use bytemuck::{Pod, Zeroable};
+use std::ops::Range;
 use std::sync::Arc;
 use wgpu::util::DeviceExt;
 use wgpu::{BindGroup, Buffer, RenderPipeline};
 
+// Distance between the selection box and the block, so that they don't z-fight
+const SELECTION_MARGIN: f32 = 0.002;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable)]
+pub struct UIVertex {
+    pub position: [f32; 3],
+    pub uv: [f32; 2],
+    // Frame of the crack texture array
+    pub layer: u32,
+}
+
+// Outline of the facing block, and the crack over it while it's being broken
 pub struct UI {
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
-    pub indices: usize,
+    // Line list
+    pub outline_indices: Range<u32>,
+    // Triangle list
+    pub crack_indices: Range<u32>,
+    // Stages of the crack, from the texture of the UI pipeline
+    pub crack_frames: u32,
 }
 
 impl UI {
-    pub fn new(device: Arc<wgpu::Device>, queue: Arc<wgpu::Queue>) -> Self {
-        let vertices: [[f32; 3]; 4] = [
-            [0.0, 0.0, 0.0],
-            [0.0, 1.0, 0.0],
-            [1.0, 0.0, 0.0],
-            [1.0, 1.0, 0.0],
-        ];
-        let indices: [u32; 6] = [0, 1, 2, 2, 1, 3];
-
-        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+    pub fn new(device: Arc<wgpu::Device>) -> Self {
+        let (vertex_buffer, index_buffer) = Self::create_buffers(&device, &[], &[]);
+        Self {
+            vertex_buffer,
+            index_buffer,
+            outline_indices: 0..0,
+            crack_indices: 0..0,
+            crack_frames: 1,
+        }
+    }
+    fn create_buffers(
+        device: &wgpu::Device,
+        vertices: &[UIVertex],
+        indices: &[u32],
+    ) -> (wgpu::Buffer, wgpu::Buffer) {
+        // A block has a few boxes at most, so the buffers rarely grow
+        let vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("UI Vertex Buffer"),
+            size: (std::mem::size_of_val(vertices) as u64).max(1024),
             usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-            contents: bytemuck::cast_slice(&vertices),
+            mapped_at_creation: false,
         });
-        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("UI Vertex Buffer"),
+        let index_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("UI Index Buffer"),
+            size: (std::mem::size_of_val(indices) as u64).max(1024),
             usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
-            contents: bytemuck::cast_slice(&indices),
+            mapped_at_creation: false,
         });
-
-        Self {
-            indices: 0,
-            vertex_buffer,
-            index_buffer,
-        }
+        (vertex_buffer, index_buffer)
     }
     pub fn update(&mut self, player: &Player, queue: Arc<wgpu::Queue>, device: Arc<wgpu::Device>) {
-        if let Some(block_ptr) = player.facing_block.as_ref() {
-            let block = block_ptr.read().unwrap();
-
-            let face_data = FaceDirections::all()
-                .iter()
-                .find(|f| **f == player.facing_face.unwrap())
-                .unwrap()
-                .create_face_data(block_ptr.clone(), &vec![]);
-
-            let blocks_position = face_data
-                .0
-                .iter()
-                .map(|v| {
-                    [
-                        // TODO: This is kinda ugly
-                        v.position[0] + (block.absolute_position.x - block.position.x),
-                        v.position[1] + (block.absolute_position.y - block.position.y),
-                        v.position[2] + (block.absolute_position.z - block.position.z),
-                    ]
-                })
-                .collect::<Vec<_>>();
-
-            self.indices = face_data.1.len();
-            queue.write_buffer(
-                &self.vertex_buffer,
-                0,
-                bytemuck::cast_slice(&blocks_position),
-            );
-            queue.write_buffer(&self.index_buffer, 0, bytemuck::cast_slice(&face_data.1));
-        } else {
-            self.indices = 0;
-            queue.write_buffer(&self.vertex_buffer, 0, &[]);
-            queue.write_buffer(&self.index_buffer, 0, &[]);
+        let Some(block) = player.facing_block.as_ref() else {
+            self.outline_indices = 0..0;
+            self.crack_indices = 0..0;
+            return;
+        };
+        let block = block.read().unwrap();
+        let crack_layer = player
+            .mining
+            .as_ref()
+            .filter(|mining| mining.is_held && mining.block.is_some())
+            .map(|mining| {
+                ((mining.progress * self.crack_frames as f32) as u32).min(self.crack_frames - 1)
+            });
+        // The meshes are centered on the block positions
+        let origin = block.absolute_position - 0.5;
+        let (vertices, indices, outline_indices, crack_indices) =
+            selection_geometry(origin, block.get_shape().get_selection_boxes(), crack_layer);
+
+        let vertex_data = bytemuck::cast_slice(&vertices);
+        let index_data = bytemuck::cast_slice(&indices);
+        if vertex_data.len() as u64 > self.vertex_buffer.size()
+            || index_data.len() as u64 > self.index_buffer.size()
+        {
+            (self.vertex_buffer, self.index_buffer) =
+                Self::create_buffers(&device, &vertices, &indices);
         }
+        queue.write_buffer(&self.vertex_buffer, 0, vertex_data);
+        queue.write_buffer(&self.index_buffer, 0, index_data);
+        self.outline_indices = outline_indices;
+        self.crack_indices = crack_indices;
     }
 
     pub fn get_vertex_data_layout() -> wgpu::VertexBufferLayout<'static> {
         wgpu::VertexBufferLayout {
-            array_stride: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+            array_stride: std::mem::size_of::<UIVertex>() as wgpu::BufferAddress,
             step_mode: wgpu::VertexStepMode::Vertex,
             attributes: &[
                 // Position
@@ -89,24 +110,130 @@ impl UI {
                     offset: 0,
                     shader_location: 0,
                 },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x2,
+                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Uint32,
+                    offset: std::mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                },
             ],
         }
     }
 }
+
+/* Edges of every box of the block, then the faces of the boxes textured with the crack.
+ * origin: corner of the block, the boxes are relative to it.
+ * Returns the vertices, the indices and the ranges of the outline and crack indices
+ */
+fn selection_geometry(
+    origin: glam::Vec3,
+    boxes: &[ShapeBox],
+    crack_layer: Option<u32>,
+) -> (Vec<UIVertex>, Vec<u32>, Range<u32>, Range<u32>) {
+    let mut vertices = vec![];
+    let mut indices = vec![];
+    // Corner i of a box has the max coordinate on the axes of its bits: x = 1, y = 2, z = 4
+    let corner = |shape_box: &ShapeBox, i: u32| {
+        let min = shape_box.min - SELECTION_MARGIN;
+        let max = shape_box.max + SELECTION_MARGIN;
+        glam::vec3(
+            if i & 1 == 0 { min.x } else { max.x },
+            if i & 2 == 0 { min.y } else { max.y },
+            if i & 4 == 0 { min.z } else { max.z },
+        )
+    };
+
+    for shape_box in boxes {
+        let first = vertices.len() as u32;
+        vertices.extend((0..8).map(|i| UIVertex {
+            position: (origin + corner(shape_box, i)).to_array(),
+            uv: [0.0, 0.0],
+            layer: 0,
+        }));
+        // Each corner is linked to the ones with one more max coordinate
+        for i in 0..8 {
+            for bit in [1, 2, 4] {
+                if i & bit == 0 {
+                    indices.extend([first + i, first + (i | bit)]);
+                }
+            }
+        }
+    }
+    let outline_indices = 0..indices.len() as u32;
+
+    if let Some(layer) = crack_layer {
+        for shape_box in boxes {
+            // (bit of the face's axis, bits of the texture's u and v axes)
+            for (axis, u, v) in [(1, 4, 2), (2, 1, 4), (4, 1, 2)] {
+                for side in [0, axis] {
+                    let first = vertices.len() as u32;
+                    for (du, dv) in [(0, 0), (u, 0), (u, v), (0, v)] {
+                        let position = corner(shape_box, side | du | dv);
+                        let coordinate = |bit| match bit {
+                            1 => position.x,
+                            2 => 1.0 - position.y,
+                            _ => position.z,
+                        };
+                        vertices.push(UIVertex {
+                            position: (origin + position).to_array(),
+                            uv: [coordinate(u), coordinate(v)],
+                            layer,
+                        });
+                    }
+                    indices.extend([0, 1, 2, 0, 2, 3].map(|i| first + i));
+                }
+            }
+        }
+    }
+    let crack_indices = outline_indices.end..indices.len() as u32;
+    (vertices, indices, outline_indices, crack_indices)
+}
+
+// Node of the render graph drawing the selection on top of the world, before the post-process
+pub fn draw_ui_pass<'a>(
+    _node: &'a RenderNode,
+    pipeline: &'a dyn PipelineTrait,
+    frame: &'a Frame<'a>,
+    rpass: &mut wgpu::RenderPass<'a>,
+) {
+    let ui = &frame.state.ui;
+    if ui.outline_indices.is_empty() {
+        return;
+    }
+    rpass.set_pipeline(pipeline.pipeline());
+    rpass.set_bind_group(0, pipeline.bind_group_0(), &[]);
+    rpass.set_bind_group(1, pipeline.bind_group_1(), &[]);
+    rpass.set_vertex_buffer(0, ui.vertex_buffer.slice(..));
+    rpass.set_index_buffer(ui.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+    rpass.draw_indexed(ui.outline_indices.clone(), 0, 0..1);
+
+    if let Some(crack_pipeline) = pipeline.translucent_pipeline() {
+        if !ui.crack_indices.is_empty() {
+            rpass.set_pipeline(crack_pipeline);
+            rpass.draw_indexed(ui.crack_indices.clone(), 0, 0..1);
+        }
+    }
+}
+
 pub struct UIPipeline {
     pub projection_buffer: wgpu::Buffer,
     pub view_buffer: wgpu::Buffer,
     pub pipeline: wgpu::RenderPipeline,
+    // Faces of the facing block textured with the crack
+    pub crack_pipeline: wgpu::RenderPipeline,
+    // Stages of the crack, a layer each in bind_group_1
+    pub crack_frames: u32,
     pub bind_group_0: wgpu::BindGroup,
     pub bind_group_1: wgpu::BindGroup,
     pub pipeline_type: PipelineType,
 }
 impl UIPipeline {
     pub fn new(state: &State) -> Self {
-        let swapchain_capabilities = state.surface.get_capabilities(&state.adapter);
-        let swapchain_format = swapchain_capabilities.formats[0];
-
-        let shader_source = std::fs::read_to_string("src/shaders/ui_shader.wgsl").unwrap();
+        let shader_source = state.resources.shader("ui_shader.wgsl");
 
         let shader = state
             .device
@@ -179,13 +306,17 @@ impl UIPipeline {
             ],
         });
 
-        let texture_atlas = Texture::from_path(
-            "assets/tex_atlas.png",
-            "tex_atlas".to_string(),
+        // The frames are stacked from top to bottom, a layer each
+        let crack = state.resources.overlay("crack");
+        let crack_frames = (crack.height() / crack.width()).max(1);
+        let crack_texture = Texture::block_array(
+            &crack,
+            1,
+            crack_frames,
+            &state.config.block_sampler,
             &state.device,
             &state.queue,
-        )
-        .unwrap();
+        );
 
         let bind_group_1_layout =
             state
@@ -198,7 +329,7 @@ impl UIPipeline {
                             visibility: wgpu::ShaderStages::FRAGMENT,
                             ty: wgpu::BindingType::Texture {
                                 sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                                view_dimension: wgpu::TextureViewDimension::D2,
+                                view_dimension: wgpu::TextureViewDimension::D2Array,
                                 multisampled: false,
                             },
                             count: None,
@@ -218,11 +349,11 @@ impl UIPipeline {
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&texture_atlas.view),
+                    resource: wgpu::BindingResource::TextureView(&crack_texture.view),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&texture_atlas.sampler),
+                    resource: wgpu::BindingResource::Sampler(&crack_texture.sampler),
                 },
             ],
         });
@@ -237,11 +368,12 @@ impl UIPipeline {
                     push_constant_ranges: &[],
                 });
 
-        let render_pipeline =
+        // Both draw over the world without hiding what is behind them
+        let create_pipeline = |entry_point: &str, topology: wgpu::PrimitiveTopology| {
             state
                 .device
                 .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                    label: None,
+                    label: Some(entry_point),
                     layout: Some(&pipeline_layout),
                     vertex: wgpu::VertexState {
                         module: &shader,
@@ -250,31 +382,36 @@ impl UIPipeline {
                     },
                     fragment: Some(wgpu::FragmentState {
                         module: &shader,
-                        entry_point: "fs_main",
+                        entry_point,
                         targets: &[Some(wgpu::ColorTargetState {
-                            format: swapchain_format,
+                            format: HDR_FORMAT,
                             blend: Some(wgpu::BlendState::ALPHA_BLENDING),
                             write_mask: wgpu::ColorWrites::ALL,
                         })],
                     }),
 
                     primitive: wgpu::PrimitiveState {
-                        polygon_mode: state.config.polygon_mode,
+                        topology,
                         cull_mode: None,
-
                         ..Default::default()
                     },
                     depth_stencil: Some(wgpu::DepthStencilState {
                         format: Texture::DEPTH_FORMAT,
-                        depth_write_enabled: true,
-                        depth_compare: wgpu::CompareFunction::Less,
+                        depth_write_enabled: false,
+                        depth_compare: wgpu::CompareFunction::LessEqual,
                         stencil: wgpu::StencilState::default(),
                         bias: wgpu::DepthBiasState::default(),
                     }),
-                    multisample: wgpu::MultisampleState::default(),
+                    multisample: wgpu::MultisampleState {
+                        count: state.config.post.anti_aliasing.samples(),
+                        ..Default::default()
+                    },
 
                     multiview: None,
-                });
+                })
+        };
+        let render_pipeline = create_pipeline("fs_outline", wgpu::PrimitiveTopology::LineList);
+        let crack_pipeline = create_pipeline("fs_crack", wgpu::PrimitiveTopology::TriangleList);
 
         Self {
             view_buffer,
@@ -283,6 +420,8 @@ impl UIPipeline {
             bind_group_0,
             bind_group_1,
             pipeline: render_pipeline,
+            crack_pipeline,
+            crack_frames,
         }
     }
 }
@@ -295,6 +434,10 @@ impl PipelineTrait for UIPipeline {
         &self.pipeline
     }
 
+    fn translucent_pipeline(&self) -> Option<&RenderPipeline> {
+        Some(&self.crack_pipeline)
+    }
+
     fn view_buffer(&self) -> &Buffer {
         &self.view_buffer
     }
@@ -307,15 +450,30 @@ impl PipelineTrait for UIPipeline {
         &self.bind_group_1
     }
 
-    fn depth_texture(&self) -> &Texture {
-        todo!()
+    fn get_type(&self) -> PipelineType {
+        self.pipeline_type
     }
+}
 
-    fn set_depth_texture(&mut self, texture: Texture) {
-        todo!()
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    fn get_type(&self) -> PipelineType {
-        self.pipeline_type
+    #[test]
+    fn should_outline_every_box_and_crack_only_while_mining() {
+        let boxes = [ShapeBox::new(0, 0, 0, 16, 8, 16), ShapeBox::FULL];
+        let (vertices, indices, outline, crack) =
+            selection_geometry(glam::Vec3::ZERO, &boxes, None);
+        // 12 edges per box
+        assert_eq!(outline, 0..48);
+        assert!(crack.is_empty());
+        assert_eq!(vertices.len(), 16);
+        assert_eq!(indices.len(), 48);
+
+        let (_, indices, outline, crack) = selection_geometry(glam::Vec3::ZERO, &boxes, Some(0));
+        // 6 faces of 2 triangles per box
+        assert_eq!(outline, 0..48);
+        assert_eq!(crack, 48..120);
+        assert_eq!(indices.len(), 120);
     }
 }
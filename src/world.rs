@@ -1,12 +1,18 @@
 use glam::Vec3;
 use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
 use std::sync::RwLock;
 use std::{
     sync::{mpsc, Arc},
     thread,
 };
 
-use crate::persistence::Saveable;
+use crate::arena::MeshArena;
+use crate::chunk::{ChunkSection, SECTIONS_PER_CHUNK};
+use crate::clock::WorldClock;
+use crate::light::LightWorld;
+use crate::lod::{build_lod_mesh, LodChunk, LodLevel, LOD_RADIUS};
+use crate::persistence::{Loadable, Saveable};
 use crate::utils::{ChunkFromPosition, RelativeFromAbsolute};
 use crate::{blocks::block::Block, chunk::Chunk, player::Player, utils::threadpool::ThreadPool};
 
@@ -36,10 +42,16 @@ pub type NoiseData = Vec<f32>;
 pub type WorldChunk = Arc<RwLock<Chunk>>;
 pub struct World {
     pub chunks: Vec<WorldChunk>,
+    // Far away chunks that are only drawn as a simplified mesh
+    pub lod_chunks: Vec<LodChunk>,
     pub thread_pool: Option<ThreadPool>,
     pub seed: u32,
+    pub clock: WorldClock,
     pub noise_data: Arc<NoiseData>,
-    pub chunk_data_layout: Arc<wgpu::BindGroupLayout>,
+    // Folder the chunks and the clock are loaded from and saved to
+    pub data_dir: PathBuf,
+    // Holds the meshes of all the chunks
+    pub arena: MeshArena,
     pub device: Arc<wgpu::Device>,
     pub queue: Arc<wgpu::Queue>,
 }
@@ -73,9 +85,10 @@ impl World {
             .collect()
     }
     pub fn place_block(&mut self, block: Arc<RwLock<Block>>) {
-        let mut chunks_to_rerender = vec![];
-
         let block_borrow = block.read().unwrap();
+        if block_borrow.position.y > CHUNK_HEIGHT as f32 {
+            return;
+        }
         let chunk_coords = block_borrow.get_chunk_coords();
         let chunk = self
             .chunks
@@ -84,36 +97,14 @@ impl World {
                 let c = c.read().unwrap();
                 c.x == chunk_coords.0 && c.y == chunk_coords.1
             })
-            .expect("Cannot delete a block from unloaded chunk");
-
-        chunks_to_rerender.push(chunk.clone());
-        let mut chunk_lock = chunk.write().unwrap();
-        chunk_lock.add_block(block.clone());
-
-        let block_borrow = block.read().unwrap();
-        let block_neighbour_chunks = block_borrow.get_neighbour_chunks_coords();
-        std::mem::drop(chunk_lock);
-
-        if block_neighbour_chunks.len() > 0 {
-            for neighbour_chunk in block_neighbour_chunks {
-                let neighbour_chunk = self
-                    .chunks
-                    .iter()
-                    .find(|o| {
-                        let c = o.read().unwrap();
-                        return c.x == neighbour_chunk.0 && c.y == neighbour_chunk.1;
-                    })
-                    .expect("Cannot destroy a block without neighbour being loaded");
+            .expect("Cannot place a block in an unloaded chunk");
 
-                chunks_to_rerender.push(neighbour_chunk.clone());
-            }
-        }
+        chunk.write().unwrap().add_block(block.clone());
 
-        self.render_chunks(&chunks_to_rerender);
+        let sections_to_rerender = self.update_light(&block_borrow);
+        self.render_sections(&sections_to_rerender);
     }
     pub fn remove_block(&mut self, block: Arc<RwLock<Block>>) {
-        let mut chunks_to_rerender = vec![];
-
         let block_borrow = block.read().unwrap();
         let chunk_coords = block_borrow.get_chunk_coords();
         let chunk = self
@@ -125,29 +116,54 @@ impl World {
             })
             .expect("Cannot delete a block from unloaded chunk");
 
-        let mut chunk_lock = chunk.write().unwrap();
-        chunk_lock.remove_block(&(block_borrow.position));
-        chunks_to_rerender.push(chunk.clone());
-        // chunk_lock.build_mesh(self.get_other_chunks(chunk.clone()));
-        let block_neighbour_chunks = block_borrow.get_neighbour_chunks_coords();
-        // I hate this so much
-        std::mem::drop(chunk_lock);
-
-        if block_neighbour_chunks.len() > 0 {
-            for neighbour_chunk in block_neighbour_chunks {
-                let neighbour_chunk = self
-                    .chunks
-                    .iter()
-                    .find(|o| {
-                        let c = o.read().unwrap();
-                        c.x == neighbour_chunk.0 && c.y == neighbour_chunk.1
-                    })
-                    .expect("Cannot destroy a block without neighbour being loaded");
+        chunk
+            .write()
+            .unwrap()
+            .remove_block(&(block_borrow.position));
 
-                chunks_to_rerender.push(neighbour_chunk.clone());
+        let sections_to_rerender = self.update_light(&block_borrow);
+        self.render_sections(&sections_to_rerender);
+    }
+    pub fn create_light_world(&self) -> LightWorld {
+        LightWorld::new(
+            self.chunks
+                .iter()
+                .map(|chunk| {
+                    let chunk = chunk.read().unwrap();
+                    (
+                        (chunk.x, chunk.y),
+                        chunk.blocks.clone(),
+                        chunk.light.clone(),
+                    )
+                })
+                .collect(),
+        )
+    }
+    // Relights around a placed or removed block, returns the sections that need to be re-rendered
+    fn update_light(&self, block: &Block) -> Vec<(WorldChunk, usize)> {
+        let light_world = self.create_light_world();
+        light_world.update_block(&block.absolute_position);
+
+        let mut sections = block.get_neighbour_sections();
+        for section in light_world.take_changed_sections() {
+            if !sections.contains(&section) {
+                sections.push(section);
             }
         }
-        self.render_chunks(&chunks_to_rerender);
+        self.get_loaded_sections(&sections)
+    }
+    // Maps (chunk coords, section) pairs to the loaded chunks, skipping the unloaded ones
+    fn get_loaded_sections(&self, sections: &[((i32, i32), usize)]) -> Vec<(WorldChunk, usize)> {
+        sections
+            .iter()
+            .filter_map(|(chunk_coords, section)| {
+                let chunk = self.chunks.iter().find(|c| {
+                    let c = c.read().unwrap();
+                    c.x == chunk_coords.0 && c.y == chunk_coords.1
+                })?;
+                Some((chunk.clone(), *section))
+            })
+            .collect()
     }
     pub fn get_blocks_absolute(&self, position: &Vec3) -> Option<Arc<RwLock<Block>>> {
         let (chunk_x, chunk_y) = position.get_chunk_from_position_absolute();
@@ -183,12 +199,7 @@ impl World {
 
         return Some(nearby_blocks);
     }
-    pub fn update(
-        &mut self,
-        player: &mut Player,
-        queue: Arc<wgpu::Queue>,
-        device: Arc<wgpu::Device>,
-    ) {
+    pub fn update(&mut self, player: &mut Player) {
         let current_chunk = player.calc_current_chunk();
         if current_chunk != player.current_chunk {
             let delta = (
@@ -251,9 +262,17 @@ impl World {
             let (sender, receiver) = mpsc::channel();
             for (o, index) in indices_to_remove.iter().enumerate() {
                 let chunk = self.chunks.remove(index - o);
+                {
+                    let chunk = chunk.read().unwrap();
+                    for section in chunk.sections.iter() {
+                        section.free(&mut self.arena);
+                    }
+                    self.arena.free_chunk(chunk.slot);
+                }
                 let sender = sender.clone();
+                let data_dir = self.data_dir.clone();
                 self.thread_pool.as_ref().unwrap().execute(move || {
-                    chunk.write().unwrap().save().unwrap();
+                    chunk.write().unwrap().save(&data_dir).unwrap();
                     sender.send(()).unwrap();
                 })
             }
@@ -269,30 +288,48 @@ impl World {
                 let new_chunk_pos = new_chunks_positions[i];
                 let sender = sender.clone();
                 let noise_data = Arc::clone(&self.noise_data);
-                let chunk_data_layout = Arc::clone(&self.chunk_data_layout);
-                let device = Arc::clone(&device);
-                let queue = Arc::clone(&queue);
+                let data_dir = self.data_dir.clone();
 
                 self.thread_pool.as_ref().unwrap().execute(move || {
-                    let chunk = Chunk::new(
-                        new_chunk_pos.0,
-                        new_chunk_pos.1,
-                        noise_data,
-                        device,
-                        queue,
-                        chunk_data_layout,
-                    );
+                    let chunk = Chunk::new(new_chunk_pos.0, new_chunk_pos.1, noise_data, &data_dir);
                     sender.send(chunk).unwrap()
                 })
             }
 
             for _ in 0..chunks_added {
-                let chunk = receiver.recv().unwrap();
+                let mut chunk = receiver.recv().unwrap();
+                chunk.slot = self.arena.allocate_chunk((chunk.x, chunk.y));
                 self.chunks.push(Arc::new(RwLock::new(chunk)));
             }
-            self.handle_outside_blocks();
-            self.render_chunks(&self.chunks[self.chunks.len() - chunks_added..]);
+            let outside_blocks = self.handle_outside_blocks();
+            let new_chunks = self.chunks[self.chunks.len() - chunks_added..].to_vec();
+            let light_world = self.create_light_world();
+            light_world.propagate_between_chunks(&Self::get_chunks_coords(&new_chunks));
+            // The trees of the new chunks can grow into the chunks that are already lit
+            let mut sections = vec![];
+            for block in outside_blocks.iter() {
+                let block = block.read().unwrap();
+                light_world.update_block(&block.absolute_position);
+                sections.extend(block.get_neighbour_sections());
+            }
             // Re-render only the last inserted chunks
+            self.render_chunks(&new_chunks);
+
+            // Light coming from the new chunks can change the sections next to them
+            sections.extend(light_world.take_changed_sections());
+            let mut unique_sections = vec![];
+            for section in sections {
+                if !unique_sections.contains(&section) {
+                    unique_sections.push(section);
+                }
+            }
+            let lit_sections: Vec<(WorldChunk, usize)> = self
+                .get_loaded_sections(&unique_sections)
+                .into_iter()
+                .filter(|(chunk, _)| !new_chunks.iter().any(|c| Arc::ptr_eq(c, chunk)))
+                .collect();
+            self.render_sections(&lit_sections);
+            self.update_lod(current_chunk);
         }
 
         player.current_chunk = current_chunk;
@@ -301,9 +338,12 @@ impl World {
         self.thread_pool = None;
     }
     pub fn save_state(&self) {
+        self.clock
+            .save(&self.data_dir)
+            .expect("failed to save the clock");
         for chunk in self.chunks.iter() {
             let chunkbrw = chunk.read().unwrap();
-            chunkbrw.save().expect("failed to save");
+            chunkbrw.save(&self.data_dir).expect("failed to save");
         }
     }
     pub fn init_chunks(&mut self) {
@@ -314,68 +354,192 @@ impl World {
             for chunk_y in LB..=UB {
                 let sender = sender.clone();
                 let noise_data = Arc::clone(&self.noise_data);
-                let chunk_data_layout = Arc::clone(&self.chunk_data_layout);
-                let device = Arc::clone(&self.device);
-                let queue = Arc::clone(&self.queue);
+                let data_dir = self.data_dir.clone();
 
                 self.thread_pool.as_ref().unwrap().execute(move || {
-                    let chunk = Chunk::new(
-                        chunk_x,
-                        chunk_y,
-                        noise_data,
-                        device,
-                        queue,
-                        chunk_data_layout,
-                    );
+                    let chunk = Chunk::new(chunk_x, chunk_y, noise_data, &data_dir);
                     sender.send(chunk).unwrap();
                 });
             }
         }
 
         for _ in 0..CHUNKS_PER_ROW * CHUNKS_PER_ROW {
-            let chunk = receiver.recv().expect("Some chunks are missing");
+            let mut chunk = receiver.recv().expect("Some chunks are missing");
+            chunk.slot = self.arena.allocate_chunk((chunk.x, chunk.y));
             chunks.push(Arc::new(RwLock::new(chunk)));
         }
         self.chunks.append(&mut chunks); // Add chunks to self
 
-        self.handle_outside_blocks();
-        self.render_chunks(&self.chunks);
+        let outside_blocks = self.handle_outside_blocks();
+        let light_world = self.create_light_world();
+        light_world.propagate_between_chunks(&Self::get_chunks_coords(&self.chunks));
+        // The chunks were lit before the trees of their neighbours grew into them
+        for block in outside_blocks.iter() {
+            light_world.update_block(&block.read().unwrap().absolute_position);
+        }
+        self.render_chunks(&self.chunks.clone());
+        self.update_lod((0, 0));
+    }
+    // Rebuilds the LOD chunks around the center chunk, keeping the ones that didn't change level
+    pub fn update_lod(&mut self, center: (i32, i32)) {
+        let loaded = Self::get_chunks_coords(&self.chunks);
+        let mut lod_chunks = std::mem::take(&mut self.lod_chunks);
+        let (sender, receiver) = mpsc::channel();
+        let mut chunks_added = 0;
+
+        for x in center.0 - LOD_RADIUS..=center.0 + LOD_RADIUS {
+            for y in center.1 - LOD_RADIUS..=center.1 + LOD_RADIUS {
+                if loaded.contains(&(x, y)) {
+                    continue;
+                }
+                let distance = i32::max((x - center.0).abs(), (y - center.1).abs());
+                let Some(level) = LodLevel::from_distance(distance) else {
+                    continue;
+                };
+                if let Some(index) = lod_chunks
+                    .iter()
+                    .position(|c| c.x == x && c.y == y && c.level == level)
+                {
+                    self.lod_chunks.push(lod_chunks.swap_remove(index));
+                    continue;
+                }
+
+                let sender = sender.clone();
+                let noise_data = Arc::clone(&self.noise_data);
+                self.thread_pool.as_ref().unwrap().execute(move || {
+                    let (mesh, max_height) = build_lod_mesh(x, y, level, noise_data);
+                    sender.send((x, y, level, mesh, max_height)).unwrap();
+                });
+                chunks_added += 1;
+            }
+        }
+
+        // The ones that are left got loaded, went out of range or changed level
+        for lod_chunk in lod_chunks.iter() {
+            lod_chunk.free(&mut self.arena);
+        }
+        for _ in 0..chunks_added {
+            let (x, y, level, mesh, max_height) =
+                receiver.recv().expect("Some LOD chunks are missing");
+            let lod_chunk = LodChunk::new(x, y, level, &mesh, max_height, &mut self.arena);
+            self.lod_chunks.push(lod_chunk);
+        }
+    }
+    fn get_chunks_coords(chunks: &[WorldChunk]) -> Vec<(i32, i32)> {
+        chunks
+            .iter()
+            .map(|chunk| {
+                let chunk = chunk.read().unwrap();
+                (chunk.x, chunk.y)
+            })
+            .collect()
+    }
+    /* Lights again from scratch the chunks that contain one of the block ids, e.g. when their opacity changed.
+     * Their neighbours are relit too, the old light could have spread into them
+     */
+    pub fn relight_chunks_containing(&mut self, block_ids: &[u32]) {
+        let contains_blocks = |chunk: &WorldChunk| {
+            let chunk = chunk.read().unwrap();
+            let blocks = chunk.blocks.read().unwrap();
+            blocks
+                .iter()
+                .flatten()
+                .flatten()
+                .any(|block| block_ids.contains(&block.read().unwrap().block_type.to_id()))
+        };
+        let affected = Self::get_chunks_coords(
+            &self
+                .chunks
+                .iter()
+                .filter(|chunk| contains_blocks(chunk))
+                .cloned()
+                .collect::<Vec<_>>(),
+        );
+        let relit = self
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let chunk = chunk.read().unwrap();
+                (
+                    (chunk.x, chunk.y),
+                    chunk.blocks.clone(),
+                    chunk.light.clone(),
+                )
+            })
+            .filter(|((x, y), _, _)| {
+                affected
+                    .iter()
+                    .any(|(ax, ay)| (ax - x).abs() <= 1 && (ay - y).abs() <= 1)
+            })
+            .collect::<Vec<_>>();
+
+        // Each chunk on its own first, like when they are created
+        let (sender, receiver) = mpsc::channel();
+        for (coords, blocks, light) in relit.iter().cloned() {
+            let sender = sender.clone();
+            self.thread_pool.as_ref().unwrap().execute(move || {
+                light.write().unwrap().fill(0);
+                LightWorld::new(vec![(coords, blocks, light)]).init_chunk(coords);
+                sender.send(()).unwrap();
+            });
+        }
+        for _ in relit.iter() {
+            receiver.recv().expect("Failed to relight a chunk");
+        }
+        let coords = relit
+            .iter()
+            .map(|(coords, _, _)| *coords)
+            .collect::<Vec<_>>();
+        self.create_light_world().propagate_between_chunks(&coords);
+    }
+    pub fn render_all_chunks(&mut self) {
+        self.render_chunks(&self.chunks.clone());
     }
     // chunks: slice containing the chunk to re-render
-    fn render_chunks(&self, chunks: &[WorldChunk]) {
+    fn render_chunks(&mut self, chunks: &[WorldChunk]) {
+        let sections = chunks
+            .iter()
+            .flat_map(|chunk| (0..SECTIONS_PER_CHUNK).map(|section| (chunk.clone(), section)))
+            .collect::<Vec<_>>();
+        self.render_sections(&sections);
+    }
+    // sections: chunk sections to re-render
+    fn render_sections(&mut self, sections: &[(WorldChunk, usize)]) {
         let (sender, receiver) = mpsc::channel();
 
-        for chunk in chunks.iter() {
+        for (chunk, section) in sections.iter() {
             let sender = sender.clone();
             let other = self.get_other_chunks(chunk.clone());
             let chunk = chunk.clone();
+            let section = *section;
 
             self.thread_pool.as_ref().unwrap().execute(move || {
                 let chunk_ptr = chunk.clone();
                 let chunk = chunk.read().unwrap();
-                let res = chunk.build_mesh(other);
-                sender.send((res, chunk_ptr)).unwrap();
+                let res = chunk.build_mesh(section, &other);
+                sender.send((res, chunk_ptr, section)).unwrap();
             });
         }
-        for _ in chunks.iter() {
-            let ((indices, vertex_buffer, index_buffer), chunk_ptr) =
-                receiver.recv().expect("Some chunks didn't render");
+        for _ in sections.iter() {
+            let (mesh, chunk_ptr, section) = receiver.recv().expect("Some chunks didn't render");
             let mut chunk_mut = chunk_ptr.write().unwrap();
-            chunk_mut.indices = indices;
-            chunk_mut.chunk_vertex_buffer = Some(vertex_buffer);
-            chunk_mut.chunk_index_buffer = Some(index_buffer);
+            // The old meshes' space is reused by the next uploads
+            chunk_mut.sections[section].free(&mut self.arena);
+            chunk_mut.sections[section] = ChunkSection::new(&mut self.arena, &mesh);
         }
     }
-    fn handle_outside_blocks(&mut self) {
+    /* Moves the blocks the chunks generated outside of themselves, e.g. tree leaves, into their chunks.
+     * Returns the blocks that were added, their light still has to be updated
+     */
+    fn handle_outside_blocks(&mut self) -> Vec<Arc<RwLock<Block>>> {
         let mut blocks_to_add = vec![];
         for chunk in self.chunks.iter() {
             let mut chunkbrw = chunk.write().unwrap();
             blocks_to_add.append(&mut chunkbrw.outside_blocks);
         }
 
-        let mut chunks_to_rerender: Vec<WorldChunk> = vec![];
-
-        for block in blocks_to_add.iter() {
+        let mut added_blocks = vec![];
+        for block in blocks_to_add.into_iter() {
             let chunk_coords = block.read().unwrap().get_chunk_coords();
             if let Some(chunkptr) = self.chunks.iter().find(|c| {
                 let c = c.read().unwrap();
@@ -383,30 +547,35 @@ impl World {
             }) {
                 let mut chunkbrw = chunkptr.write().unwrap();
                 chunkbrw.add_block(block.clone());
-                if let None = chunks_to_rerender.iter().find(|c| Arc::ptr_eq(c, chunkptr)) {
-                    chunks_to_rerender.push(chunkptr.clone());
-                };
+                added_blocks.push(block);
             }
         }
+        added_blocks
     }
-    pub fn init_world(device: Arc<wgpu::Device>, queue: Arc<wgpu::Queue>) -> Self {
+    pub fn init_world(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        data_dir: PathBuf,
+    ) -> Self {
         let noise_data = Arc::new(crate::utils::noise::create_world_noise_data(
             NOISE_SIZE, NOISE_SIZE, FREQUENCY,
         ));
-        let chunk_data_layout =
-            Arc::new(device.create_bind_group_layout(&Chunk::get_bind_group_layout()));
+        let arena = MeshArena::new(device.clone(), queue.clone());
 
         let max_threads = thread::available_parallelism().unwrap();
         let threads = usize::max(usize::from(max_threads), 8);
         let thread_pool = ThreadPool::new(threads);
 
         World {
-            chunk_data_layout,
+            arena,
             chunks: vec![],
+            lod_chunks: vec![],
             noise_data,
             device,
             queue,
             seed: 0,
+            clock: WorldClock::load(&data_dir, Box::new(())).unwrap_or_default(),
+            data_dir,
             thread_pool: Some(thread_pool),
         }
     }